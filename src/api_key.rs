@@ -0,0 +1,590 @@
+use crate::auth_middleware::{AuthContext, AuthSource};
+use crate::subject::Subject;
+use crate::{BusinessResult, Problem};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::AUTHORIZATION;
+use log::warn;
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+static API_KEY_HEADER_NAME: &str = "x-api-key";
+static AUTHORIZATION_SCHEME: &str = "ApiKey ";
+
+/// Last-used timestamps are only written back when they are older than this.
+const TOUCH_RESOLUTION_MILLIS: u64 = 60_000;
+
+/// A stored API key. Keys are handed out as `<id>.<secret>`, only a salted hash of the secret is kept.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ApiKeyRecord {
+  pub id: String,
+  /// Becomes `Subject::Api(name)`.
+  pub name: String,
+  pub salt: String,
+  pub hash: String,
+  pub organization: Option<String>,
+  #[serde(default)]
+  pub scopes: BTreeMap<String, Vec<String>>,
+  #[serde(default)]
+  pub last_used_millis: Option<u64>,
+}
+
+impl ApiKeyRecord {
+  /// Creates a record with a random secret, returning it together with the key to hand out.
+  pub fn generate<S: Into<String>>(id: S, name: S) -> (ApiKeyRecord, String) {
+    let mut salt = [0u8; 16];
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut secret);
+    let salt = hex::encode(salt);
+    let secret = hex::encode(secret);
+    let id = id.into();
+
+    let record = ApiKeyRecord {
+      hash: hash_secret(&salt, &secret),
+      id: id.clone(),
+      name: name.into(),
+      salt,
+      organization: None,
+      scopes: BTreeMap::new(),
+      last_used_millis: None,
+    };
+    (record, format!("{}.{}", id, secret))
+  }
+
+  pub fn with_organization<S: Into<String>>(mut self, organization: S) -> Self {
+    self.organization = Some(organization.into());
+    self
+  }
+
+  pub fn with_scope<S: Into<String>>(mut self, service: S, scope: S) -> Self {
+    self.scopes.entry(service.into()).or_default().push(scope.into());
+    self
+  }
+
+  pub fn verify(&self, secret: &str) -> bool {
+    let expected = self.hash.as_bytes();
+    let actual = hash_secret(&self.salt, secret);
+
+    expected.len() == actual.len()
+      && expected
+        .iter()
+        .zip(actual.as_bytes())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+  }
+}
+
+fn hash_secret(salt: &str, secret: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(salt.as_bytes());
+  hasher.update(secret.as_bytes());
+  hex::encode(hasher.finalize())
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+pub trait ApiKeyStore: Send + Sync {
+  fn find(&self, id: &str) -> BusinessResult<Option<ApiKeyRecord>>;
+
+  fn touch(&self, id: &str, used_at_millis: u64) -> BusinessResult<()>;
+}
+
+#[derive(Default)]
+pub struct MemoryApiKeyStore {
+  records: RwLock<HashMap<String, ApiKeyRecord>>,
+}
+
+impl MemoryApiKeyStore {
+  pub fn new(records: Vec<ApiKeyRecord>) -> MemoryApiKeyStore {
+    MemoryApiKeyStore {
+      records: RwLock::new(records.into_iter().map(|r| (r.id.clone(), r)).collect()),
+    }
+  }
+
+  pub fn insert(&self, record: ApiKeyRecord) {
+    if let Ok(mut records) = self.records.write() {
+      records.insert(record.id.clone(), record);
+    }
+  }
+}
+
+impl ApiKeyStore for MemoryApiKeyStore {
+  fn find(&self, id: &str) -> BusinessResult<Option<ApiKeyRecord>> {
+    match self.records.read() {
+      Ok(records) => Ok(records.get(id).cloned()),
+      Err(_) => Err(Problem::internal_server_error().with_details("API key store lock poisoned")),
+    }
+  }
+
+  fn touch(&self, id: &str, used_at_millis: u64) -> BusinessResult<()> {
+    match self.records.write() {
+      Ok(mut records) => {
+        if let Some(record) = records.get_mut(id) {
+          record.last_used_millis = Some(used_at_millis);
+        }
+        Ok(())
+      }
+      Err(_) => Err(Problem::internal_server_error().with_details("API key store lock poisoned")),
+    }
+  }
+}
+
+/// Keeps records as a JSON array in a file, which is rewritten whenever a last-used timestamp changes.
+pub struct FileApiKeyStore {
+  path: PathBuf,
+  records: MemoryApiKeyStore,
+  save_lock: Mutex<()>,
+}
+
+impl FileApiKeyStore {
+  pub fn open<P: Into<PathBuf>>(path: P) -> BusinessResult<FileApiKeyStore> {
+    let path = path.into();
+    let records: Vec<ApiKeyRecord> = match fs::read(&path) {
+      Ok(content) => serde_json::from_slice(&content)?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+      Err(err) => return Err(err.into()),
+    };
+
+    Ok(FileApiKeyStore {
+      path,
+      records: MemoryApiKeyStore::new(records),
+      save_lock: Mutex::new(()),
+    })
+  }
+
+  pub fn insert(&self, record: ApiKeyRecord) -> BusinessResult<()> {
+    self.records.insert(record);
+    self.save()
+  }
+
+  /// Writes all records via a temporary file, one save at a time so that none is lost.
+  fn save(&self) -> BusinessResult<()> {
+    let _saving = self
+      .save_lock
+      .lock()
+      .map_err(|_| Problem::internal_server_error().with_details("API key store lock poisoned"))?;
+    let mut records: Vec<ApiKeyRecord> = match self.records.records.read() {
+      Ok(records) => records.values().cloned().collect(),
+      Err(_) => return Err(Problem::internal_server_error().with_details("API key store lock poisoned")),
+    };
+    records.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let tmp_path = self.path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(&records)?)?;
+    fs::rename(&tmp_path, &self.path)?;
+    Ok(())
+  }
+}
+
+impl ApiKeyStore for FileApiKeyStore {
+  fn find(&self, id: &str) -> BusinessResult<Option<ApiKeyRecord>> {
+    self.records.find(id)
+  }
+
+  fn touch(&self, id: &str, used_at_millis: u64) -> BusinessResult<()> {
+    self.records.touch(id, used_at_millis)?;
+    self.save()
+  }
+}
+
+#[cfg(feature = "with-diesel")]
+pub mod diesel_store {
+  use super::{ApiKeyRecord, ApiKeyStore};
+  use crate::{BusinessResult, BusinessResultExt, Problem};
+  use diesel::prelude::*;
+  use diesel::query_dsl::methods::{FilterDsl, LimitDsl};
+  use diesel::query_dsl::LoadQuery;
+  use r2d2::{ManageConnection, Pool};
+
+  diesel::table! {
+    api_keys (id) {
+      id -> Text,
+      name -> Text,
+      salt -> Text,
+      hash -> Text,
+      organization -> Nullable<Text>,
+      scopes -> Text,
+      last_used_millis -> Nullable<BigInt>,
+    }
+  }
+
+  #[derive(Queryable)]
+  struct ApiKeyRow {
+    id: String,
+    name: String,
+    salt: String,
+    hash: String,
+    organization: Option<String>,
+    scopes: String,
+    last_used_millis: Option<i64>,
+  }
+
+  impl ApiKeyRow {
+    fn into_record(self) -> BusinessResult<ApiKeyRecord> {
+      Ok(ApiKeyRecord {
+        id: self.id,
+        name: self.name,
+        salt: self.salt,
+        hash: self.hash,
+        organization: self.organization,
+        scopes: serde_json::from_str(&self.scopes)?,
+        last_used_millis: self.last_used_millis.map(|millis| millis as u64),
+      })
+    }
+  }
+
+  type FindById = diesel::dsl::Limit<diesel::dsl::Filter<api_keys::table, diesel::dsl::Eq<api_keys::id, String>>>;
+  type TouchById = diesel::dsl::Update<
+    diesel::dsl::Filter<api_keys::table, diesel::dsl::Eq<api_keys::id, String>>,
+    diesel::dsl::Eq<api_keys::last_used_millis, Option<i64>>,
+  >;
+
+  /// Reads records from an `api_keys` table, scopes are stored as a JSON object.
+  pub struct DieselApiKeyStore<M: ManageConnection> {
+    pool: Pool<M>,
+  }
+
+  impl<M: ManageConnection> DieselApiKeyStore<M> {
+    pub fn new(pool: Pool<M>) -> DieselApiKeyStore<M> {
+      DieselApiKeyStore { pool }
+    }
+  }
+
+  impl<M> ApiKeyStore for DieselApiKeyStore<M>
+  where
+    M: ManageConnection,
+    M::Connection: Connection,
+    for<'a> FindById: LoadQuery<'a, M::Connection, ApiKeyRow>,
+    TouchById: RunQueryDsl<M::Connection> + diesel::query_dsl::methods::ExecuteDsl<M::Connection>,
+  {
+    fn find(&self, id: &str) -> BusinessResult<Option<ApiKeyRecord>> {
+      let mut conn = self.pool.get().chain_problem("Unable to get database connection")?;
+      let query: FindById = LimitDsl::limit(FilterDsl::filter(api_keys::table, api_keys::id.eq(id.to_string())), 1);
+      let rows: Vec<ApiKeyRow> = query
+        .load(&mut conn)
+        .map_err(|err| Problem::internal_server_error().with_details(format!("Database: {}", err)))?;
+
+      rows.into_iter().next().map(ApiKeyRow::into_record).transpose()
+    }
+
+    fn touch(&self, id: &str, used_at_millis: u64) -> BusinessResult<()> {
+      let mut conn = self.pool.get().chain_problem("Unable to get database connection")?;
+      let statement: TouchById = diesel::update(FilterDsl::filter(api_keys::table, api_keys::id.eq(id.to_string())))
+        .set(api_keys::last_used_millis.eq(Some(used_at_millis as i64)));
+
+      statement
+        .execute(&mut conn)
+        .map(|_| ())
+        .map_err(|err| Problem::internal_server_error().with_details(format!("Database: {}", err)))
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    fn row(scopes: &str) -> ApiKeyRow {
+      ApiKeyRow {
+        id: "key1".to_string(),
+        name: "partner".to_string(),
+        salt: "salt".to_string(),
+        hash: "hash".to_string(),
+        organization: Some("acme".to_string()),
+        scopes: scopes.to_string(),
+        last_used_millis: Some(42),
+      }
+    }
+
+    #[test]
+    fn rows_become_records() {
+      let record = row(r#"{"orders":["read","write"]}"#).into_record().unwrap();
+
+      assert_that(&record.scopes.get("orders")).is_equal_to(Some(&vec!["read".to_string(), "write".to_string()]));
+      assert_that(&record.last_used_millis).is_equal_to(Some(42));
+      assert_that(&row("not json").into_record().is_err()).is_true();
+    }
+  }
+}
+
+/// Authenticates `Subject::Api` callers by an `Authorization: ApiKey <key>` or `x-api-key` header.
+///
+/// Lookups are cached in memory for the cache TTL (by default 1 min), so only the first use of a key in that time
+/// waits for the store on the request thread, and changed or revoked keys take up to that long to apply.
+/// Last-used timestamps are written on the blocking thread pool of the runtime, if there is one.
+pub struct ApiKeyAuth {
+  store: Arc<dyn ApiKeyStore>,
+  cache_ttl: Duration,
+  cache: Mutex<HashMap<String, CachedLookup>>,
+}
+
+/// At most this many lookups are cached, expired ones are dropped once it is reached.
+const MAX_CACHED_LOOKUPS: usize = 10_000;
+
+struct CachedLookup {
+  record: Option<ApiKeyRecord>,
+  expires: Instant,
+}
+
+impl ApiKeyAuth {
+  pub fn new(store: Arc<dyn ApiKeyStore>) -> ApiKeyAuth {
+    ApiKeyAuth {
+      store,
+      cache_ttl: Duration::from_secs(60),
+      cache: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Caches lookups for `cache_ttl`, zero disables the cache.
+  pub fn with_cache_ttl(mut self, cache_ttl: Duration) -> Self {
+    self.cache_ttl = cache_ttl;
+    self
+  }
+
+  fn find(&self, id: &str) -> BusinessResult<Option<ApiKeyRecord>> {
+    let now = Instant::now();
+    if let Some(cached) = self.lock_cache()?.get(id).filter(|cached| cached.expires > now) {
+      return Ok(cached.record.clone());
+    }
+    let record = self.store.find(id)?;
+    if !self.cache_ttl.is_zero() {
+      let mut cache = self.lock_cache()?;
+      if cache.len() >= MAX_CACHED_LOOKUPS {
+        cache.retain(|_, cached| cached.expires > now);
+      }
+      if cache.len() < MAX_CACHED_LOOKUPS {
+        let expires = now + self.cache_ttl;
+        cache.insert(
+          id.to_string(),
+          CachedLookup {
+            record: record.clone(),
+            expires,
+          },
+        );
+      }
+    }
+
+    Ok(record)
+  }
+
+  /// Keeps the cached record in line with a last-used timestamp written back to the store.
+  fn touched(&self, id: &str, used_at_millis: u64) -> BusinessResult<()> {
+    if let Some(record) = self.lock_cache()?.get_mut(id).and_then(|cached| cached.record.as_mut()) {
+      record.last_used_millis = Some(used_at_millis);
+    }
+    Ok(())
+  }
+
+  fn lock_cache(&self) -> BusinessResult<MutexGuard<'_, HashMap<String, CachedLookup>>> {
+    self
+      .cache
+      .lock()
+      .map_err(|_| Problem::internal_server_error().with_details("API key cache lock poisoned"))
+  }
+}
+
+fn extract_api_key(req: &ServiceRequest) -> Option<&str> {
+  let authorization = req
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix(AUTHORIZATION_SCHEME));
+
+  authorization.or_else(|| {
+    req
+      .headers()
+      .get(API_KEY_HEADER_NAME)
+      .and_then(|value| value.to_str().ok())
+  })
+}
+
+/// Updates the last-used timestamp, a failure is only logged as the key itself is valid.
+fn touch(store: Arc<dyn ApiKeyStore>, id: String, used_at_millis: u64) {
+  let touch = move || {
+    if let Err(problem) = store.touch(&id, used_at_millis) {
+      warn!("Unable to update last use of API key {}: {}", id, problem);
+    }
+  };
+
+  match tokio::runtime::Handle::try_current() {
+    Ok(runtime) => drop(runtime.spawn_blocking(touch)),
+    Err(_) => touch(),
+  }
+}
+
+impl AuthSource for ApiKeyAuth {
+  fn authenticate(&self, req: &ServiceRequest) -> BusinessResult<Option<AuthContext>> {
+    let api_key = match extract_api_key(req) {
+      Some(api_key) => api_key.trim(),
+      None => return Ok(None),
+    };
+    let (id, secret) = api_key
+      .split_once('.')
+      .ok_or_else(|| Problem::unauthorized().with_details("Malformed API key"))?;
+    let record = match self.find(id)? {
+      Some(record) if record.verify(secret) => record,
+      _ => return Err(Problem::unauthorized().with_details("Invalid API key")),
+    };
+
+    let now = now_millis();
+    if record
+      .last_used_millis
+      .map(|last_used| now.saturating_sub(last_used) >= TOUCH_RESOLUTION_MILLIS)
+      .unwrap_or(true)
+    {
+      touch(self.store.clone(), record.id.clone(), now);
+      self.touched(&record.id, now)?;
+    }
+
+    Ok(Some(AuthContext {
+      subject: Subject::Api(record.name),
+      token: String::new(),
+      organization: record.organization,
+      scopes: record.scopes,
+      real_subject: None,
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tls::tests::TempFile;
+  use actix_web::test::TestRequest;
+  use spectral::prelude::*;
+
+  #[test]
+  fn generated_keys_verify() {
+    let (record, api_key) = ApiKeyRecord::generate("key1", "partner");
+    let (_, secret) = api_key.split_once('.').unwrap();
+
+    assert_that(&record.verify(secret)).is_true();
+    assert_that(&record.verify("wrong")).is_false();
+    assert_that(&record.hash.contains(secret)).is_false();
+  }
+
+  #[test]
+  fn authenticates_api_keys_from_either_header() {
+    let (record, api_key) = ApiKeyRecord::generate("key1", "partner");
+    let store = Arc::new(MemoryApiKeyStore::new(vec![record
+      .with_organization("acme")
+      .with_scope("orders", "read")]));
+    let auth = ApiKeyAuth::new(store.clone());
+
+    let via_authorization = TestRequest::default()
+      .insert_header(("authorization", format!("ApiKey {}", api_key)))
+      .to_srv_request();
+    let via_header = TestRequest::default()
+      .insert_header(("x-api-key", api_key.as_str()))
+      .to_srv_request();
+    let wrong_secret = TestRequest::default()
+      .insert_header(("x-api-key", "key1.wrong"))
+      .to_srv_request();
+
+    let auth_context = auth.authenticate(&via_authorization).unwrap().unwrap();
+    assert_that(&auth_context.subject).is_equal_to(Subject::Api("partner".to_string()));
+    assert_that(&auth_context.organization).is_equal_to(Some("acme".to_string()));
+    assert_that(&auth_context.has_scope("orders", "read")).is_true();
+    assert_that(&auth.authenticate(&via_header).unwrap().is_some()).is_true();
+    assert_that(&auth.authenticate(&wrong_secret).err().map(|p| p.code)).is_equal_to(Some(401));
+    assert_that(&auth.authenticate(&TestRequest::default().to_srv_request()).unwrap()).is_none();
+    assert_that(&store.find("key1").unwrap().unwrap().last_used_millis).is_some();
+  }
+
+  #[test]
+  fn caches_lookups() {
+    let (record, api_key) = ApiKeyRecord::generate("key1", "partner");
+    let store = Arc::new(MemoryApiKeyStore::new(vec![record]));
+    let cached = ApiKeyAuth::new(store.clone());
+    let uncached = ApiKeyAuth::new(store.clone()).with_cache_ttl(Duration::ZERO);
+    let req = || {
+      TestRequest::default()
+        .insert_header(("x-api-key", api_key.as_str()))
+        .to_srv_request()
+    };
+
+    assert_that(&cached.authenticate(&req()).unwrap().is_some()).is_true();
+    store.records.write().unwrap().clear();
+
+    assert_that(&cached.authenticate(&req()).unwrap().is_some()).is_true();
+    assert_that(&uncached.authenticate(&req()).err().map(|p| p.code)).is_equal_to(Some(401));
+  }
+
+  struct FailingTouch(MemoryApiKeyStore);
+
+  impl ApiKeyStore for FailingTouch {
+    fn find(&self, id: &str) -> BusinessResult<Option<ApiKeyRecord>> {
+      self.0.find(id)
+    }
+
+    fn touch(&self, _id: &str, _used_at_millis: u64) -> BusinessResult<()> {
+      Err(Problem::internal_server_error().with_details("Read-only"))
+    }
+  }
+
+  #[test]
+  fn failed_last_used_update_does_not_fail_authentication() {
+    let (record, api_key) = ApiKeyRecord::generate("key1", "partner");
+    let auth = ApiKeyAuth::new(Arc::new(FailingTouch(MemoryApiKeyStore::new(vec![record]))));
+    let req = TestRequest::default()
+      .insert_header(("x-api-key", api_key.as_str()))
+      .to_srv_request();
+
+    assert_that(&auth.authenticate(&req).unwrap().is_some()).is_true();
+  }
+
+  #[test]
+  fn file_store_saves_concurrent_touches() {
+    let path = TempFile::new("concurrent-api-keys.json");
+    let store = Arc::new(FileApiKeyStore::open(path.to_path_buf()).unwrap());
+    for id in 0..8 {
+      store
+        .insert(ApiKeyRecord::generate(format!("key{}", id), "partner".to_string()).0)
+        .unwrap();
+    }
+
+    let touches = (0..8)
+      .map(|id| {
+        let store = store.clone();
+        std::thread::spawn(move || store.touch(&format!("key{}", id), id))
+      })
+      .collect::<Vec<_>>();
+    let results = touches
+      .into_iter()
+      .map(|touch| touch.join().unwrap())
+      .collect::<Vec<_>>();
+    let reopened = FileApiKeyStore::open(path.to_path_buf()).unwrap();
+
+    assert_that(&results.iter().all(|result| result.is_ok())).is_true();
+    for id in 0..8 {
+      assert_that(&reopened.find(&format!("key{}", id)).unwrap().unwrap().last_used_millis).is_equal_to(Some(id));
+    }
+  }
+
+  #[test]
+  fn file_store_persists_last_used() {
+    let path = TempFile::new("api-keys.json");
+    let (record, _) = ApiKeyRecord::generate("key1", "partner");
+
+    let store = FileApiKeyStore::open(path.to_path_buf()).unwrap();
+    store.insert(record.clone()).unwrap();
+    store.touch("key1", 42).unwrap();
+
+    let reopened = FileApiKeyStore::open(path.to_path_buf()).unwrap();
+    assert_that(&reopened.find("key1").unwrap()).is_equal_to(Some(ApiKeyRecord {
+      last_used_millis: Some(42),
+      ..record
+    }));
+  }
+}
@@ -0,0 +1,318 @@
+use crate::auth_middleware::AuthContext;
+use crate::line_writer::LineWriter;
+use crate::{AsyncBusinessResult, Problem};
+use actix_web::body::BoxBody;
+use actix_web::dev::{Path as MatchPath, ResourceDef, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::HttpMessage;
+use futures::future::{ok, Ready};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditRecord {
+  pub timestamp_millis: u64,
+  pub subject: Option<String>,
+  pub real_subject: Option<String>,
+  pub organization: Option<String>,
+  pub method: String,
+  pub path: String,
+  pub route: Option<String>,
+  pub resource_ids: BTreeMap<String, String>,
+  pub status: u16,
+  pub problem_type: Option<String>,
+}
+
+pub trait AuditSink: Send + Sync {
+  fn record(&self, record: &AuditRecord);
+}
+
+/// Appends one JSON document per line to a file, written from a background thread.
+pub struct FileAuditSink {
+  lines: LineWriter,
+}
+
+impl FileAuditSink {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<FileAuditSink, Problem> {
+    Ok(FileAuditSink {
+      lines: LineWriter::open(path)?,
+    })
+  }
+}
+
+impl AuditSink for FileAuditSink {
+  fn record(&self, record: &AuditRecord) {
+    match serde_json::to_vec(record) {
+      Ok(line) => self.lines.write(line),
+      Err(err) => error!("Unable to serialize audit record: {}", err),
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct MemoryAuditSink {
+  records: Mutex<Vec<AuditRecord>>,
+}
+
+impl MemoryAuditSink {
+  pub fn records(&self) -> Vec<AuditRecord> {
+    self.records.lock().map(|records| records.clone()).unwrap_or_default()
+  }
+}
+
+impl AuditSink for MemoryAuditSink {
+  fn record(&self, record: &AuditRecord) {
+    if let Ok(mut records) = self.records.lock() {
+      records.push(record.clone());
+    }
+  }
+}
+
+#[cfg(feature = "with-slog")]
+pub struct SlogAuditSink {
+  logger: slog::Logger,
+}
+
+#[cfg(feature = "with-slog")]
+impl SlogAuditSink {
+  pub fn new(logger: slog::Logger) -> SlogAuditSink {
+    SlogAuditSink { logger }
+  }
+}
+
+#[cfg(feature = "with-slog")]
+impl AuditSink for SlogAuditSink {
+  fn record(&self, record: &AuditRecord) {
+    slog::info!(self.logger, "audit";
+      "timestamp_millis" => record.timestamp_millis,
+      "subject" => &record.subject,
+      "real_subject" => &record.real_subject,
+      "organization" => &record.organization,
+      "method" => &record.method,
+      "path" => &record.path,
+      "route" => &record.route,
+      "resource_ids" => serde_json::to_string(&record.resource_ids).unwrap_or_default(),
+      "status" => record.status,
+      "problem_type" => &record.problem_type,
+    );
+  }
+}
+
+/// Records every handled request, or only mutating ones, to an `AuditSink`.
+///
+/// The subject is taken from the `AuthContext`, so the middleware must run inside `AuthMiddleware`, i.e. be
+/// registered first: `App::new().wrap(AuditMiddlewareFactory::new(sink)).wrap(AuthMiddlewareFactory())`. Sinks are
+/// called within the response future and must not block.
+#[derive(Clone)]
+pub struct AuditMiddlewareFactory {
+  sink: Arc<dyn AuditSink>,
+  only_mutating: bool,
+}
+
+impl AuditMiddlewareFactory {
+  pub fn new(sink: Arc<dyn AuditSink>) -> AuditMiddlewareFactory {
+    AuditMiddlewareFactory {
+      sink,
+      only_mutating: false,
+    }
+  }
+
+  /// Skip safe methods like GET and HEAD.
+  pub fn only_mutating(mut self) -> Self {
+    self.only_mutating = true;
+    self
+  }
+}
+
+impl<S> Transform<S, ServiceRequest> for AuditMiddlewareFactory
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Problem> + 'static,
+{
+  type Response = ServiceResponse<BoxBody>;
+  type Error = Problem;
+  type InitError = ();
+  type Transform = AuditMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(AuditMiddleware {
+      service,
+      sink: self.sink.clone(),
+      only_mutating: self.only_mutating,
+    })
+  }
+}
+
+pub struct AuditMiddleware<S> {
+  service: S,
+  sink: Arc<dyn AuditSink>,
+  only_mutating: bool,
+}
+
+impl<S, B: 'static> Service<ServiceRequest> for AuditMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Problem> + 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = Problem;
+  type Future = AsyncBusinessResult<Self::Response>;
+
+  fn poll_ready(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(cx)
+  }
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    if self.only_mutating && req.method().is_safe() {
+      return Box::pin(self.service.call(req));
+    }
+
+    let auth_context = req.extensions().get::<AuthContext>().cloned();
+    let route = req.match_pattern();
+    let mut resource_ids = req
+      .match_info()
+      .iter()
+      .map(|(name, value)| (name.to_string(), value.to_string()))
+      .collect::<BTreeMap<_, _>>();
+    if let (Some(route), true) = (&route, resource_ids.is_empty()) {
+      // Outside of a resource the request is not routed yet, so its parameters are taken from the pattern.
+      let mut path = MatchPath::new(req.path());
+      if ResourceDef::new(route.as_str()).capture_match_info(&mut path) {
+        resource_ids = path
+          .iter()
+          .map(|(name, value)| (name.to_string(), value.to_string()))
+          .collect();
+      }
+    }
+    let mut record = AuditRecord {
+      timestamp_millis: SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default(),
+      subject: auth_context.as_ref().map(|a| a.subject.to_string()),
+      real_subject: auth_context
+        .as_ref()
+        .and_then(|a| a.real_subject.as_ref())
+        .map(|s| s.to_string()),
+      organization: auth_context.and_then(|a| a.organization),
+      method: req.method().to_string(),
+      path: req.path().to_string(),
+      route,
+      resource_ids,
+      status: 0,
+      problem_type: None,
+    };
+    let sink = self.sink.clone();
+    let fut = self.service.call(req);
+
+    Box::pin(async move {
+      let result = fut.await;
+
+      match &result {
+        Ok(res) => {
+          record.status = res.status().as_u16();
+          record.problem_type = res
+            .response()
+            .error()
+            .and_then(|e| e.as_error::<Problem>())
+            .map(|problem| problem.problem_type.clone());
+        }
+        Err(problem) => {
+          record.status = problem.code;
+          record.problem_type = Some(problem.problem_type.clone());
+        }
+      }
+      sink.record(&record);
+
+      result
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::dev::{fn_service, Service, Transform};
+  use actix_web::http::Method;
+  use actix_web::test::TestRequest;
+  use actix_web::{web, App, HttpResponse};
+  use spectral::prelude::*;
+  use std::rc::Rc;
+
+  async fn audited(factory: AuditMiddlewareFactory, req: ServiceRequest) {
+    let service = factory
+      .new_transform(fn_service(|req: ServiceRequest| async move {
+        match req.method() {
+          &Method::DELETE => Err(Problem::conflict()),
+          _ => Ok(req.into_response(HttpResponse::Created().finish())),
+        }
+      }))
+      .await
+      .unwrap();
+
+    let _ = service.call(req).await;
+  }
+
+  #[actix_web::test]
+  async fn records_successful_requests() {
+    let sink = Arc::new(MemoryAuditSink::default());
+    let req = TestRequest::post().uri("/orders/42").param("id", "42").to_srv_request();
+
+    audited(AuditMiddlewareFactory::new(sink.clone()), req).await;
+
+    let records = sink.records();
+    assert_that(&records).has_length(1);
+    assert_that(&records[0].method.as_str()).is_equal_to("POST");
+    assert_that(&records[0].path.as_str()).is_equal_to("/orders/42");
+    assert_that(&records[0].resource_ids.get("id")).is_equal_to(Some(&"42".to_string()));
+    assert_that(&records[0].status).is_equal_to(201);
+    assert_that(&records[0].problem_type).is_none();
+  }
+
+  #[actix_web::test]
+  async fn records_problems() {
+    let sink = Arc::new(MemoryAuditSink::default());
+    let service = Rc::new(
+      AuditMiddlewareFactory::new(sink.clone())
+        .new_transform(fn_service(|_: ServiceRequest| async {
+          Err::<ServiceResponse, _>(Problem::conflict())
+        }))
+        .await
+        .unwrap(),
+    );
+    let app = actix_web::test::init_service(
+      App::new()
+        .wrap_fn(move |req, _| {
+          let service = service.clone();
+          async move { service.call(req).await.map_err(actix_web::Error::from) }
+        })
+        .route("/orders/{id}", web::delete().to(HttpResponse::NoContent)),
+    )
+    .await;
+
+    let _ = actix_web::test::try_call_service(&app, TestRequest::delete().uri("/orders/42").to_request()).await;
+
+    let records = sink.records();
+    assert_that(&records).has_length(1);
+    assert_that(&records[0].route).is_equal_to(Some("/orders/{id}".to_string()));
+    assert_that(&records[0].resource_ids.get("id")).is_equal_to(Some(&"42".to_string()));
+    assert_that(&records[0].status).is_equal_to(409);
+    assert_that(&records[0].problem_type).is_equal_to(Some("https://httpstatus.es/409".to_string()));
+  }
+
+  #[actix_web::test]
+  async fn skips_safe_methods_when_only_mutating() {
+    let sink = Arc::new(MemoryAuditSink::default());
+
+    audited(
+      AuditMiddlewareFactory::new(sink.clone()).only_mutating(),
+      TestRequest::get().uri("/orders/42").to_srv_request(),
+    )
+    .await;
+
+    assert_that(&sink.records()).is_empty();
+  }
+}
@@ -1,5 +1,6 @@
 use super::{AsyncBusinessResult, BusinessResult, Problem};
 use crate::subject::Subject;
+use crate::tls::ClientCertificateAuth;
 use actix_web::body::BoxBody;
 use actix_web::error::{ErrorForbidden, ErrorUnauthorized};
 use actix_web::http::header::{HeaderMap, HeaderValue};
@@ -9,8 +10,15 @@ use actix_web::{
   HttpMessage, HttpRequest, HttpResponse, Result,
 };
 use futures::future::{err, ok, Future, Ready};
+use ipnet::IpNet;
+use log::{info, warn};
+use prometheus::{register, IntCounterVec, Opts};
+use serde_json::json;
 use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
 use std::task::{Context, Poll};
 
 #[derive(Clone, Debug)]
@@ -19,9 +27,19 @@ pub struct AuthContext {
   pub token: String,
   pub organization: Option<String>,
   pub scopes: BTreeMap<String, Vec<String>>,
+  /// The admin acting as `subject` of an impersonated request.
+  pub real_subject: Option<Subject>,
 }
 
 impl AuthContext {
+  pub fn has_scope(&self, service: &str, scope: &str) -> bool {
+    self
+      .scopes
+      .get(service)
+      .map(|scopes| scopes.iter().any(|s| s == scope))
+      .unwrap_or(false)
+  }
+
   pub async fn require<R, F, FU, U>(self, requirements: R, f: F) -> BusinessResult<U>
   where
     F: FnOnce() -> FU,
@@ -53,10 +71,13 @@ impl FromRequest for AuthContext {
   }
 }
 
-static SUBJECT_HEADER_NAME: &str = "x-auth-sub";
-static TOKEN_HEADER_NAME: &str = "x-auth-token";
-static ORGANIZATION_HEADER_NAME: &str = "x-auth-org";
-static SCOPES_HEADER_PREFIX: &str = "x-auth-scopes-";
+pub(crate) static SUBJECT_HEADER_NAME: &str = "x-auth-sub";
+pub(crate) static TOKEN_HEADER_NAME: &str = "x-auth-token";
+pub(crate) static ORGANIZATION_HEADER_NAME: &str = "x-auth-org";
+pub(crate) static SCOPES_HEADER_PREFIX: &str = "x-auth-scopes-";
+pub(crate) static REAL_SUBJECT_HEADER_NAME: &str = "x-auth-real-sub";
+static IMPERSONATE_HEADER_NAME: &str = "x-auth-impersonate";
+static IMPERSONATE_ORGANIZATION_HEADER_NAME: &str = "x-auth-impersonate-org";
 
 pub fn admin_scoped_action<F>(req: &HttpRequest, f: F) -> Result<HttpResponse>
 where
@@ -127,8 +148,285 @@ fn extract_organization(maybe_organization: Option<&HeaderValue>) -> Option<Stri
   }
 }
 
-#[derive(Default)]
-pub struct AuthMiddlewareFactory();
+fn extract_auth_context(headers: &HeaderMap) -> Option<AuthContext> {
+  let subject = headers.get(SUBJECT_HEADER_NAME)?.to_str().ok()?;
+  let token = headers.get(TOKEN_HEADER_NAME)?.to_str().ok()?;
+  let subject = Subject::from_str(subject).ok()?;
+
+  Some(AuthContext {
+    subject,
+    token: token.to_string(),
+    organization: extract_organization(headers.get(ORGANIZATION_HEADER_NAME)),
+    scopes: extract_scopes_from_headers(headers),
+    real_subject: headers
+      .get(REAL_SUBJECT_HEADER_NAME)
+      .and_then(|real_subject| real_subject.to_str().ok())
+      .and_then(|real_subject| Subject::from_str(real_subject).ok()),
+  })
+}
+
+/// A way of establishing who is calling. Sources are consulted in order, the first context found wins.
+pub trait AuthSource: Send + Sync {
+  fn authenticate(&self, req: &ServiceRequest) -> BusinessResult<Option<AuthContext>>;
+}
+
+/// The `x-auth-*` headers set by the gateway.
+pub struct GatewayHeaders;
+
+impl AuthSource for GatewayHeaders {
+  fn authenticate(&self, req: &ServiceRequest) -> BusinessResult<Option<AuthContext>> {
+    Ok(extract_auth_context(req.headers()))
+  }
+}
+
+/// Allows admins holding `scope` of `service` to act as another subject via the `x-auth-impersonate` header.
+#[derive(Clone, Debug)]
+pub struct Impersonation {
+  pub service: String,
+  pub scope: String,
+}
+
+impl Impersonation {
+  pub fn new<S: Into<String>>(service: S, scope: S) -> Impersonation {
+    Impersonation {
+      service: service.into(),
+      scope: scope.into(),
+    }
+  }
+
+  fn apply(&self, auth_context: AuthContext, req: &ServiceRequest) -> BusinessResult<AuthContext> {
+    let target = match req.headers().get(IMPERSONATE_HEADER_NAME) {
+      Some(target) => target
+        .to_str()
+        .map_err(|_| Problem::bad_request().with_details("Invalid impersonation header"))?,
+      None => return Ok(auth_context),
+    };
+
+    if !matches!(auth_context.subject, Subject::Admin(_)) {
+      return Err(Problem::forbidden().with_details("Only admins may impersonate"));
+    }
+    if !auth_context.has_scope(&self.service, &self.scope) {
+      return Err(Problem::forbidden().with_details(format!(
+        "Impersonation requires scope {} of {}",
+        self.scope, self.service
+      )));
+    }
+    let subject = Subject::from_str(target)?;
+    if matches!(subject, Subject::Admin(_) | Subject::Service(_)) {
+      return Err(Problem::forbidden().with_details("Admins and services cannot be impersonated"));
+    }
+    let organization = extract_organization(req.headers().get(IMPERSONATE_ORGANIZATION_HEADER_NAME));
+
+    info!(
+      target: "audit",
+      "{}",
+      json!({
+        "event": "impersonation",
+        "subject": subject.to_string(),
+        "real_subject": auth_context.subject.to_string(),
+        "organization": organization,
+        "method": req.method().as_str(),
+        "path": req.path(),
+      })
+    );
+
+    Ok(AuthContext {
+      subject,
+      token: auth_context.token,
+      organization,
+      scopes: BTreeMap::new(),
+      real_subject: Some(auth_context.subject),
+    })
+  }
+}
+
+/// What to do with `x-auth-*` headers sent by a peer that is not a trusted proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UntrustedIdentityHeaders {
+  Strip,
+  Reject,
+}
+
+impl UntrustedIdentityHeaders {
+  fn as_str(&self) -> &'static str {
+    match self {
+      UntrustedIdentityHeaders::Strip => "stripped",
+      UntrustedIdentityHeaders::Reject => "rejected",
+    }
+  }
+}
+
+/// Only peers within these networks (usually the gateway) may set identity headers.
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+  networks: Vec<IpNet>,
+  untrusted: UntrustedIdentityHeaders,
+}
+
+fn untrusted_identity_headers_counter() -> &'static IntCounterVec {
+  static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+
+  COUNTER.get_or_init(|| {
+    let opts = Opts::new(
+      "auth_untrusted_identity_headers_total",
+      "Requests carrying identity headers from untrusted peers",
+    );
+    let counter = IntCounterVec::new(opts, &["action"]).unwrap();
+
+    register(Box::new(counter.clone())).unwrap();
+
+    counter
+  })
+}
+
+impl TrustedProxies {
+  /// Accepts networks in CIDR notation, plain addresses are treated as single hosts.
+  pub fn new<S: AsRef<str>>(networks: &[S], untrusted: UntrustedIdentityHeaders) -> BusinessResult<TrustedProxies> {
+    let networks = networks
+      .iter()
+      .map(|network| {
+        let network = network.as_ref().trim();
+        IpNet::from_str(network)
+          .or_else(|_| IpAddr::from_str(network).map(IpNet::from))
+          .map_err(|_| Problem::internal_server_error().with_details(format!("Invalid trusted proxy: {}", network)))
+      })
+      .collect::<BusinessResult<Vec<IpNet>>>()?;
+
+    Ok(TrustedProxies { networks, untrusted })
+  }
+
+  pub fn is_trusted(&self, peer: Option<IpAddr>) -> bool {
+    match peer {
+      Some(peer) => self.networks.iter().any(|network| network.contains(&peer)),
+      None => false,
+    }
+  }
+
+  /// The address of the client, taken from `X-Forwarded-For` as far as the hops are trusted proxies.
+  ///
+  /// Entries are read from the right, the first one not within a trusted network is the client, so that clients
+  /// cannot pose as someone else by sending their own `X-Forwarded-For`.
+  pub fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+    let mut client = req.peer_addr().map(|addr| addr.ip());
+    if !self.is_trusted(client) {
+      return client;
+    }
+    let forwarded = req
+      .headers()
+      .get_all("x-forwarded-for")
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .collect::<Vec<_>>();
+    for hop in forwarded.into_iter().rev() {
+      match IpAddr::from_str(hop.trim()) {
+        Ok(hop) => client = Some(hop),
+        Err(_) => break,
+      }
+      if !self.is_trusted(client) {
+        break;
+      }
+    }
+
+    client
+  }
+
+  fn guard(&self, req: &mut ServiceRequest) -> BusinessResult<()> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if self.is_trusted(peer) {
+      return Ok(());
+    }
+    let identity_headers: Vec<_> = req
+      .headers()
+      .keys()
+      .filter(|name| is_identity_header(name.as_str()))
+      .cloned()
+      .collect();
+    if identity_headers.is_empty() {
+      return Ok(());
+    }
+
+    warn!(
+      "Identity headers {:?} {} from untrusted peer {:?} for {} {}",
+      identity_headers,
+      self.untrusted.as_str(),
+      peer,
+      req.method(),
+      req.path()
+    );
+    untrusted_identity_headers_counter()
+      .with_label_values(&[self.untrusted.as_str()])
+      .inc();
+
+    match self.untrusted {
+      UntrustedIdentityHeaders::Strip => {
+        for name in identity_headers {
+          req.headers_mut().remove(name);
+        }
+        Ok(())
+      }
+      UntrustedIdentityHeaders::Reject => {
+        Err(Problem::forbidden().with_details("Identity headers are only accepted from trusted proxies"))
+      }
+    }
+  }
+}
+
+pub(crate) fn is_identity_header(name: &str) -> bool {
+  name.starts_with("x-auth-")
+}
+
+/// Authenticates requests via the configured sources, by default the gateway headers only.
+#[derive(Clone)]
+pub struct AuthMiddlewareFactory {
+  sources: Vec<Arc<dyn AuthSource>>,
+  impersonation: Option<Rc<Impersonation>>,
+  trusted_proxies: Option<Rc<TrustedProxies>>,
+}
+
+/// The gateway header authentication of `AuthMiddlewareFactory::default`, kept for `.wrap(AuthMiddlewareFactory())`.
+#[allow(non_snake_case)]
+pub fn AuthMiddlewareFactory() -> AuthMiddlewareFactory {
+  AuthMiddlewareFactory::default()
+}
+
+impl Default for AuthMiddlewareFactory {
+  fn default() -> Self {
+    AuthMiddlewareFactory::new(vec![Arc::new(GatewayHeaders)])
+  }
+}
+
+impl AuthMiddlewareFactory {
+  pub fn new(sources: Vec<Arc<dyn AuthSource>>) -> Self {
+    AuthMiddlewareFactory {
+      sources,
+      impersonation: None,
+      trusted_proxies: None,
+    }
+  }
+
+  /// Consults `source` after the sources configured so far.
+  pub fn with_auth_source<A: AuthSource + 'static>(mut self, source: A) -> Self {
+    self.sources.push(Arc::new(source));
+    self
+  }
+
+  /// Consults verified client certificates before any other source, so that with mTLS a peer cannot pose as
+  /// someone else via gateway headers.
+  pub fn with_client_certificate_auth(mut self) -> Self {
+    self.sources.insert(0, Arc::new(ClientCertificateAuth));
+    self
+  }
+
+  pub fn with_impersonation(mut self, impersonation: Impersonation) -> Self {
+    self.impersonation = Some(Rc::new(impersonation));
+    self
+  }
+
+  pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+    self.trusted_proxies = Some(Rc::new(trusted_proxies));
+    self
+  }
+}
 
 impl<S> Transform<S, ServiceRequest> for AuthMiddlewareFactory
 where
@@ -141,15 +439,40 @@ where
   type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
   fn new_transform(&self, service: S) -> Self::Future {
-    ok(AuthMiddleware { service })
+    ok(AuthMiddleware {
+      service,
+      sources: self.sources.clone(),
+      impersonation: self.impersonation.clone(),
+      trusted_proxies: self.trusted_proxies.clone(),
+    })
   }
 }
 
 pub struct AuthMiddleware<S> {
   service: S,
+  sources: Vec<Arc<dyn AuthSource>>,
+  impersonation: Option<Rc<Impersonation>>,
+  trusted_proxies: Option<Rc<TrustedProxies>>,
 }
 
-impl<S, B> Service<ServiceRequest> for AuthMiddleware<S>
+impl<S> AuthMiddleware<S> {
+  fn authenticate(&self, req: &ServiceRequest) -> BusinessResult<Option<AuthContext>> {
+    let mut maybe_auth_context = None;
+    for source in &self.sources {
+      maybe_auth_context = source.authenticate(req)?;
+      if maybe_auth_context.is_some() {
+        break;
+      }
+    }
+
+    match (maybe_auth_context, &self.impersonation) {
+      (Some(auth_context), Some(impersonation)) => impersonation.apply(auth_context, req).map(Some),
+      (maybe_auth_context, _) => Ok(maybe_auth_context),
+    }
+  }
+}
+
+impl<S, B: 'static> Service<ServiceRequest> for AuthMiddleware<S>
 where
   S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Problem> + 'static,
 {
@@ -161,24 +484,19 @@ where
     self.service.poll_ready(cx)
   }
 
-  fn call(&self, req: ServiceRequest) -> Self::Future {
-    let maybe_subject: Option<&HeaderValue> = req.headers().get(SUBJECT_HEADER_NAME);
-    let maybe_token: Option<&HeaderValue> = req.headers().get(TOKEN_HEADER_NAME);
-    let maybe_organization: Option<&HeaderValue> = req.headers().get(ORGANIZATION_HEADER_NAME);
-
-    if let (Some(subject), Some(token)) = (maybe_subject, maybe_token) {
-      let scopes: BTreeMap<String, Vec<String>> = extract_scopes_from_headers(req.headers());
+  fn call(&self, mut req: ServiceRequest) -> Self::Future {
+    if let Some(trusted_proxies) = &self.trusted_proxies {
+      if let Err(problem) = trusted_proxies.guard(&mut req) {
+        return Box::pin(err(problem));
+      }
+    }
 
-      if let (Ok(subject_str), Ok(token)) = (subject.to_str(), token.to_str()) {
-        if let Ok(subject) = Subject::from_str(subject_str) {
-          req.extensions_mut().insert(AuthContext {
-            subject,
-            token: token.to_string(),
-            organization: extract_organization(maybe_organization),
-            scopes,
-          });
-        }
+    match self.authenticate(&req) {
+      Ok(Some(auth_context)) => {
+        req.extensions_mut().insert(auth_context);
       }
+      Ok(None) => (),
+      Err(problem) => return Box::pin(err(problem)),
     }
 
     let fut = self.service.call(req);
@@ -194,8 +512,18 @@ where
 mod tests {
   use super::*;
   use actix_web::http::header::HeaderName;
+  use actix_web::test::TestRequest;
   use spectral::prelude::*;
 
+  fn impersonating_middleware() -> AuthMiddleware<()> {
+    AuthMiddleware {
+      service: (),
+      sources: vec![Arc::new(GatewayHeaders)],
+      impersonation: Some(Rc::new(Impersonation::new("support", "impersonate"))),
+      trusted_proxies: None,
+    }
+  }
+
   #[test]
   fn extract_organization_from_header_is_successful() {
     let header_value = HeaderValue::from_static("fkbr org");
@@ -222,4 +550,161 @@ mod tests {
     assert_that(&scopes.get("kuci").unwrap()).is_equal_to(&vec!["fkbr".to_string(), "sxoe".to_string()]);
     assert_that(&scopes.get("sxoe").unwrap()).is_equal_to(&vec!["kuci".to_string()]);
   }
+
+  #[test]
+  fn admin_with_scope_may_impersonate() {
+    let req = TestRequest::default()
+      .insert_header(("x-auth-sub", "admin/alice"))
+      .insert_header(("x-auth-token", "token"))
+      .insert_header(("x-auth-scopes-support", "impersonate"))
+      .insert_header(("x-auth-impersonate", "customer/bob"))
+      .insert_header(("x-auth-impersonate-org", "bobs-org"))
+      .to_srv_request();
+
+    let auth_context = impersonating_middleware().authenticate(&req).unwrap().unwrap();
+
+    assert_that(&auth_context.subject).is_equal_to(Subject::Customer("bob".to_string()));
+    assert_that(&auth_context.real_subject).is_equal_to(Some(Subject::Admin("alice".to_string())));
+    assert_that(&auth_context.organization).is_equal_to(Some("bobs-org".to_string()));
+    assert_that(&auth_context.scopes).is_equal_to(BTreeMap::new());
+  }
+
+  #[test]
+  fn impersonation_requires_admin_with_scope() {
+    let without_scope = TestRequest::default()
+      .insert_header(("x-auth-sub", "admin/alice"))
+      .insert_header(("x-auth-token", "token"))
+      .insert_header(("x-auth-impersonate", "customer/bob"))
+      .to_srv_request();
+    let customer = TestRequest::default()
+      .insert_header(("x-auth-sub", "customer/carol"))
+      .insert_header(("x-auth-token", "token"))
+      .insert_header(("x-auth-scopes-support", "impersonate"))
+      .insert_header(("x-auth-impersonate", "customer/bob"))
+      .to_srv_request();
+
+    assert_that(
+      &impersonating_middleware()
+        .authenticate(&without_scope)
+        .err()
+        .map(|p| p.code),
+    )
+    .is_equal_to(Some(403));
+    assert_that(&impersonating_middleware().authenticate(&customer).err().map(|p| p.code)).is_equal_to(Some(403));
+  }
+
+  #[test]
+  fn impersonation_header_is_ignored_when_not_configured() {
+    let req = TestRequest::default()
+      .insert_header(("x-auth-sub", "admin/alice"))
+      .insert_header(("x-auth-token", "token"))
+      .insert_header(("x-auth-impersonate", "customer/bob"))
+      .to_srv_request();
+    let middleware = AuthMiddleware {
+      service: (),
+      sources: vec![Arc::new(GatewayHeaders)],
+      impersonation: None,
+      trusted_proxies: None,
+    };
+
+    let auth_context = middleware.authenticate(&req).unwrap().unwrap();
+
+    assert_that(&auth_context.subject).is_equal_to(Subject::Admin("alice".to_string()));
+    assert_that(&auth_context.real_subject).is_none();
+  }
+
+  #[test]
+  fn admins_and_services_cannot_be_impersonated() {
+    let impersonating = |target: &str| {
+      TestRequest::default()
+        .insert_header(("x-auth-sub", "admin/alice"))
+        .insert_header(("x-auth-token", "token"))
+        .insert_header(("x-auth-scopes-support", "impersonate"))
+        .insert_header(("x-auth-impersonate", target.to_string()))
+        .to_srv_request()
+    };
+
+    for target in ["admin/bob", "service/billing"] {
+      assert_that(
+        &impersonating_middleware()
+          .authenticate(&impersonating(target))
+          .err()
+          .map(|p| p.code),
+      )
+      .is_equal_to(Some(403));
+    }
+  }
+
+  #[test]
+  fn real_subject_is_forwarded_like_the_subject() {
+    let proxies = TrustedProxies::new(&["10.0.0.0/8"], UntrustedIdentityHeaders::Strip).unwrap();
+    let request = |peer: &str| {
+      TestRequest::default()
+        .peer_addr(peer.parse().unwrap())
+        .insert_header(("x-auth-sub", "customer/bob"))
+        .insert_header(("x-auth-token", "token"))
+        .insert_header(("x-auth-real-sub", "admin/alice"))
+        .to_srv_request()
+    };
+
+    let trusted = request("10.1.2.3:1234");
+    let auth_context = impersonating_middleware().authenticate(&trusted).unwrap().unwrap();
+    assert_that(&auth_context.real_subject).is_equal_to(Some(Subject::Admin("alice".to_string())));
+
+    let mut untrusted = request("172.16.0.1:1234");
+    assert_that(&proxies.guard(&mut untrusted).is_ok()).is_true();
+    assert_that(&untrusted.headers().contains_key(REAL_SUBJECT_HEADER_NAME)).is_false();
+  }
+
+  #[test]
+  fn trusted_proxies_accept_identity_headers_from_trusted_peers_only() {
+    let strip = TrustedProxies::new(&["10.0.0.0/8", "192.168.1.1"], UntrustedIdentityHeaders::Strip).unwrap();
+    let reject = TrustedProxies::new(&["10.0.0.0/8"], UntrustedIdentityHeaders::Reject).unwrap();
+    let request = |peer: &str| {
+      TestRequest::default()
+        .peer_addr(peer.parse().unwrap())
+        .insert_header(("x-auth-sub", "admin/mallory"))
+        .insert_header(("x-auth-token", "token"))
+        .insert_header(("x-auth-scopes-orders", "admin"))
+        .to_srv_request()
+    };
+
+    let mut trusted = request("10.1.2.3:1234");
+    assert_that(&strip.guard(&mut trusted).is_ok()).is_true();
+    assert_that(&extract_auth_context(trusted.headers()).is_some()).is_true();
+
+    let mut untrusted = request("172.16.0.1:1234");
+    assert_that(&strip.guard(&mut untrusted).is_ok()).is_true();
+    assert_that(&untrusted.headers().len()).is_equal_to(0);
+
+    let mut single_host = request("192.168.1.1:1234");
+    assert_that(&strip.guard(&mut single_host).is_ok()).is_true();
+    assert_that(&single_host.headers().len()).is_equal_to(3);
+
+    let mut rejected = request("172.16.0.1:1234");
+    assert_that(&reject.guard(&mut rejected).err().map(|p| p.code)).is_equal_to(Some(403));
+  }
+
+  #[test]
+  fn trusted_proxies_forward_client_addresses() {
+    let proxies = TrustedProxies::new(&["10.0.0.0/8"], UntrustedIdentityHeaders::Strip).unwrap();
+    let request = |peer: &str, forwarded: &str| {
+      TestRequest::default()
+        .peer_addr(peer.parse().unwrap())
+        .insert_header(("x-forwarded-for", forwarded))
+        .to_srv_request()
+    };
+    let client_ip = |req| proxies.client_ip(&req).map(|ip| ip.to_string());
+
+    assert_that(&client_ip(request("10.0.0.1:1234", "1.2.3.4"))).is_equal_to(Some("1.2.3.4".to_string()));
+    assert_that(&client_ip(request("10.0.0.1:1234", "6.6.6.6, 1.2.3.4, 10.0.0.2")))
+      .is_equal_to(Some("1.2.3.4".to_string()));
+    assert_that(&client_ip(request("172.16.0.1:1234", "1.2.3.4"))).is_equal_to(Some("172.16.0.1".to_string()));
+    assert_that(&client_ip(request("10.0.0.1:1234", "garbage"))).is_equal_to(Some("10.0.0.1".to_string()));
+  }
+
+  #[test]
+  fn trusted_proxies_reject_invalid_networks() {
+    assert_that(&TrustedProxies::new(&["10.0.0.0/33"], UntrustedIdentityHeaders::Strip).is_err()).is_true();
+  }
 }
@@ -0,0 +1,199 @@
+use crate::{BusinessResult, Problem};
+use actix_web::rt::time::timeout;
+use prometheus::{register, IntGaugeVec, Opts};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// At most `max_concurrency` calls to a target run at once, further calls wait up to `queue_timeout` for a slot.
+#[derive(Clone, Debug)]
+pub struct BulkheadConfig {
+  pub max_concurrency: usize,
+  pub queue_timeout: Duration,
+}
+
+impl Default for BulkheadConfig {
+  fn default() -> Self {
+    BulkheadConfig {
+      max_concurrency: 64,
+      queue_timeout: Duration::from_secs(1),
+    }
+  }
+}
+
+impl BulkheadConfig {
+  fn validate(self) -> BusinessResult<BulkheadConfig> {
+    if self.max_concurrency == 0 {
+      return Err(Problem::internal_server_error().with_details("Bulkhead max_concurrency must be at least 1"));
+    }
+    Ok(self)
+  }
+}
+
+/// Concurrency limits for all downstream targets, keyed by host or logical service name.
+pub struct Bulkheads {
+  default_config: BulkheadConfig,
+  configs: HashMap<String, BulkheadConfig>,
+  semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// A slot of a bulkhead, released when dropped.
+pub struct BulkheadPermit {
+  target: String,
+  _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for BulkheadPermit {
+  fn drop(&mut self) {
+    gauges().in_use.with_label_values(&[&self.target]).dec();
+  }
+}
+
+struct Waiting<'a>(&'a str);
+
+impl Drop for Waiting<'_> {
+  fn drop(&mut self) {
+    gauges().waiting.with_label_values(&[self.0]).dec();
+  }
+}
+
+impl Bulkheads {
+  /// Fails if `max_concurrency` is 0, as no call could ever run.
+  pub fn new(default_config: BulkheadConfig) -> BusinessResult<Bulkheads> {
+    Ok(Bulkheads {
+      default_config: default_config.validate()?,
+      configs: HashMap::new(),
+      semaphores: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// Fails if `max_concurrency` is 0, as no call could ever run.
+  pub fn with_config_for<S: Into<String>>(mut self, target: S, config: BulkheadConfig) -> BusinessResult<Self> {
+    self.configs.insert(target.into(), config.validate()?);
+    Ok(self)
+  }
+
+  fn config_for(&self, target: &str) -> &BulkheadConfig {
+    self.configs.get(target).unwrap_or(&self.default_config)
+  }
+
+  /// Waits for a slot of `target`, failing with 503 once the queue timeout is exceeded.
+  pub async fn acquire(&self, target: &str) -> BusinessResult<BulkheadPermit> {
+    let config = self.config_for(target);
+    let semaphore = self
+      .semaphores
+      .lock()
+      .map_err(|_| Problem::internal_server_error().with_details("Bulkhead lock poisoned"))?
+      .entry(target.to_string())
+      .or_insert_with(|| Arc::new(Semaphore::new(config.max_concurrency)))
+      .clone();
+    let gauges = gauges();
+
+    gauges.waiting.with_label_values(&[target]).inc();
+    let waiting = Waiting(target);
+    let permit = timeout(config.queue_timeout, semaphore.acquire_owned()).await;
+    drop(waiting);
+    match permit {
+      Ok(Ok(permit)) => {
+        gauges.in_use.with_label_values(&[target]).inc();
+        Ok(BulkheadPermit {
+          target: target.to_string(),
+          _permit: permit,
+        })
+      }
+      _ => Err(Problem::service_unavailable().with_details(format!(
+        "Bulkhead for {} is full, no slot within {} ms",
+        target,
+        config.queue_timeout.as_millis()
+      ))),
+    }
+  }
+}
+
+struct BulkheadGauges {
+  in_use: IntGaugeVec,
+  waiting: IntGaugeVec,
+}
+
+fn gauges() -> &'static BulkheadGauges {
+  static GAUGES: OnceLock<BulkheadGauges> = OnceLock::new();
+
+  GAUGES.get_or_init(|| {
+    let in_use = IntGaugeVec::new(
+      Opts::new("service_requester_bulkhead_in_use", "Calls running per target"),
+      &["target"],
+    )
+    .unwrap();
+    let waiting = IntGaugeVec::new(
+      Opts::new(
+        "service_requester_bulkhead_waiting",
+        "Calls waiting for a bulkhead slot per target",
+      ),
+      &["target"],
+    )
+    .unwrap();
+
+    register(Box::new(in_use.clone())).unwrap();
+    register(Box::new(waiting.clone())).unwrap();
+
+    BulkheadGauges { in_use, waiting }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[actix_web::test]
+  async fn limits_concurrent_calls_per_target() {
+    let bulkheads = Bulkheads::new(BulkheadConfig {
+      max_concurrency: 1,
+      queue_timeout: Duration::from_millis(20),
+    })
+    .and_then(|bulkheads| {
+      bulkheads.with_config_for(
+        "billing",
+        BulkheadConfig {
+          max_concurrency: 2,
+          ..BulkheadConfig::default()
+        },
+      )
+    })
+    .unwrap();
+
+    let permit = bulkheads.acquire("orders").await.unwrap();
+    let full = bulkheads
+      .acquire("orders")
+      .await
+      .map(|_| ())
+      .map_err(|problem| problem.code);
+    let billing = (bulkheads.acquire("billing").await, bulkheads.acquire("billing").await);
+    assert_that(&gauges().in_use.with_label_values(&["orders"]).get()).is_equal_to(1);
+    assert_that(&gauges().waiting.with_label_values(&["orders"]).get()).is_equal_to(0);
+    drop(permit);
+    let released = bulkheads.acquire("orders").await;
+
+    assert_that(&full).is_equal_to(Err(503));
+    assert_that(&(billing.0.is_ok() && billing.1.is_ok())).is_true();
+    assert_that(&released.is_ok()).is_true();
+  }
+
+  #[test]
+  fn rejects_zero_concurrency() {
+    let zero = BulkheadConfig {
+      max_concurrency: 0,
+      ..BulkheadConfig::default()
+    };
+
+    assert_that(&Bulkheads::new(zero.clone()).err().and_then(|problem| problem.details))
+      .is_equal_to(Some("Bulkhead max_concurrency must be at least 1".to_string()));
+    assert_that(
+      &Bulkheads::new(BulkheadConfig::default())
+        .and_then(|bulkheads| bulkheads.with_config_for("orders", zero))
+        .is_err(),
+    )
+    .is_true();
+  }
+}
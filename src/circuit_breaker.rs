@@ -0,0 +1,349 @@
+use crate::{BusinessResult, Problem};
+use log::{info, warn};
+use prometheus::{register, IntGaugeVec, Opts};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+  Closed,
+  Open,
+  HalfOpen,
+}
+
+impl CircuitState {
+  fn as_str(&self) -> &'static str {
+    match self {
+      CircuitState::Closed => "closed",
+      CircuitState::Open => "open",
+      CircuitState::HalfOpen => "half_open",
+    }
+  }
+
+  fn gauge_value(&self) -> i64 {
+    match self {
+      CircuitState::Closed => 0,
+      CircuitState::Open => 1,
+      CircuitState::HalfOpen => 2,
+    }
+  }
+}
+
+/// The circuit opens once at least `minimum_calls` of the last `window_size` calls were made and
+/// `failure_rate_threshold` of them failed. After `open_duration` up to `half_open_calls` trial calls
+/// are let through; if all of them succeed the circuit closes again, otherwise it reopens.
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerConfig {
+  pub failure_rate_threshold: f64,
+  pub minimum_calls: usize,
+  pub window_size: usize,
+  pub open_duration: Duration,
+  pub half_open_calls: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+  fn default() -> Self {
+    CircuitBreakerConfig {
+      failure_rate_threshold: 0.5,
+      minimum_calls: 10,
+      window_size: 20,
+      open_duration: Duration::from_secs(30),
+      half_open_calls: 3,
+    }
+  }
+}
+
+struct Circuit {
+  state: CircuitState,
+  outcomes: VecDeque<bool>,
+  opened_at: Instant,
+  trial_calls: u32,
+  trial_successes: u32,
+}
+
+impl Circuit {
+  fn new(now: Instant) -> Circuit {
+    Circuit {
+      state: CircuitState::Closed,
+      outcomes: VecDeque::new(),
+      opened_at: now,
+      trial_calls: 0,
+      trial_successes: 0,
+    }
+  }
+
+  fn failure_rate(&self) -> f64 {
+    let failures = self.outcomes.iter().filter(|success| !**success).count();
+
+    failures as f64 / self.outcomes.len() as f64
+  }
+}
+
+/// Circuit breakers for all downstream targets, keyed by host or logical service name.
+pub struct CircuitBreakers {
+  default_config: CircuitBreakerConfig,
+  configs: HashMap<String, CircuitBreakerConfig>,
+  circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreakers {
+  pub fn new(default_config: CircuitBreakerConfig) -> CircuitBreakers {
+    CircuitBreakers {
+      default_config,
+      configs: HashMap::new(),
+      circuits: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn with_config_for<S: Into<String>>(mut self, target: S, config: CircuitBreakerConfig) -> Self {
+    self.configs.insert(target.into(), config);
+    self
+  }
+
+  fn config_for(&self, target: &str) -> &CircuitBreakerConfig {
+    self.configs.get(target).unwrap_or(&self.default_config)
+  }
+
+  pub fn state(&self, target: &str) -> CircuitState {
+    match self.circuits.lock() {
+      Ok(circuits) => circuits.get(target).map(|c| c.state).unwrap_or(CircuitState::Closed),
+      Err(_) => CircuitState::Closed,
+    }
+  }
+
+  /// Fails fast with 503 while the circuit of `target` is open, otherwise lets one call through.
+  ///
+  /// The outcome of the call is passed to `CircuitPermit::record`. A permit dropped without it, e.g. because the
+  /// call was cancelled, frees its half open trial slot again.
+  pub fn acquire(&self, target: &str) -> BusinessResult<CircuitPermit<'_>> {
+    self
+      .acquire_at(target, Instant::now())
+      .map(|trial_since| CircuitPermit {
+        breakers: self,
+        target: target.to_string(),
+        trial_since,
+        recorded: false,
+      })
+  }
+
+  pub fn record(&self, target: &str, success: bool) {
+    self.record_at(target, success, Instant::now())
+  }
+
+  /// Returns when the circuit became half open if the call is a trial call.
+  fn acquire_at(&self, target: &str, now: Instant) -> BusinessResult<Option<Instant>> {
+    let config = self.config_for(target);
+    let mut circuits = match self.circuits.lock() {
+      Ok(circuits) => circuits,
+      Err(_) => return Ok(None),
+    };
+    let circuit = circuit_for(&mut circuits, target, now);
+
+    if circuit.state == CircuitState::Open && now.duration_since(circuit.opened_at) >= config.open_duration {
+      transition(target, circuit, CircuitState::HalfOpen, now);
+    }
+    match circuit.state {
+      CircuitState::Closed => Ok(None),
+      CircuitState::HalfOpen if circuit.trial_calls < config.half_open_calls => {
+        circuit.trial_calls += 1;
+        Ok(Some(circuit.opened_at))
+      }
+      _ => Err(Problem::service_unavailable().with_details(format!("Circuit breaker for {} is open", target))),
+    }
+  }
+
+  /// Frees the trial slot of a call without outcome, unless the circuit left that half open state meanwhile.
+  fn release_trial(&self, target: &str, trial_since: Instant) {
+    if let Ok(mut circuits) = self.circuits.lock() {
+      if let Some(circuit) = circuits
+        .get_mut(target)
+        .filter(|circuit| circuit.state == CircuitState::HalfOpen && circuit.opened_at == trial_since)
+      {
+        circuit.trial_calls = circuit.trial_calls.saturating_sub(1);
+      }
+    }
+  }
+
+  fn record_at(&self, target: &str, success: bool, now: Instant) {
+    let config = self.config_for(target);
+    let mut circuits = match self.circuits.lock() {
+      Ok(circuits) => circuits,
+      Err(_) => return,
+    };
+    let circuit = circuit_for(&mut circuits, target, now);
+
+    match circuit.state {
+      CircuitState::Closed => {
+        circuit.outcomes.push_back(success);
+        while circuit.outcomes.len() > config.window_size {
+          circuit.outcomes.pop_front();
+        }
+        if circuit.outcomes.len() >= config.minimum_calls && circuit.failure_rate() >= config.failure_rate_threshold {
+          transition(target, circuit, CircuitState::Open, now);
+        }
+      }
+      CircuitState::HalfOpen if !success => transition(target, circuit, CircuitState::Open, now),
+      CircuitState::HalfOpen => {
+        circuit.trial_successes += 1;
+        if circuit.trial_successes >= config.half_open_calls {
+          transition(target, circuit, CircuitState::Closed, now);
+        }
+      }
+      CircuitState::Open => (),
+    }
+  }
+}
+
+/// A call let through by `CircuitBreakers::acquire`.
+pub struct CircuitPermit<'a> {
+  breakers: &'a CircuitBreakers,
+  target: String,
+  trial_since: Option<Instant>,
+  recorded: bool,
+}
+
+impl CircuitPermit<'_> {
+  pub fn record(mut self, success: bool) {
+    self.recorded = true;
+    self.breakers.record(&self.target, success);
+  }
+}
+
+impl Drop for CircuitPermit<'_> {
+  fn drop(&mut self) {
+    if let (false, Some(trial_since)) = (self.recorded, self.trial_since) {
+      self.breakers.release_trial(&self.target, trial_since);
+    }
+  }
+}
+
+fn circuit_for<'a>(circuits: &'a mut HashMap<String, Circuit>, target: &str, now: Instant) -> &'a mut Circuit {
+  circuits.entry(target.to_string()).or_insert_with(|| {
+    state_gauge()
+      .with_label_values(&[target])
+      .set(CircuitState::Closed.gauge_value());
+    Circuit::new(now)
+  })
+}
+
+fn transition(target: &str, circuit: &mut Circuit, state: CircuitState, now: Instant) {
+  let (from, to) = (circuit.state.as_str(), state.as_str());
+  match state {
+    CircuitState::Open => warn!("Circuit breaker for {} changed from {} to {}", target, from, to),
+    _ => info!("Circuit breaker for {} changed from {} to {}", target, from, to),
+  }
+  circuit.state = state;
+  circuit.outcomes.clear();
+  circuit.opened_at = now;
+  circuit.trial_calls = 0;
+  circuit.trial_successes = 0;
+  state_gauge().with_label_values(&[target]).set(state.gauge_value());
+}
+
+fn state_gauge() -> &'static IntGaugeVec {
+  static GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+
+  GAUGE.get_or_init(|| {
+    let gauge = IntGaugeVec::new(
+      Opts::new(
+        "service_requester_circuit_state",
+        "Circuit breaker state per target (0 closed, 1 open, 2 half open)",
+      ),
+      &["target"],
+    )
+    .unwrap();
+
+    register(Box::new(gauge.clone())).unwrap();
+
+    gauge
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  fn breakers() -> CircuitBreakers {
+    CircuitBreakers::new(CircuitBreakerConfig {
+      failure_rate_threshold: 0.5,
+      minimum_calls: 4,
+      window_size: 4,
+      open_duration: Duration::from_secs(10),
+      half_open_calls: 2,
+    })
+  }
+
+  #[test]
+  fn opens_when_failure_rate_is_exceeded() {
+    let breakers = breakers();
+    let now = Instant::now();
+
+    for success in [true, false, true] {
+      breakers.record_at("orders", success, now);
+    }
+    assert_that(&breakers.state("orders")).is_equal_to(CircuitState::Closed);
+
+    breakers.record_at("orders", false, now);
+    assert_that(&breakers.state("orders")).is_equal_to(CircuitState::Open);
+    assert_that(&breakers.acquire_at("orders", now).unwrap_err().code).is_equal_to(503);
+    assert_that(&breakers.acquire_at("billing", now).is_ok()).is_true();
+  }
+
+  #[test]
+  fn half_open_closes_after_successful_trials() {
+    let breakers = breakers();
+    let now = Instant::now();
+    for _ in 0..4 {
+      breakers.record_at("orders", false, now);
+    }
+    let later = now + Duration::from_secs(10);
+
+    assert_that(&breakers.acquire_at("orders", later).is_ok()).is_true();
+    assert_that(&breakers.acquire_at("orders", later).is_ok()).is_true();
+    assert_that(&breakers.acquire_at("orders", later).is_err()).is_true();
+    assert_that(&breakers.state("orders")).is_equal_to(CircuitState::HalfOpen);
+
+    breakers.record_at("orders", true, later);
+    breakers.record_at("orders", true, later);
+    assert_that(&breakers.state("orders")).is_equal_to(CircuitState::Closed);
+  }
+
+  #[test]
+  fn half_open_reopens_on_failure() {
+    let breakers = breakers();
+    let now = Instant::now();
+    for _ in 0..4 {
+      breakers.record_at("orders", false, now);
+    }
+    let later = now + Duration::from_secs(10);
+
+    assert_that(&breakers.acquire_at("orders", later).is_ok()).is_true();
+    breakers.record_at("orders", false, later);
+
+    assert_that(&breakers.state("orders")).is_equal_to(CircuitState::Open);
+    assert_that(&breakers.acquire_at("orders", later).is_err()).is_true();
+  }
+
+  #[test]
+  fn dropped_trial_calls_free_their_slot() {
+    let breakers = CircuitBreakers::new(CircuitBreakerConfig {
+      minimum_calls: 2,
+      window_size: 2,
+      open_duration: Duration::ZERO,
+      half_open_calls: 1,
+      ..CircuitBreakerConfig::default()
+    });
+    breakers.record("payments", false);
+    breakers.record("payments", false);
+
+    let cancelled = breakers.acquire("payments").unwrap();
+    assert_that(&breakers.acquire("payments").is_err()).is_true();
+    drop(cancelled);
+    breakers.acquire("payments").unwrap().record(true);
+
+    assert_that(&breakers.state("payments")).is_equal_to(CircuitState::Closed);
+    assert_that(&state_gauge().with_label_values(&["payments"]).get()).is_equal_to(0);
+  }
+}
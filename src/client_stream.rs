@@ -0,0 +1,290 @@
+use crate::{bulkhead::BulkheadPermit, ws_try::FromClientResponse, AsyncBusinessResult, BusinessResult, Problem};
+use actix_web::rt::time::{sleep, Instant, Sleep};
+use bytes::Bytes;
+use futures::{future, Future, Stream, StreamExt};
+use reqwest::Response;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// How long `ServiceRequester` lets a streamed body wait for its next chunk, passed along with the response.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ReadTimeout(pub Duration);
+
+/// Fails `body` once no chunk arrived for `timeout`.
+struct IdleTimeout<S> {
+  body: S,
+  timeout: Duration,
+  deadline: Pin<Box<Sleep>>,
+  expired: bool,
+}
+
+impl<S> Stream for IdleTimeout<S>
+where
+  S: Stream<Item = BusinessResult<Bytes>> + Unpin,
+{
+  type Item = BusinessResult<Bytes>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    if self.expired {
+      return Poll::Ready(None);
+    }
+    match self.body.poll_next_unpin(cx) {
+      Poll::Ready(chunk) => {
+        let deadline = Instant::now() + self.timeout;
+        self.deadline.as_mut().reset(deadline);
+        Poll::Ready(chunk)
+      }
+      Poll::Pending => match self.deadline.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+          self.expired = true;
+          Poll::Ready(Some(Err(
+            Problem::for_status(504, "Gateway timeout")
+              .with_details(format!("No data within {} ms", self.timeout.as_millis())),
+          )))
+        }
+        Poll::Pending => Poll::Pending,
+      },
+    }
+  }
+}
+
+/// A response body consumed chunk by chunk as it arrives, instead of being buffered.
+///
+/// The request timeout of the `ServiceRequester` still applies until the body has been read completely, its read
+/// timeout between chunks. The bulkhead slot of the call is held until the stream is dropped.
+pub struct ByteStream {
+  body: Pin<Box<dyn Stream<Item = BusinessResult<Bytes>> + Send>>,
+  _permit: Option<BulkheadPermit>,
+}
+
+impl ByteStream {
+  pub fn new<S>(stream: S) -> ByteStream
+  where
+    S: Stream<Item = BusinessResult<Bytes>> + Send + 'static,
+  {
+    ByteStream {
+      body: Box::pin(stream),
+      _permit: None,
+    }
+  }
+}
+
+impl Stream for ByteStream {
+  type Item = BusinessResult<Bytes>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    self.body.as_mut().poll_next(cx)
+  }
+}
+
+impl FromClientResponse<ByteStream> for ByteStream {
+  fn from_response(mut response: Response) -> AsyncBusinessResult<ByteStream> {
+    let permit = response.extensions_mut().remove::<BulkheadPermit>();
+    let read_timeout = response.extensions_mut().remove::<ReadTimeout>();
+    let body = response.bytes_stream().map(|chunk| chunk.map_err(Problem::from));
+    let stream = match read_timeout {
+      Some(ReadTimeout(timeout)) => ByteStream::new(IdleTimeout {
+        body: Box::pin(body),
+        timeout,
+        deadline: Box::pin(sleep(timeout)),
+        expired: false,
+      }),
+      None => ByteStream::new(body),
+    };
+
+    Box::pin(future::ok(ByteStream {
+      _permit: permit,
+      ..stream
+    }))
+  }
+}
+
+const DEFAULT_MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// Decodes a newline delimited JSON body (NDJSON) one line at a time.
+///
+/// Only as much of the body is read as is needed for the next item, so slow consumers slow down the download.
+/// Empty lines are skipped, the stream ends after the first error. Lines longer than the maximum line length (by
+/// default 1 MiB) are an error, so that a body without line breaks is not buffered completely.
+pub struct JsonLines<T> {
+  body: ByteStream,
+  buffer: Vec<u8>,
+  scanned: usize,
+  max_line_length: usize,
+  done: bool,
+  item: PhantomData<fn() -> T>,
+}
+
+impl<T> JsonLines<T>
+where
+  T: DeserializeOwned,
+{
+  pub fn new(body: ByteStream) -> JsonLines<T> {
+    JsonLines {
+      body,
+      buffer: Vec::new(),
+      scanned: 0,
+      max_line_length: DEFAULT_MAX_LINE_LENGTH,
+      done: false,
+      item: PhantomData,
+    }
+  }
+
+  pub fn with_max_line_length(self, max_line_length: usize) -> Self {
+    JsonLines {
+      max_line_length,
+      ..self
+    }
+  }
+
+  fn line_too_long(&mut self) -> Problem {
+    self.stop();
+    Problem::internal_server_error().with_details(format!("JSON line exceeds {} bytes", self.max_line_length))
+  }
+
+  fn next_line(&mut self) -> Option<Vec<u8>> {
+    match self.buffer[self.scanned..].iter().position(|b| *b == b'\n') {
+      Some(offset) => {
+        let line = self.buffer.drain(..=self.scanned + offset).collect();
+        self.scanned = 0;
+        Some(line)
+      }
+      None if self.done && !self.buffer.is_empty() => {
+        self.scanned = 0;
+        Some(std::mem::take(&mut self.buffer))
+      }
+      None => {
+        self.scanned = self.buffer.len();
+        None
+      }
+    }
+  }
+
+  fn stop(&mut self) {
+    self.done = true;
+    self.buffer.clear();
+    self.scanned = 0;
+  }
+}
+
+impl<T> Stream for JsonLines<T>
+where
+  T: DeserializeOwned,
+{
+  type Item = BusinessResult<T>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    loop {
+      while let Some(line) = self.next_line() {
+        if line.len() > self.max_line_length {
+          return Poll::Ready(Some(Err(self.line_too_long())));
+        }
+        if !line.iter().all(u8::is_ascii_whitespace) {
+          let item = serde_json::from_slice(&line).map_err(Problem::from);
+          if item.is_err() {
+            self.stop();
+          }
+          return Poll::Ready(Some(item));
+        }
+      }
+      if self.done {
+        return Poll::Ready(None);
+      }
+      if self.buffer.len() > self.max_line_length {
+        return Poll::Ready(Some(Err(self.line_too_long())));
+      }
+      match self.body.poll_next_unpin(cx) {
+        Poll::Ready(Some(Ok(chunk))) => self.buffer.extend_from_slice(&chunk),
+        Poll::Ready(Some(Err(problem))) => {
+          self.stop();
+          return Poll::Ready(Some(Err(problem)));
+        }
+        Poll::Ready(None) => self.done = true,
+        Poll::Pending => return Poll::Pending,
+      }
+    }
+  }
+}
+
+impl<T> FromClientResponse<JsonLines<T>> for JsonLines<T>
+where
+  T: DeserializeOwned + 'static,
+{
+  fn from_response(response: Response) -> AsyncBusinessResult<JsonLines<T>> {
+    Box::pin(async move { Ok(JsonLines::new(ByteStream::from_response(response).await?)) })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::stream;
+  use serde_derive::Deserialize;
+  use spectral::prelude::*;
+
+  #[derive(Debug, Deserialize, PartialEq)]
+  struct Order {
+    id: u32,
+  }
+
+  fn chunks(chunks: Vec<BusinessResult<&'static str>>) -> ByteStream {
+    ByteStream::new(stream::iter(
+      chunks
+        .into_iter()
+        .map(|chunk| chunk.map(|chunk| Bytes::from_static(chunk.as_bytes()))),
+    ))
+  }
+
+  #[actix_web::test]
+  async fn decodes_lines_split_across_chunks() {
+    let body = chunks(vec![Ok("{\"id\":1}\n{\"i"), Ok("d\":2}\n\n"), Ok("{\"id\":3}")]);
+
+    let orders = JsonLines::<Order>::new(body).collect::<Vec<_>>().await;
+
+    assert_that(&orders.into_iter().map(Result::unwrap).collect::<Vec<_>>()).is_equal_to(vec![
+      Order { id: 1 },
+      Order { id: 2 },
+      Order { id: 3 },
+    ]);
+  }
+
+  #[actix_web::test]
+  async fn stops_after_mid_stream_errors() {
+    let body = chunks(vec![
+      Ok("{\"id\":1}\n{\"id\":"),
+      Err(Problem::internal_server_error().with_details("connection reset")),
+      Ok("2}\n"),
+    ]);
+
+    let orders = JsonLines::<Order>::new(body).collect::<Vec<_>>().await;
+
+    assert_that(&orders.len()).is_equal_to(2);
+    assert_that(&orders[0].as_ref().ok()).is_equal_to(Some(&Order { id: 1 }));
+    assert_that(&orders[1].as_ref().err().and_then(|p| p.details.clone()))
+      .is_equal_to(Some("connection reset".to_string()));
+  }
+
+  #[actix_web::test]
+  async fn rejects_overlong_lines() {
+    let unterminated = chunks(vec![Ok("{\"id\":1}\n{\"id\":"), Ok("1234567"), Ok("8}\n")]);
+    let complete = chunks(vec![Ok("{\"id\":12345678}\n")]);
+
+    let orders = JsonLines::<Order>::new(unterminated)
+      .with_max_line_length(10)
+      .collect::<Vec<_>>()
+      .await;
+    let single = JsonLines::<Order>::new(complete)
+      .with_max_line_length(10)
+      .collect::<Vec<_>>()
+      .await;
+
+    assert_that(&orders.len()).is_equal_to(2);
+    assert_that(&orders[1].as_ref().err().and_then(|p| p.details.clone()))
+      .is_equal_to(Some("JSON line exceeds 10 bytes".to_string()));
+    assert_that(&single.len()).is_equal_to(1);
+    assert_that(&single[0].is_err()).is_true();
+  }
+}
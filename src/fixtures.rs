@@ -0,0 +1,366 @@
+use crate::{BusinessResult, Problem};
+use bytes::Bytes;
+use log::error;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Request, Response, ResponseBuilderExt, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use url::Url;
+
+const REDACTED: &str = "REDACTED";
+
+/// Environment variable that selects `record` or `replay` (the default) for `Fixtures::from_env`.
+pub const FIXTURES_MODE_ENV: &str = "SERVICE_REQUESTER_FIXTURES";
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct RecordedMessage {
+  headers: Vec<(String, String)>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  body: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  body_hex: Option<String>,
+}
+
+impl RecordedMessage {
+  fn new(headers: &HeaderMap, body: Option<&[u8]>, redacted: &[HeaderName]) -> RecordedMessage {
+    let headers = headers
+      .iter()
+      .map(|(name, value)| {
+        let value = match redacted.contains(name) {
+          true => REDACTED.to_string(),
+          false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        };
+        (name.to_string(), value)
+      })
+      .collect();
+    let (body, body_hex) = match body.map(std::str::from_utf8) {
+      Some(Ok(text)) => (Some(text.to_string()), None),
+      Some(Err(_)) => (None, body.map(hex::encode)),
+      None => (None, None),
+    };
+
+    RecordedMessage {
+      headers,
+      body,
+      body_hex,
+    }
+  }
+
+  fn body(&self) -> Bytes {
+    match (&self.body, &self.body_hex) {
+      (Some(body), _) => Bytes::from(body.clone()),
+      (None, Some(body_hex)) => hex::decode(body_hex).map(Bytes::from).unwrap_or_default(),
+      (None, None) => Bytes::new(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct Interaction {
+  method: String,
+  url: String,
+  request: RecordedMessage,
+  status: u16,
+  response: RecordedMessage,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FixtureMode {
+  Record,
+  Replay,
+}
+
+#[derive(Default)]
+struct Recorded {
+  interactions: Vec<Interaction>,
+  replayed: Vec<bool>,
+}
+
+/// Requests and responses of a `ServiceRequester` stored in a JSON file, to replay them in tests without network.
+///
+/// In record mode responses are kept with credentials redacted, and written to the file by `save` or once the
+/// fixtures are dropped. In replay mode each request is answered by the first recorded interaction that matches and
+/// was not replayed yet, or by the last matching one. Requests match by method, path and query by default.
+pub struct Fixtures {
+  path: PathBuf,
+  mode: FixtureMode,
+  match_method: bool,
+  match_host: bool,
+  match_query: bool,
+  match_body: bool,
+  redacted_headers: Vec<HeaderName>,
+  redacted_query_params: Vec<String>,
+  recorded: Mutex<Recorded>,
+}
+
+impl Fixtures {
+  fn new(path: PathBuf, mode: FixtureMode, interactions: Vec<Interaction>) -> Fixtures {
+    Fixtures {
+      path,
+      mode,
+      match_method: true,
+      match_host: false,
+      match_query: true,
+      match_body: false,
+      redacted_headers: [
+        "authorization",
+        "cookie",
+        "proxy-authorization",
+        "set-cookie",
+        "x-api-key",
+        "x-auth-token",
+      ]
+      .iter()
+      .map(|name| HeaderName::from_static(name))
+      .collect(),
+      redacted_query_params: ["access_token", "api_key", "token"]
+        .iter()
+        .map(|name| name.to_string())
+        .collect(),
+      recorded: Mutex::new(Recorded {
+        replayed: vec![false; interactions.len()],
+        interactions,
+      }),
+    }
+  }
+
+  /// Starts a new recording in `path`, replacing an existing one.
+  pub fn record<P: AsRef<Path>>(path: P) -> Fixtures {
+    Fixtures::new(path.as_ref().to_path_buf(), FixtureMode::Record, vec![])
+  }
+
+  pub fn replay<P: AsRef<Path>>(path: P) -> BusinessResult<Fixtures> {
+    let interactions = serde_json::from_slice(&fs::read(path.as_ref())?)?;
+
+    Ok(Fixtures::new(
+      path.as_ref().to_path_buf(),
+      FixtureMode::Replay,
+      interactions,
+    ))
+  }
+
+  /// Records if `SERVICE_REQUESTER_FIXTURES=record`, and replays otherwise.
+  pub fn from_env<P: AsRef<Path>>(path: P) -> BusinessResult<Fixtures> {
+    match std::env::var(FIXTURES_MODE_ENV).as_deref() {
+      Ok("record") => Ok(Fixtures::record(path)),
+      _ => Fixtures::replay(path),
+    }
+  }
+
+  pub fn mode(&self) -> FixtureMode {
+    self.mode
+  }
+
+  pub fn matching_host(mut self) -> Self {
+    self.match_host = true;
+    self
+  }
+
+  pub fn ignoring_method(mut self) -> Self {
+    self.match_method = false;
+    self
+  }
+
+  pub fn ignoring_query(mut self) -> Self {
+    self.match_query = false;
+    self
+  }
+
+  /// Also matches request bodies, comparing JSON bodies regardless of formatting and key order.
+  pub fn matching_body(mut self) -> Self {
+    self.match_body = true;
+    self
+  }
+
+  pub fn redact_header(mut self, name: HeaderName) -> Self {
+    self.redacted_headers.push(name);
+    self
+  }
+
+  /// Redacts the values of query parameter `name` in recorded URLs, by default `access_token`, `api_key` and
+  /// `token`. Requests still match as the same parameters are redacted before comparing.
+  pub fn redact_query_param<S: Into<String>>(mut self, name: S) -> Self {
+    self.redacted_query_params.push(name.into());
+    self
+  }
+
+  fn redact_url(&self, url: &Url) -> Url {
+    if !url
+      .query_pairs()
+      .any(|(name, _)| self.redacted_query_params.iter().any(|param| *param == name))
+    {
+      return url.clone();
+    }
+    let pairs = url
+      .query_pairs()
+      .map(|(name, value)| {
+        let value = match self.redacted_query_params.iter().any(|param| *param == name) {
+          true => REDACTED.to_string(),
+          false => value.into_owned(),
+        };
+        (name.into_owned(), value)
+      })
+      .collect::<Vec<_>>();
+    let mut redacted = url.clone();
+    redacted.query_pairs_mut().clear().extend_pairs(pairs);
+
+    redacted
+  }
+
+  /// Sends `request` with `send` when recording, or answers it from the recording.
+  ///
+  /// A request without recorded response fails with a `Fixture missing` problem instead of an error response, so
+  /// that it is neither retried nor counted by circuit breakers.
+  pub(crate) async fn execute<S, F>(
+    &self,
+    request: Request,
+    send: S,
+  ) -> BusinessResult<Result<Response, reqwest::Error>>
+  where
+    S: FnOnce(Request) -> F,
+    F: Future<Output = Result<Response, reqwest::Error>>,
+  {
+    if self.mode == FixtureMode::Replay {
+      return self.replay_response(&request).map(Ok);
+    }
+    let url = self.redact_url(request.url()).to_string();
+    let method = request.method().to_string();
+    let recorded_request = RecordedMessage::new(
+      request.headers(),
+      request.body().and_then(|body| body.as_bytes()),
+      &self.redacted_headers,
+    );
+    let response = match send(request).await {
+      Ok(response) => response,
+      Err(error) => return Ok(Err(error)),
+    };
+    let status = response.status();
+    let headers = response.headers().clone();
+    let response_url = response.url().clone();
+    let body = match response.bytes().await {
+      Ok(body) => body,
+      Err(error) => return Ok(Err(error)),
+    };
+    self.push(Interaction {
+      method,
+      url,
+      request: recorded_request,
+      status: status.as_u16(),
+      response: RecordedMessage::new(&headers, Some(&body), &self.redacted_headers),
+    })?;
+
+    Ok(Ok(to_response(status, headers, response_url, body)))
+  }
+
+  /// Writes the recording to its file, which also happens when the fixtures are dropped.
+  pub fn save(&self) -> BusinessResult<()> {
+    if self.mode != FixtureMode::Record {
+      return Ok(());
+    }
+    let recorded = self.lock_recorded()?;
+
+    Ok(fs::write(
+      &self.path,
+      serde_json::to_vec_pretty(&recorded.interactions)?,
+    )?)
+  }
+
+  fn push(&self, interaction: Interaction) -> BusinessResult<()> {
+    let mut recorded = self.lock_recorded()?;
+    recorded.interactions.push(interaction);
+    recorded.replayed.push(true);
+
+    Ok(())
+  }
+
+  fn lock_recorded(&self) -> BusinessResult<MutexGuard<'_, Recorded>> {
+    self
+      .recorded
+      .lock()
+      .map_err(|_| Problem::internal_server_error().with_details("Fixtures lock poisoned"))
+  }
+
+  fn replay_response(&self, request: &Request) -> BusinessResult<Response> {
+    let mut recorded = self.lock_recorded()?;
+    let matching = (0..recorded.interactions.len())
+      .filter(|index| self.matches(&recorded.interactions[*index], request))
+      .collect::<Vec<_>>();
+    let index = matching
+      .iter()
+      .find(|index| !recorded.replayed[**index])
+      .or_else(|| matching.last())
+      .copied()
+      .ok_or_else(|| {
+        Problem::for_status(500, "Fixture missing").with_details(format!(
+          "No recorded response for {} {} in {}",
+          request.method(),
+          self.redact_url(request.url()),
+          self.path.display()
+        ))
+      })?;
+    recorded.replayed[index] = true;
+    let interaction = &recorded.interactions[index];
+    let headers = interaction
+      .response
+      .headers
+      .iter()
+      .filter_map(|(name, value)| {
+        Some((
+          HeaderName::from_bytes(name.as_bytes()).ok()?,
+          HeaderValue::from_str(value).ok()?,
+        ))
+      })
+      .collect();
+
+    Ok(to_response(
+      StatusCode::from_u16(interaction.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+      headers,
+      request.url().clone(),
+      interaction.response.body(),
+    ))
+  }
+
+  fn matches(&self, interaction: &Interaction, request: &Request) -> bool {
+    let recorded_url = match Url::parse(&interaction.url) {
+      Ok(url) => url,
+      Err(_) => return false,
+    };
+    let url = self.redact_url(request.url());
+
+    (!self.match_method || interaction.method == request.method().as_str())
+      && (!self.match_host || (recorded_url.host_str(), recorded_url.port()) == (url.host_str(), url.port()))
+      && recorded_url.path() == url.path()
+      && (!self.match_query || recorded_url.query() == url.query())
+      && (!self.match_body
+        || normalized_body(interaction.request.body().as_ref())
+          == normalized_body(request.body().and_then(|body| body.as_bytes()).unwrap_or_default()))
+  }
+}
+
+impl Drop for Fixtures {
+  fn drop(&mut self) {
+    if let Err(problem) = self.save() {
+      error!("Unable to save fixtures to {}: {}", self.path.display(), problem);
+    }
+  }
+}
+
+fn normalized_body(body: &[u8]) -> Value {
+  serde_json::from_slice(body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).trim().to_string()))
+}
+
+fn to_response(status: StatusCode, headers: HeaderMap, url: Url, body: Bytes) -> Response {
+  let mut response = http::Response::builder().status(status).url(url);
+  if let Some(response_headers) = response.headers_mut() {
+    *response_headers = headers;
+  }
+
+  match response.body(body.clone()) {
+    Ok(response) => response.into(),
+    Err(_) => http::Response::new(body).into(),
+  }
+}
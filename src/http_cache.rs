@@ -0,0 +1,488 @@
+use crate::BusinessResult;
+use bytes::Bytes;
+use prometheus::{register, IntCounterVec, Opts};
+use reqwest::header::{
+  HeaderMap, HeaderValue, ACCEPT, AGE, AUTHORIZATION, CACHE_CONTROL, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+  LAST_MODIFIED, VARY,
+};
+use reqwest::{Method, Request, Response, ResponseBuilderExt, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use url::Url;
+
+fn cache_counter() -> &'static IntCounterVec {
+  static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+
+  COUNTER.get_or_init(|| {
+    let counter = IntCounterVec::new(
+      Opts::new(
+        "service_requester_cache_total",
+        "Lookups in the HTTP cache of outbound service requests",
+      ),
+      &["target", "result"],
+    )
+    .unwrap();
+
+    register(Box::new(counter.clone())).unwrap();
+
+    counter
+  })
+}
+
+/// A cached response, also kept by a revalidating request in case it is evicted meanwhile.
+#[derive(Clone)]
+pub(crate) struct Entry {
+  status: StatusCode,
+  headers: HeaderMap,
+  url: Url,
+  body: Bytes,
+  fresh_until: Instant,
+  size: usize,
+  last_used: u64,
+}
+
+impl Entry {
+  fn to_response(&self) -> Response {
+    let mut response = http::Response::builder().status(self.status).url(self.url.clone());
+    if let Some(headers) = response.headers_mut() {
+      *headers = self.headers.clone();
+    }
+
+    match response.body(self.body.clone()) {
+      Ok(response) => response.into(),
+      Err(_) => http::Response::new(self.body.clone()).into(),
+    }
+  }
+}
+
+/// Outcome of looking up a request in the cache, see `HttpCache::lookup`.
+pub(crate) enum CacheLookup {
+  /// The request cannot be served from the cache.
+  Bypass,
+  Fresh(Response),
+  /// Not cached yet, or stale and revalidated by the request, under the given key.
+  Miss(String, Option<Box<Entry>>),
+  /// A successful response means the URL changed.
+  Unsafe(Url),
+}
+
+/// A private, in-memory HTTP cache of `GET` responses for `ServiceRequester`, bounded to `max_bytes`.
+///
+/// Responses are fresh as long as `Cache-Control: max-age` or `Expires` say so; stale responses with an `ETag`
+/// or `Last-Modified` are revalidated with a conditional request. Entries are separate per caller identity,
+/// other `Vary` headers are not taken into account and `Vary: *` is not cached.
+pub struct HttpCache {
+  max_bytes: usize,
+  entries: Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+  entries: HashMap<String, Entry>,
+  /// Keys by `last_used`, least recently used first.
+  order: BTreeMap<u64, String>,
+  size: usize,
+  clock: u64,
+}
+
+impl CacheEntries {
+  fn touch(&mut self, key: &str) -> Option<&mut Entry> {
+    self.clock += 1;
+    let entry = self.entries.get_mut(key)?;
+    self.order.remove(&entry.last_used);
+    entry.last_used = self.clock;
+    self.order.insert(self.clock, key.to_string());
+
+    Some(entry)
+  }
+
+  fn insert(&mut self, key: &str, mut entry: Entry, max_bytes: usize) {
+    self.remove(key);
+    entry.size = key.len() + entry.url.as_str().len() + entry.body.len() + header_size(&entry.headers);
+    if entry.size > max_bytes {
+      return;
+    }
+    self.clock += 1;
+    entry.last_used = self.clock;
+    self.size += entry.size;
+    self.order.insert(self.clock, key.to_string());
+    self.entries.insert(key.to_string(), entry);
+    self.evict_until(max_bytes);
+  }
+
+  fn remove(&mut self, key: &str) -> Option<Entry> {
+    let entry = self.entries.remove(key)?;
+    self.size -= entry.size;
+    self.order.remove(&entry.last_used);
+
+    Some(entry)
+  }
+
+  fn evict_until(&mut self, max_bytes: usize) {
+    while self.size > max_bytes {
+      match self.order.pop_first() {
+        Some((_, key)) => {
+          self.remove(&key);
+        }
+        None => break,
+      }
+    }
+  }
+}
+
+/// Whether `url` is `prefix` or below it, e.g. `/orders/1?full` is below `/orders` but `/orders-archive` is not.
+fn is_below(url: &str, prefix: &str) -> bool {
+  match url.strip_prefix(prefix.trim_end_matches('/')) {
+    Some(rest) => rest.is_empty() || rest.starts_with(['/', '?', '#']),
+    None => false,
+  }
+}
+
+impl HttpCache {
+  pub fn new(max_bytes: usize) -> HttpCache {
+    HttpCache {
+      max_bytes,
+      entries: Mutex::new(CacheEntries::default()),
+    }
+  }
+
+  /// Drops all entries of `prefix` and the paths below it, e.g. after changing the underlying data.
+  pub fn invalidate_prefix(&self, prefix: &str) {
+    if let Ok(mut entries) = self.entries.lock() {
+      let keys = entries
+        .entries
+        .keys()
+        .filter(|key| key.rsplit_once(' ').is_some_and(|(url, _)| is_below(url, prefix)))
+        .cloned()
+        .collect::<Vec<_>>();
+      for key in keys {
+        entries.remove(&key);
+      }
+    }
+  }
+
+  pub fn size(&self) -> usize {
+    self.entries.lock().map(|entries| entries.size).unwrap_or_default()
+  }
+
+  /// Looks `request` up, adding conditional headers if a stale entry can be revalidated.
+  pub(crate) fn lookup(&self, request: &mut Request, target: &str) -> CacheLookup {
+    if request.method() != Method::GET {
+      return match request.method().is_safe() {
+        true => CacheLookup::Bypass,
+        false => CacheLookup::Unsafe(request.url().clone()),
+      };
+    }
+    let key = cache_key(request);
+    let mut entries = match self.entries.lock() {
+      Ok(entries) => entries,
+      Err(_) => return CacheLookup::Bypass,
+    };
+    let mut stale = None;
+
+    if let Some(entry) = entries.touch(&key) {
+      if entry.fresh_until > Instant::now() {
+        cache_counter().with_label_values(&[target, "hit"]).inc();
+        return CacheLookup::Fresh(entry.to_response());
+      }
+      if let Some(etag) = entry.headers.get(ETAG) {
+        request.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+      }
+      if let Some(last_modified) = entry.headers.get(LAST_MODIFIED) {
+        request.headers_mut().insert(IF_MODIFIED_SINCE, last_modified.clone());
+      }
+      stale = Some(Box::new(entry.clone()));
+    }
+    cache_counter().with_label_values(&[target, "miss"]).inc();
+
+    CacheLookup::Miss(key, stale)
+  }
+
+  /// Serves a `304 Not Modified` from the cache and stores cacheable responses, buffering their body.
+  ///
+  /// Responses without a `Content-Length`, like streamed ones, or longer than the whole cache are passed through.
+  ///
+  /// The `stale` entry the request was revalidating answers a `304` even if it has been evicted meanwhile.
+  pub(crate) async fn update(
+    &self,
+    key: &str,
+    stale: Option<Box<Entry>>,
+    response: Response,
+    target: &str,
+  ) -> BusinessResult<Response> {
+    let policy = CachePolicy::of(response.headers());
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+      if let Ok(mut entries) = self.entries.lock() {
+        if let Some(mut entry) = entries.remove(key).or(stale.map(|stale| *stale)) {
+          for (name, value) in response.headers() {
+            entry.headers.insert(name, value.clone());
+          }
+          entry.fresh_until = policy.fresh_until();
+          let revalidated = entry.to_response();
+          entries.insert(key, entry, self.max_bytes);
+          cache_counter().with_label_values(&[target, "revalidated"]).inc();
+          return Ok(revalidated);
+        }
+      }
+      return Ok(response);
+    }
+    let fits = response
+      .content_length()
+      .map(|length| length <= self.max_bytes as u64)
+      .unwrap_or(false);
+    if response.status() != StatusCode::OK || !policy.storable(response.headers()) || !fits {
+      if response.status().is_success() {
+        if let Ok(mut entries) = self.entries.lock() {
+          entries.remove(key);
+        }
+      }
+      return Ok(response);
+    }
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let url = response.url().clone();
+    let body = response.bytes().await?;
+    let entry = Entry {
+      status,
+      headers,
+      url,
+      body,
+      fresh_until: policy.fresh_until(),
+      size: 0,
+      last_used: 0,
+    };
+    let response = entry.to_response();
+
+    if let Ok(mut entries) = self.entries.lock() {
+      entries.insert(key, entry, self.max_bytes);
+    }
+
+    Ok(response)
+  }
+}
+
+/// The URL and a hash of the headers identifying the caller, so responses are never served to someone else.
+fn cache_key(request: &Request) -> String {
+  let mut identity = Sha256::new();
+  for (name, value) in request.headers() {
+    if name.as_str().starts_with("x-auth-") || name == AUTHORIZATION || name == ACCEPT {
+      identity.update(name.as_str());
+      identity.update(b":");
+      identity.update(value.as_bytes());
+      identity.update(b"\n");
+    }
+  }
+
+  format!("{} {}", request.url(), hex::encode(identity.finalize()))
+}
+
+fn header_size(headers: &HeaderMap) -> usize {
+  headers
+    .iter()
+    .map(|(name, value)| name.as_str().len() + value.len())
+    .sum()
+}
+
+struct CachePolicy {
+  no_store: bool,
+  freshness: Option<Duration>,
+}
+
+impl CachePolicy {
+  fn of(headers: &HeaderMap) -> CachePolicy {
+    let directives = headers
+      .get_all(CACHE_CONTROL)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .map(|directive| directive.trim().to_ascii_lowercase())
+      .collect::<Vec<_>>();
+    let no_store = directives.iter().any(|directive| directive == "no-store");
+    let no_cache = directives.iter().any(|directive| directive == "no-cache");
+    let max_age = directives
+      .iter()
+      .find_map(|directive| directive.strip_prefix("max-age="))
+      .and_then(|seconds| seconds.trim_matches('"').parse::<u64>().ok())
+      .map(Duration::from_secs);
+    let expires = || {
+      let expires = httpdate::parse_http_date(header_str(headers.get(EXPIRES))?).ok()?;
+      Some(expires.duration_since(SystemTime::now()).unwrap_or_default())
+    };
+    let age = header_str(headers.get(AGE))
+      .and_then(|age| age.parse::<u64>().ok())
+      .map(Duration::from_secs)
+      .unwrap_or_default();
+    let freshness = match no_cache {
+      true => None,
+      false => max_age.map(|max_age| max_age.saturating_sub(age)).or_else(expires),
+    };
+
+    CachePolicy { no_store, freshness }
+  }
+
+  fn storable(&self, headers: &HeaderMap) -> bool {
+    let varies_on_everything = headers
+      .get_all(VARY)
+      .iter()
+      .any(|value| value.to_str().map(|value| value.contains('*')).unwrap_or(false));
+    let validated = headers.contains_key(ETAG) || headers.contains_key(LAST_MODIFIED);
+    let fresh = self.freshness.map(|freshness| !freshness.is_zero()).unwrap_or(false);
+
+    !self.no_store && !varies_on_everything && (fresh || validated)
+  }
+
+  fn fresh_until(&self) -> Instant {
+    Instant::now() + self.freshness.unwrap_or_default()
+  }
+}
+
+fn header_str(value: Option<&HeaderValue>) -> Option<&str> {
+  value.and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+    values
+      .iter()
+      .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+      .collect()
+  }
+
+  #[test]
+  fn follows_cache_control() {
+    let max_age = CachePolicy::of(&headers(&[("cache-control", "public, max-age=60"), ("age", "20")]));
+    let no_cache = CachePolicy::of(&headers(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]));
+    let no_store = CachePolicy::of(&headers(&[("cache-control", "no-store, max-age=60")]));
+
+    assert_that(&max_age.freshness).is_equal_to(Some(Duration::from_secs(40)));
+    assert_that(&no_cache.freshness).is_none();
+    assert_that(&no_cache.storable(&headers(&[("etag", "\"v1\"")]))).is_true();
+    assert_that(&no_store.storable(&headers(&[]))).is_false();
+    assert_that(&CachePolicy::of(&headers(&[])).storable(&headers(&[]))).is_false();
+  }
+
+  fn get(url: &str) -> Request {
+    Request::new(Method::GET, url.parse().unwrap())
+  }
+
+  fn response(status: u16, headers: &[(&'static str, &'static str)], body: &'static str) -> Response {
+    let mut response = http::Response::builder().status(status);
+    for (name, value) in headers {
+      response = response.header(*name, *value);
+    }
+    response.body(body).unwrap().into()
+  }
+
+  async fn store(cache: &HttpCache, url: &str, headers: &[(&'static str, &'static str)]) {
+    if let CacheLookup::Miss(key, stale) = cache.lookup(&mut get(url), "cache-test") {
+      cache
+        .update(&key, stale, response(200, headers, "body"), "cache-test")
+        .await
+        .unwrap();
+    }
+  }
+
+  fn is_fresh(cache: &HttpCache, url: &str) -> bool {
+    matches!(cache.lookup(&mut get(url), "cache-test"), CacheLookup::Fresh(_))
+  }
+
+  #[actix_web::test]
+  async fn invalidates_whole_path_segments() {
+    let cache = HttpCache::new(64 * 1024);
+    for url in [
+      "http://orders/orders",
+      "http://orders/orders/1?full",
+      "http://orders/orders-archive",
+    ] {
+      store(&cache, url, &[("cache-control", "max-age=60")]).await;
+    }
+
+    cache.invalidate_prefix("http://orders/orders");
+
+    assert_that(&is_fresh(&cache, "http://orders/orders")).is_false();
+    assert_that(&is_fresh(&cache, "http://orders/orders/1?full")).is_false();
+    assert_that(&is_fresh(&cache, "http://orders/orders-archive")).is_true();
+  }
+
+  #[actix_web::test]
+  async fn evicts_least_recently_used() {
+    let cache = HttpCache::new(64 * 1024);
+    store(&cache, "http://orders/1", &[("cache-control", "max-age=60")]).await;
+    let entry_size = cache.size();
+    let cache = HttpCache::new(entry_size * 2);
+    store(&cache, "http://orders/1", &[("cache-control", "max-age=60")]).await;
+    store(&cache, "http://orders/2", &[("cache-control", "max-age=60")]).await;
+
+    assert_that(&is_fresh(&cache, "http://orders/1")).is_true();
+    store(&cache, "http://orders/3", &[("cache-control", "max-age=60")]).await;
+
+    assert_that(&is_fresh(&cache, "http://orders/1")).is_true();
+    assert_that(&is_fresh(&cache, "http://orders/2")).is_false();
+    assert_that(&is_fresh(&cache, "http://orders/3")).is_true();
+    assert_that(&cache.size()).is_equal_to(entry_size * 2);
+  }
+
+  #[actix_web::test]
+  async fn passes_streamed_and_oversized_responses_through() {
+    let cache = HttpCache::new(3);
+    let streamed = http::Response::builder()
+      .header("cache-control", "max-age=60")
+      .body(reqwest::Body::wrap_stream(futures::stream::iter(vec![
+        Ok::<_, std::io::Error>("bo"),
+        Ok("dy"),
+      ])))
+      .unwrap()
+      .into();
+
+    for (url, response) in [
+      (
+        "http://orders/1",
+        response(200, &[("cache-control", "max-age=60")], "body"),
+      ),
+      ("http://orders/2", streamed),
+    ] {
+      let passed = match cache.lookup(&mut get(url), "cache-test") {
+        CacheLookup::Miss(key, stale) => cache.update(&key, stale, response, "cache-test").await.unwrap(),
+        _ => panic!("expected a miss"),
+      };
+
+      assert_that(&passed.text().await.unwrap().as_str()).is_equal_to("body");
+      assert_that(&is_fresh(&cache, url)).is_false();
+    }
+  }
+
+  #[actix_web::test]
+  async fn revalidates_entries_evicted_meanwhile() {
+    let cache = HttpCache::new(64 * 1024);
+    store(&cache, "http://orders/1", &[("etag", "\"v1\"")]).await;
+    let mut request = get("http://orders/1");
+    let lookup = cache.lookup(&mut request, "cache-test");
+    cache.invalidate_prefix("http://orders/1");
+
+    let revalidated = match lookup {
+      CacheLookup::Miss(key, stale) => cache
+        .update(
+          &key,
+          stale,
+          response(304, &[("cache-control", "max-age=60")], ""),
+          "cache-test",
+        )
+        .await
+        .unwrap(),
+      _ => panic!("expected a miss"),
+    };
+
+    assert_that(&request.headers().get(IF_NONE_MATCH).map(|etag| etag.as_bytes())).is_equal_to(Some(&b"\"v1\""[..]));
+    assert_that(&revalidated.status()).is_equal_to(StatusCode::OK);
+    assert_that(&revalidated.text().await.unwrap().as_str()).is_equal_to("body");
+    assert_that(&is_fresh(&cache, "http://orders/1")).is_true();
+  }
+}
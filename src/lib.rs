@@ -1,23 +1,47 @@
 #![crate_type = "lib"]
 
+pub mod api_key;
+pub mod audit;
 pub mod auth_middleware;
+pub mod bulkhead;
 pub mod business_result;
+pub mod circuit_breaker;
+pub mod client_stream;
 pub mod elasticsearch;
 #[cfg(test)]
 pub mod elasticsearch_test;
+pub mod fixtures;
+pub mod http_cache;
+mod line_writer;
 #[cfg(feature = "with-slog")]
 pub mod logging_slog;
 pub mod metrics;
+pub mod mock_requester;
+pub mod pagination;
 mod problem;
+pub mod rate_limit;
+pub mod rbac;
+pub mod request_body;
+pub mod request_id;
+pub mod requester;
+pub mod requester_metrics;
+pub mod response_meta;
+pub mod retry;
 pub mod serde_field_value;
 mod service_requester;
+mod service_requester_builder;
 #[cfg(test)]
 mod service_requester_test;
+pub mod service_resolver;
 pub mod status;
 pub mod subject;
+pub mod tls;
+pub mod trace;
 pub mod types;
+pub mod url_template;
 pub mod ws_try;
 
 pub use crate::business_result::{AsyncBusinessResult, BusinessResult, BusinessResultExt};
 pub use crate::problem::*;
 pub use crate::service_requester::*;
+pub use crate::service_requester_builder::*;
@@ -0,0 +1,81 @@
+use crate::Problem;
+use log::error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+/// Appends lines to a file from a background thread, so that writers never wait for the disk.
+///
+/// Lines are flushed whenever no further line is pending, and all of them once the writer is dropped.
+pub(crate) struct LineWriter {
+  lines: Option<mpsc::Sender<Vec<u8>>>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl LineWriter {
+  pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<LineWriter, Problem> {
+    let file = OpenOptions::new().create(true).append(true).open(path.as_ref())?;
+    let name = path.as_ref().display().to_string();
+    let (lines, received) = mpsc::channel();
+    let thread = thread::spawn(move || write_lines(file, &name, received));
+
+    Ok(LineWriter {
+      lines: Some(lines),
+      thread: Some(thread),
+    })
+  }
+
+  /// Queues `line`, a line break is appended.
+  pub(crate) fn write(&self, mut line: Vec<u8>) {
+    line.push(b'\n');
+    if let Some(Err(_)) = self.lines.as_ref().map(|lines| lines.send(line)) {
+      error!("Line writer has stopped");
+    }
+  }
+}
+
+impl Drop for LineWriter {
+  fn drop(&mut self) {
+    drop(self.lines.take());
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+fn write_lines(file: File, name: &str, lines: mpsc::Receiver<Vec<u8>>) {
+  let mut file = BufWriter::new(file);
+
+  while let Ok(line) = lines.recv() {
+    let mut result = file.write_all(&line);
+    for line in lines.try_iter() {
+      result = result.and_then(|_| file.write_all(&line));
+    }
+    if let Err(err) = result.and_then(|_| file.flush()) {
+      error!("Unable to write to {}: {}", name, err);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn writes_all_lines_before_dropping() {
+    let path = std::env::temp_dir().join(format!("microtools-{}-lines.log", std::process::id()));
+    let writer = LineWriter::open(&path).unwrap();
+
+    for line in ["first", "second"] {
+      writer.write(line.as_bytes().to_vec());
+    }
+    drop(writer);
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_that(&written.as_str()).is_equal_to("first\nsecond\n");
+  }
+}
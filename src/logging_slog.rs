@@ -1,6 +1,25 @@
-use slog::{self, slog_o, Drain};
+use crate::request_id::RequestId;
+use slog::{self, slog_o, Drain, OwnedKV, OwnedKVList, Record, SingleKV};
 use std::env;
 
+/// Adds the id of the current request to each record before passing it on to `drain`.
+///
+/// The id is taken on the logging thread, as drains like `slog_async::Async` serialize on a worker thread.
+pub struct RequestIdDrain<D>(pub D);
+
+impl<D: Drain> Drain for RequestIdDrain<D> {
+  type Ok = D::Ok;
+  type Err = D::Err;
+
+  fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+    let request_id = SingleKV("request_id", RequestId::current().map(|id| id.0));
+
+    self
+      .0
+      .log(record, &OwnedKVList::from(OwnedKV((request_id, values.clone()))))
+  }
+}
+
 pub fn default_json_drain() -> slog_async::Async {
   let drain = slog_json::Json::new(std::io::stdout())
     .add_key_value(slog_o!(
@@ -30,10 +49,58 @@ pub fn default_json_drain() -> slog_async::Async {
 pub fn default_root_logger(process_name: &'static str, version: &'static str) -> slog::Logger {
   let drain = default_json_drain();
   slog::Logger::root(
-    drain.fuse(),
+    RequestIdDrain(drain).fuse(),
     slog_o!(
       "version" => version,
       "process" => process_name,
     ),
   )
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use slog::{slog_info, Key, Serializer, KV};
+  use spectral::prelude::*;
+  use std::fmt::Arguments;
+  use std::sync::{Arc, Mutex};
+
+  #[derive(Clone, Default)]
+  struct Collected(Arc<Mutex<Vec<String>>>);
+
+  impl Serializer for Collected {
+    fn emit_arguments(&mut self, key: Key, value: &Arguments) -> slog::Result {
+      self.0.lock().unwrap().push(format!("{}={}", key, value));
+      Ok(())
+    }
+  }
+
+  impl Drain for Collected {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), slog::Never> {
+      let _ = values.serialize(record, &mut self.clone());
+      Ok(())
+    }
+  }
+
+  #[actix_web::test]
+  async fn logs_request_id_through_async_drain() {
+    let collected = Collected::default();
+    let logger = slog::Logger::root(
+      RequestIdDrain(slog_async::Async::default(collected.clone())).fuse(),
+      slog_o!(),
+    );
+
+    RequestId::scope(RequestId("abc-123".to_string()), async {
+      slog_info!(logger, "within request");
+    })
+    .await;
+    slog_info!(logger, "outside request");
+    drop(logger);
+
+    assert_that(&*collected.0.lock().unwrap())
+      .is_equal_to(vec!["request_id=abc-123".to_string(), "request_id=".to_string()]);
+  }
+}
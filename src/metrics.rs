@@ -1,7 +1,8 @@
 use super::Problem;
+use crate::trace::{Span, SpanKind};
 use actix_web::{web, HttpResponse, Resource, ResponseError};
 use futures::Future;
-use prometheus::{gather, register, Encoder, HistogramOpts, HistogramVec, TextEncoder};
+use prometheus::{gather, register, Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, TextEncoder};
 use std::time::Instant;
 
 pub fn metrics_resource() -> Resource {
@@ -130,7 +131,15 @@ impl TimedActions {
   {
     let histogram = self.histogram.clone();
     let start = Instant::now();
-    let result = f.await;
+    let result = match Span::start_child(action, SpanKind::Internal) {
+      Some(mut span) => {
+        let result = span.in_scope(f).await;
+        span.set_error(result.is_err());
+        span.end();
+        result
+      }
+      None => f.await,
+    };
 
     let outcome = if result.is_ok() { "ok" } else { "err" };
 
@@ -145,22 +154,42 @@ impl TimedActions {
     F: FnOnce() -> Result<U, E>,
   {
     let start = Instant::now();
-
-    match f() {
-      Ok(value) => {
-        self
-          .histogram
-          .with_label_values(&[action, "ok"])
-          .observe(seconds_since(&start));
-        Ok(value)
-      }
-      Err(error) => {
-        self
-          .histogram
-          .with_label_values(&[action, "err"])
-          .observe(seconds_since(&start));
-        Err(error)
+    let result = match Span::start_child(action, SpanKind::Internal) {
+      Some(mut span) => {
+        let result = span.in_scope_sync(f);
+        span.set_error(result.is_err());
+        span.end();
+        result
       }
-    }
+      None => f(),
+    };
+
+    let outcome = if result.is_ok() { "ok" } else { "err" };
+
+    self
+      .histogram
+      .with_label_values(&[action, outcome])
+      .observe(seconds_since(&start));
+    result
+  }
+}
+
+#[derive(Clone)]
+pub struct CountedActions {
+  counter: IntCounterVec,
+}
+
+impl CountedActions {
+  pub fn new<S: Into<String>>(name: S, help: S) -> CountedActions {
+    let opts = Opts::new(name, help);
+    let counter = IntCounterVec::new(opts, &["action", "outcome"]).unwrap();
+
+    register(Box::new(counter.clone())).unwrap();
+
+    CountedActions { counter }
+  }
+
+  pub fn count(&self, action: &str, outcome: &str) {
+    self.counter.with_label_values(&[action, outcome]).inc();
   }
 }
@@ -0,0 +1,417 @@
+use crate::{
+  requester::Requester,
+  url_template::query_pairs,
+  ws_try::{default_error_handler, from_error_response, FromClientResponse},
+  AsyncBusinessResult, BusinessResult, IntoClientRequest, Problem, RequestOptions,
+};
+use bytes::Bytes;
+use futures::future;
+use reqwest::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, IntoUrl, Method, Request, RequestBuilder, Response, ResponseBuilderExt, StatusCode};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::{Arc, Mutex, OnceLock};
+use url::Url;
+
+type BodyMatcher = Box<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+enum MockResponse {
+  Response {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+  },
+  Problem(Problem),
+}
+
+/// A call `MockRequester` expects, and what it answers.
+///
+/// URL patterns match the whole URL, or only path and query if they start with `/`; `*` matches any characters.
+pub struct Expectation {
+  method: Method,
+  url_pattern: String,
+  body_matcher: Option<BodyMatcher>,
+  response: MockResponse,
+  times: Option<usize>,
+  calls: usize,
+}
+
+impl Expectation {
+  pub fn new<S: Into<String>>(method: Method, url_pattern: S) -> Expectation {
+    Expectation {
+      method,
+      url_pattern: url_pattern.into(),
+      body_matcher: None,
+      response: MockResponse::Response {
+        status: StatusCode::NO_CONTENT,
+        headers: vec![],
+        body: Bytes::new(),
+      },
+      times: None,
+      calls: 0,
+    }
+  }
+
+  pub fn with_body<F: Fn(&[u8]) -> bool + Send + Sync + 'static>(self, matcher: F) -> Self {
+    Expectation {
+      body_matcher: Some(Box::new(matcher)),
+      ..self
+    }
+  }
+
+  /// Matches bodies that are JSON equal to `expected`.
+  pub fn with_json_body<T: Serialize>(self, expected: &T) -> Self {
+    let expected = serde_json::to_value(expected).unwrap_or(Value::Null);
+
+    self.with_body(move |body| serde_json::from_slice::<Value>(body).ok().as_ref() == Some(&expected))
+  }
+
+  pub fn respond<B: Into<Bytes>>(self, status: u16, body: B) -> Self {
+    Expectation {
+      response: MockResponse::Response {
+        status: StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        headers: vec![],
+        body: body.into(),
+      },
+      ..self
+    }
+  }
+
+  pub fn respond_json<T: Serialize>(self, status: u16, body: &T) -> Self {
+    self
+      .respond(status, serde_json::to_vec(body).unwrap_or_default())
+      .with_response_header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+  }
+
+  pub fn with_response_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+    if let MockResponse::Response { headers, .. } = &mut self.response {
+      headers.push((name, value));
+    }
+    self
+  }
+
+  /// Fails the call with `problem`, as if the service could not be reached.
+  pub fn fail_with(self, problem: Problem) -> Self {
+    Expectation {
+      response: MockResponse::Problem(problem),
+      ..self
+    }
+  }
+
+  /// Expects exactly `times` calls, by default at least one.
+  pub fn times(self, times: usize) -> Self {
+    Expectation {
+      times: Some(times),
+      ..self
+    }
+  }
+
+  fn matches(&self, request: &Request) -> bool {
+    let url = request.url();
+    let target = match self.url_pattern.starts_with('/') {
+      true => match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+      },
+      false => url.to_string(),
+    };
+
+    self.method == request.method()
+      && self.times.map(|times| self.calls < times).unwrap_or(true)
+      && glob_matches(&self.url_pattern, &target)
+      && self
+        .body_matcher
+        .as_ref()
+        .map(|matcher| matcher(body_of(request).as_deref().unwrap_or_default()))
+        .unwrap_or(true)
+  }
+
+  fn response(&self, url: &Url) -> BusinessResult<Response> {
+    match &self.response {
+      MockResponse::Response { status, headers, body } => {
+        let mut response = http::Response::builder().status(*status).url(url.clone());
+        for (name, value) in headers {
+          response = response.header(name, value);
+        }
+        response
+          .body(body.clone())
+          .map(Response::from)
+          .map_err(|e| Problem::internal_server_error().with_details(format!("Invalid mock response: {}", e)))
+      }
+      MockResponse::Problem(problem) => Err(problem.clone()),
+    }
+  }
+
+  fn is_satisfied(&self) -> bool {
+    match self.times {
+      Some(times) => self.calls == times,
+      None => self.calls > 0,
+    }
+  }
+}
+
+fn glob_matches(pattern: &str, value: &str) -> bool {
+  match pattern.split_once('*') {
+    None => pattern == value,
+    Some((prefix, rest)) => {
+      value.starts_with(prefix)
+        && (0..=value.len() - prefix.len())
+          .filter(|skip| value.is_char_boundary(prefix.len() + skip))
+          .any(|skip| glob_matches(rest, &value[prefix.len() + skip..]))
+    }
+  }
+}
+
+fn body_of(request: &Request) -> Option<Bytes> {
+  request
+    .body()
+    .and_then(|body| body.as_bytes())
+    .map(Bytes::copy_from_slice)
+}
+
+/// A call received by `MockRequester`; streamed bodies are not recorded.
+#[derive(Clone, Debug)]
+pub struct RecordedCall {
+  pub method: Method,
+  pub url: Url,
+  pub body: Option<Bytes>,
+}
+
+/// An in-process `Requester` answering from expectations instead of sending requests.
+///
+/// Calls without a matching expectation fail with a problem naming the call.
+#[derive(Clone, Default)]
+pub struct MockRequester {
+  expectations: Arc<Mutex<Vec<Expectation>>>,
+  calls: Arc<Mutex<Vec<RecordedCall>>>,
+  options: RequestOptions,
+}
+
+impl MockRequester {
+  pub fn new() -> MockRequester {
+    MockRequester::default()
+  }
+
+  /// Like `ServiceRequester::with_options`, sharing the expectations and calls, only the not found problem type
+  /// applies to mocked responses.
+  pub fn with_options(&self, options: RequestOptions) -> Self {
+    MockRequester {
+      options,
+      ..self.clone()
+    }
+  }
+
+  pub fn expect(self, expectation: Expectation) -> Self {
+    if let Ok(mut expectations) = self.expectations.lock() {
+      expectations.push(expectation);
+    }
+    self
+  }
+
+  pub fn calls(&self) -> Vec<RecordedCall> {
+    self.calls.lock().map(|calls| calls.clone()).unwrap_or_default()
+  }
+
+  /// Fails with the expectations that were not called as often as expected.
+  pub fn verify(&self) -> BusinessResult<()> {
+    let expectations = self
+      .expectations
+      .lock()
+      .map_err(|_| Problem::internal_server_error().with_details("Mock lock poisoned"))?;
+    let unsatisfied = expectations
+      .iter()
+      .filter(|expectation| !expectation.is_satisfied())
+      .map(|expectation| {
+        format!(
+          "{} {} called {} times",
+          expectation.method, expectation.url_pattern, expectation.calls
+        )
+      })
+      .collect::<Vec<_>>();
+
+    match unsatisfied.is_empty() {
+      true => Ok(()),
+      false => Err(
+        Problem::internal_server_error().with_details(format!("Unsatisfied expectations: {}", unsatisfied.join(", "))),
+      ),
+    }
+  }
+
+  fn respond<O>(&self, request: reqwest::Result<Request>) -> AsyncBusinessResult<O>
+  where
+    O: FromClientResponse<O> + 'static,
+  {
+    let response = request.map_err(Problem::from).and_then(|request| {
+      if let Ok(mut calls) = self.calls.lock() {
+        calls.push(RecordedCall {
+          method: request.method().clone(),
+          url: request.url().clone(),
+          body: body_of(&request),
+        });
+      }
+      let mut expectations = self
+        .expectations
+        .lock()
+        .map_err(|_| Problem::internal_server_error().with_details("Mock lock poisoned"))?;
+      let expectation = expectations
+        .iter_mut()
+        .find(|expectation| expectation.matches(&request))
+        .ok_or_else(|| {
+          Problem::internal_server_error().with_details(format!(
+            "Unexpected request: {} {}",
+            request.method(),
+            request.url()
+          ))
+        })?;
+      expectation.calls += 1;
+
+      expectation.response(request.url())
+    });
+
+    match response {
+      Ok(response) if response.status().is_success() => O::from_response(response),
+      Ok(response) => {
+        let not_found_problem_type = self.options.not_found_problem_type.clone();
+        Box::pin(async move {
+          from_error_response(response, not_found_problem_type.as_deref(), default_error_handler).await
+        })
+      }
+      Err(problem) => Box::pin(future::err(problem)),
+    }
+  }
+}
+
+fn request_builder<U: IntoUrl>(method: Method, url: U) -> RequestBuilder {
+  static CLIENT: OnceLock<Client> = OnceLock::new();
+
+  CLIENT.get_or_init(Client::new).request(method, url)
+}
+
+impl Requester for MockRequester {
+  fn with_body<U, I, O>(&self, method: Method, url: U, body: I) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    I: IntoClientRequest,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.respond(body.apply_body(request_builder(method, url)).build())
+  }
+
+  fn without_body<U, O>(&self, method: Method, url: U) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.respond(request_builder(method, url).build())
+  }
+
+  fn without_body_with_query<U, Q, O>(&self, method: Method, url: U, query: &Q) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    Q: Serialize + ?Sized,
+    O: FromClientResponse<O> + 'static,
+  {
+    match query_pairs(query) {
+      Ok(query) => self.respond(request_builder(method, url).query(&query).build()),
+      Err(problem) => Box::pin(future::err(problem)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::Done;
+  use serde_json::json;
+  use spectral::prelude::*;
+
+  async fn order_exists<R: Requester>(requester: &R, id: u32) -> BusinessResult<bool> {
+    let order: Option<Value> = requester
+      .get(format!("http://orders/api/v1/orders/{}", id).as_str())
+      .await?;
+
+    Ok(order.is_some())
+  }
+
+  #[actix_web::test]
+  async fn answers_from_expectations() {
+    let mock = MockRequester::new()
+      .expect(Expectation::new(Method::GET, "/api/v1/orders/1").respond_json(200, &json!({"id": 1})))
+      .expect(Expectation::new(Method::GET, "/api/v1/orders/*").respond(404, ""))
+      .expect(
+        Expectation::new(Method::POST, "http://orders/api/*")
+          .with_json_body(&json!({"total": 10}))
+          .fail_with(Problem::conflict())
+          .times(1),
+      );
+
+    let existing = order_exists(&mock, 1).await;
+    let missing = order_exists(&mock, 2).await;
+    let conflict = mock
+      .post::<_, _, Done>("http://orders/api/v1/orders", json!({"total": 10}))
+      .await;
+    let unexpected = mock
+      .post::<_, _, Done>("http://orders/api/v1/orders", json!({"total": 20}))
+      .await;
+
+    assert_that(&existing.ok()).is_equal_to(Some(true));
+    assert_that(&missing.ok()).is_equal_to(Some(false));
+    assert_that(&conflict.err().map(|problem| problem.code)).is_equal_to(Some(409));
+    assert_that(&unexpected.err().and_then(|problem| problem.details))
+      .is_equal_to(Some("Unexpected request: POST http://orders/api/v1/orders".to_string()));
+    assert_that(&mock.calls().len()).is_equal_to(4);
+    assert_that(&mock.verify().is_ok()).is_true();
+  }
+
+  #[actix_web::test]
+  async fn verifies_expected_calls() {
+    let mock = MockRequester::new().expect(Expectation::new(Method::DELETE, "/api/v1/orders/*").times(2));
+
+    let _: BusinessResult<Done> = mock.delete("http://orders/api/v1/orders/1").await;
+
+    assert_that(&mock.verify().map_err(|problem| problem.details)).is_equal_to(Err(Some(
+      "Unsatisfied expectations: DELETE /api/v1/orders/* called 1 times".to_string(),
+    )));
+  }
+
+  #[actix_web::test]
+  async fn honours_the_not_found_problem_type() {
+    let order_not_found = Problem {
+      problem_type: "https://example.com/order-not-found".to_string(),
+      ..Problem::not_found()
+    };
+    let mock = MockRequester::new()
+      .expect(Expectation::new(Method::GET, "/api/v1/orders/1").respond_json(404, &order_not_found))
+      .expect(Expectation::new(Method::GET, "/api/v1/orders/2").respond(404, "no such route"));
+    let typed = mock.with_options(RequestOptions::default().with_not_found_problem_type(order_not_found.problem_type));
+
+    let missing: BusinessResult<Option<Value>> = typed.get("http://orders/api/v1/orders/1").await;
+    let wrong_route: BusinessResult<Option<Value>> = typed.get("http://orders/api/v1/orders/2").await;
+
+    assert_that(&missing.ok()).is_equal_to(Some(None));
+    assert_that(&wrong_route.err().map(|problem| problem.code)).is_equal_to(Some(404));
+    assert_that(&mock.calls().len()).is_equal_to(2);
+  }
+
+  #[actix_web::test]
+  async fn appends_queries_in_order() {
+    let mock = MockRequester::new()
+      .expect(Expectation::new(Method::GET, "/api/v1/orders?status=paid&status=open&customer=42").respond(200, "[]"));
+
+    let orders: BusinessResult<Vec<Value>> = mock
+      .get_with_query(
+        "http://orders/api/v1/orders",
+        &[("status", "paid"), ("status", "open"), ("customer", "42")],
+      )
+      .await;
+
+    assert_that(&orders.ok()).is_equal_to(Some(vec![]));
+  }
+
+  #[test]
+  fn matches_url_patterns() {
+    assert_that(&glob_matches("/orders/*/items", "/orders/42/items")).is_true();
+    assert_that(&glob_matches("/orders/*", "/orders/42?expand=items")).is_true();
+    assert_that(&glob_matches("/orders/*/items", "/orders/42")).is_false();
+  }
+}
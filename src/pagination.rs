@@ -0,0 +1,302 @@
+use crate::{response_meta::WithMeta, BusinessResult, Problem, ServiceRequester};
+use futures::stream::{self, Fuse, LocalBoxStream};
+use futures::{Stream, StreamExt};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use url::Url;
+
+#[derive(Clone, Debug)]
+enum PageStrategy {
+  LinkHeader,
+  OffsetLimit {
+    offset_param: String,
+    limit_param: String,
+    limit: usize,
+  },
+  Cursor {
+    cursor_field: String,
+    cursor_param: String,
+  },
+}
+
+/// How `ServiceRequester::paginate` gets from one page of a collection to the next.
+///
+/// Pages are either JSON arrays of items, or objects with the items in `items_field`.
+#[derive(Clone, Debug)]
+pub struct Pagination {
+  strategy: PageStrategy,
+  items_field: Option<String>,
+  prefetch: usize,
+  max_items: Option<usize>,
+}
+
+impl Pagination {
+  fn new(strategy: PageStrategy, items_field: Option<String>) -> Pagination {
+    Pagination {
+      strategy,
+      items_field,
+      prefetch: 1,
+      max_items: None,
+    }
+  }
+
+  /// Follows the `Link` header with `rel="next"` until a page has none.
+  pub fn link_header() -> Pagination {
+    Pagination::new(PageStrategy::LinkHeader, None)
+  }
+
+  /// Requests `limit` items per page, increasing `offset_param` until a page has fewer items.
+  pub fn offset_limit<O: Into<String>, L: Into<String>>(offset_param: O, limit_param: L, limit: usize) -> Pagination {
+    Pagination::new(
+      PageStrategy::OffsetLimit {
+        offset_param: offset_param.into(),
+        limit_param: limit_param.into(),
+        limit: limit.max(1),
+      },
+      None,
+    )
+  }
+
+  /// Passes the `cursor_field` of each page on as `cursor_param` until it is missing, with the items in `items`.
+  pub fn cursor<F: Into<String>, P: Into<String>>(cursor_field: F, cursor_param: P) -> Pagination {
+    Pagination::new(
+      PageStrategy::Cursor {
+        cursor_field: cursor_field.into(),
+        cursor_param: cursor_param.into(),
+      },
+      Some("items".to_string()),
+    )
+  }
+
+  pub fn with_items_field<S: Into<String>>(self, items_field: S) -> Self {
+    Pagination {
+      items_field: Some(items_field.into()),
+      ..self
+    }
+  }
+
+  /// Fetches up to `pages` pages ahead of the consumer, by default and at least 1.
+  pub fn with_prefetch(self, pages: usize) -> Self {
+    Pagination {
+      prefetch: pages,
+      ..self
+    }
+  }
+
+  /// Ends the stream after `max_items` items, fetching no further pages.
+  pub fn with_max_items(self, max_items: usize) -> Self {
+    Pagination {
+      max_items: Some(max_items),
+      ..self
+    }
+  }
+
+  fn items(&self, body: Value) -> BusinessResult<Vec<Value>> {
+    let items = match &self.items_field {
+      Some(items_field) => match body {
+        Value::Object(mut fields) => fields.remove(items_field).unwrap_or(Value::Null),
+        _ => Value::Null,
+      },
+      None => body,
+    };
+
+    match items {
+      Value::Array(items) => Ok(items),
+      _ => Err(Problem::internal_server_error().with_details("Page without list of items")),
+    }
+  }
+
+  /// The URL of the page after `page`, which had `items` items.
+  fn next(&self, url: &Url, page: &WithMeta<Value>, items: usize, offset: usize) -> Option<Url> {
+    match &self.strategy {
+      PageStrategy::LinkHeader => page.link("next"),
+      PageStrategy::OffsetLimit {
+        offset_param,
+        limit_param,
+        limit,
+      } => (items >= *limit).then(|| {
+        with_query_params(
+          url,
+          &[
+            (offset_param, (offset + items).to_string()),
+            (limit_param, limit.to_string()),
+          ],
+        )
+      }),
+      PageStrategy::Cursor {
+        cursor_field,
+        cursor_param,
+      } => match page.body.get(cursor_field) {
+        Some(Value::String(cursor)) if !cursor.is_empty() => {
+          Some(with_query_params(url, &[(cursor_param, cursor.clone())]))
+        }
+        Some(Value::Number(cursor)) => Some(with_query_params(url, &[(cursor_param, cursor.to_string())])),
+        _ => None,
+      },
+    }
+  }
+
+  fn first(&self, url: Url) -> Url {
+    match &self.strategy {
+      PageStrategy::OffsetLimit {
+        offset_param,
+        limit_param,
+        limit,
+      } => with_query_params(
+        &url,
+        &[(offset_param, "0".to_string()), (limit_param, limit.to_string())],
+      ),
+      _ => url,
+    }
+  }
+}
+
+fn with_query_params(url: &Url, params: &[(&String, String)]) -> Url {
+  let mut next = url.clone();
+  let kept = url
+    .query_pairs()
+    .filter(|(name, _)| params.iter().all(|(param, _)| name != param.as_str()))
+    .map(|(name, value)| (name.into_owned(), value.into_owned()))
+    .collect::<Vec<_>>();
+  next
+    .query_pairs_mut()
+    .clear()
+    .extend_pairs(kept)
+    .extend_pairs(params.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+
+  next
+}
+
+/// Where `ServiceRequester::paginate` continues, `None` once it is done.
+struct Cursor {
+  requester: ServiceRequester,
+  pagination: Pagination,
+  next: Option<Url>,
+  fetched: usize,
+  visited: HashSet<Url>,
+}
+
+impl Cursor {
+  async fn fetch<T: DeserializeOwned>(mut self) -> Option<(BusinessResult<Vec<T>>, Cursor)> {
+    let url = self
+      .next
+      .take()
+      .filter(|_| self.fetched < self.pagination.max_items.unwrap_or(usize::MAX))?;
+    if !self.visited.insert(url.clone()) {
+      warn!("Stopped paginating at {}, which was already fetched", url);
+      return None;
+    }
+    let page = match self.requester.get::<_, WithMeta<Value>>(url.clone()).await {
+      Ok(page) => page,
+      Err(problem) => return Some((Err(problem), self)),
+    };
+    let items = self.pagination.items(page.body.clone()).and_then(|items| {
+      items
+        .into_iter()
+        .map(|item| serde_json::from_value::<T>(item).map_err(Problem::from))
+        .collect::<BusinessResult<Vec<_>>>()
+    });
+    if let Some(items) = items.as_ref().ok().filter(|items| !items.is_empty()) {
+      self.next = self.pagination.next(&url, &page, items.len(), self.fetched);
+      self.fetched += items.len();
+    }
+
+    Some((items, self))
+  }
+}
+
+/// Hands out the items of `pages`, polling up to `prefetch` further pages whenever an item is asked for.
+struct Prefetch<T> {
+  pages: Fuse<LocalBoxStream<'static, BusinessResult<Vec<T>>>>,
+  ready: VecDeque<BusinessResult<Vec<T>>>,
+  items: std::vec::IntoIter<T>,
+  prefetch: usize,
+}
+
+impl<T> Unpin for Prefetch<T> {}
+
+impl<T> Stream for Prefetch<T> {
+  type Item = BusinessResult<T>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let this = &mut *self;
+    while this.ready.len() < this.prefetch {
+      match this.pages.poll_next_unpin(cx) {
+        Poll::Ready(Some(page)) => this.ready.push_back(page),
+        _ => break,
+      }
+    }
+
+    loop {
+      if let Some(item) = this.items.next() {
+        return Poll::Ready(Some(Ok(item)));
+      }
+      match this.ready.pop_front() {
+        Some(Ok(items)) => this.items = items.into_iter(),
+        Some(Err(problem)) => return Poll::Ready(Some(Err(problem))),
+        None if this.pages.is_done() => return Poll::Ready(None),
+        None => match this.pages.poll_next_unpin(cx) {
+          Poll::Ready(Some(page)) => this.ready.push_back(page),
+          Poll::Ready(None) => return Poll::Ready(None),
+          Poll::Pending => return Poll::Pending,
+        },
+      }
+    }
+  }
+}
+
+impl ServiceRequester {
+  /// Streams the items of all pages of the collection at `url`.
+  ///
+  /// Pages are fetched by the task consuming the stream, as far ahead as `Pagination::with_prefetch` allows. The
+  /// stream ends after the first error, or when a page links to a page that was already fetched.
+  pub fn paginate<T>(&self, url: Url, pagination: Pagination) -> LocalBoxStream<'static, BusinessResult<T>>
+  where
+    T: DeserializeOwned + 'static,
+  {
+    let max_items = pagination.max_items.unwrap_or(usize::MAX);
+    let prefetch = pagination.prefetch.max(1);
+    let cursor = Cursor {
+      requester: self.clone(),
+      next: Some(pagination.first(url)),
+      pagination,
+      fetched: 0,
+      visited: HashSet::new(),
+    };
+    let pages = stream::unfold(Some(cursor), |cursor| async move {
+      let (page, cursor) = cursor?.fetch::<T>().await?;
+      let cursor = page.is_ok().then_some(cursor);
+      Some((page, cursor))
+    });
+
+    Prefetch {
+      pages: pages.boxed_local().fuse(),
+      ready: VecDeque::new(),
+      items: Vec::new().into_iter(),
+      prefetch,
+    }
+    .take(max_items)
+    .boxed_local()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn replaces_paging_parameters() {
+    let url = Url::parse("http://orders/api/v1/orders?status=open&offset=0&limit=10").unwrap();
+    let offset = "offset".to_string();
+    let limit = "limit".to_string();
+
+    let next = with_query_params(&url, &[(&offset, "10".to_string()), (&limit, "10".to_string())]);
+
+    assert_that(&next.as_str()).is_equal_to("http://orders/api/v1/orders?status=open&offset=10&limit=10");
+  }
+}
@@ -54,6 +54,14 @@ impl Problem {
     Self::for_status(424, "Failed dependency")
   }
 
+  pub fn too_many_requests() -> Problem {
+    Self::for_status(429, "Too many requests")
+  }
+
+  pub fn service_unavailable() -> Problem {
+    Self::for_status(503, "Service unavailable")
+  }
+
   pub fn with_details<T: std::fmt::Display>(mut self, details: T) -> Problem {
     self.details = match self.details {
       Some(existing) => Some(format!("{}: {}", existing, details)),
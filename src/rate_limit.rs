@@ -0,0 +1,435 @@
+use crate::auth_middleware::{AuthContext, TrustedProxies};
+use crate::metrics::CountedActions;
+use crate::subject::Subject;
+use crate::{AsyncBusinessResult, Problem};
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::{HttpMessage, ResponseError};
+use futures::future::{ok, Ready};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// How often keys that are back at their full burst are forgotten.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Allows `burst` requests per `period`, replenished evenly over the period (GCRA).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+  pub burst: u32,
+  pub period: Duration,
+}
+
+impl Quota {
+  pub fn new(burst: u32, period: Duration) -> Quota {
+    Quota {
+      burst: burst.max(1),
+      period,
+    }
+  }
+
+  pub fn per_second(burst: u32) -> Quota {
+    Quota::new(burst, Duration::from_secs(1))
+  }
+
+  pub fn per_minute(burst: u32) -> Quota {
+    Quota::new(burst, Duration::from_secs(60))
+  }
+
+  fn emission_interval(&self) -> Duration {
+    self.period / self.burst
+  }
+}
+
+/// What requests are counted against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitKey {
+  Subject,
+  Organization,
+  ClientIp,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RateLimitDecision {
+  Allowed {
+    limit: u32,
+    remaining: u32,
+    reset: Duration,
+  },
+  Rejected {
+    limit: u32,
+    retry_after: Duration,
+  },
+}
+
+impl RateLimitDecision {
+  fn headers(&self) -> Vec<(HeaderName, u64)> {
+    match self {
+      RateLimitDecision::Allowed {
+        limit,
+        remaining,
+        reset,
+      } => vec![
+        (HeaderName::from_static("ratelimit-limit"), *limit as u64),
+        (HeaderName::from_static("ratelimit-remaining"), *remaining as u64),
+        (HeaderName::from_static("ratelimit-reset"), ceil_secs(*reset)),
+      ],
+      RateLimitDecision::Rejected { limit, retry_after } => vec![
+        (HeaderName::from_static("ratelimit-limit"), *limit as u64),
+        (HeaderName::from_static("ratelimit-remaining"), 0),
+        (HeaderName::from_static("ratelimit-reset"), ceil_secs(*retry_after)),
+        (RETRY_AFTER, ceil_secs(*retry_after)),
+      ],
+    }
+  }
+
+  fn apply_headers(&self, headers: &mut HeaderMap) {
+    for (name, value) in self.headers() {
+      headers.insert(name, HeaderValue::from(value));
+    }
+  }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+  duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+fn subject_kind(subject: &Subject) -> &'static str {
+  match subject {
+    Subject::Admin(_) => "admin",
+    Subject::Customer(_) => "customer",
+    Subject::Api(_) => "api",
+    Subject::Service(_) => "service",
+    Subject::Generic(_) => "generic",
+  }
+}
+
+pub struct RateLimiter {
+  key: RateLimitKey,
+  default_quota: Option<Quota>,
+  subject_quotas: HashMap<&'static str, Quota>,
+  route_quotas: Vec<(String, Quota)>,
+  trusted_proxies: Option<TrustedProxies>,
+  theoretical_arrivals: Mutex<TheoreticalArrivals>,
+  metrics: CountedActions,
+}
+
+struct TheoreticalArrivals {
+  by_key: HashMap<String, Instant>,
+  next_sweep: Instant,
+}
+
+/// Whether `path` is `prefix` or below it, e.g. `/orders/1` is below `/orders` but `/orders-archive` is not.
+fn is_within(path: &str, prefix: &str) -> bool {
+  match path.strip_prefix(prefix.trim_end_matches('/')) {
+    Some(rest) => rest.is_empty() || rest.starts_with('/'),
+    None => false,
+  }
+}
+
+impl RateLimiter {
+  /// `name` and `help` describe the Prometheus counter of allowed and rejected requests.
+  pub fn new<S: Into<String>>(name: S, help: S, key: RateLimitKey) -> RateLimiter {
+    RateLimiter {
+      key,
+      default_quota: None,
+      subject_quotas: HashMap::new(),
+      route_quotas: Vec::new(),
+      trusted_proxies: None,
+      theoretical_arrivals: Mutex::new(TheoreticalArrivals {
+        by_key: HashMap::new(),
+        next_sweep: Instant::now() + SWEEP_INTERVAL,
+      }),
+      metrics: CountedActions::new(name, help),
+    }
+  }
+
+  pub fn with_default_quota(mut self, quota: Quota) -> Self {
+    self.default_quota = Some(quota);
+    self
+  }
+
+  /// Quota for all subjects of the same kind as `subject`, e.g. `Subject::Api(String::new())`.
+  pub fn with_subject_quota(mut self, subject: &Subject, quota: Quota) -> Self {
+    self.subject_quotas.insert(subject_kind(subject), quota);
+    self
+  }
+
+  /// Quota for requests to `path_prefix` and the paths below it, counted separately from other routes.
+  pub fn with_route_quota<S: Into<String>>(mut self, path_prefix: S, quota: Quota) -> Self {
+    self.route_quotas.push((path_prefix.into(), quota));
+    self.route_quotas.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+    self
+  }
+
+  /// Counts `ClientIp` keys by the forwarded client address for requests from these proxies.
+  pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+    self.trusted_proxies = Some(trusted_proxies);
+    self
+  }
+
+  fn key_for(&self, req: &ServiceRequest, auth_context: Option<&AuthContext>) -> String {
+    let client_ip = || {
+      let ip = match &self.trusted_proxies {
+        Some(trusted_proxies) => trusted_proxies.client_ip(req),
+        None => req.peer_addr().map(|addr| addr.ip()),
+      };
+      ip.map(|ip| format!("ip:{}", ip))
+        .unwrap_or_else(|| "ip:unknown".to_string())
+    };
+
+    match (self.key, auth_context) {
+      (RateLimitKey::Subject, Some(auth_context)) => format!("sub:{}", auth_context.subject.to_string()),
+      (RateLimitKey::Organization, Some(auth_context)) => match &auth_context.organization {
+        Some(organization) => format!("org:{}", organization),
+        None => format!("sub:{}", auth_context.subject.to_string()),
+      },
+      _ => client_ip(),
+    }
+  }
+
+  fn quota_for(&self, path: &str, auth_context: Option<&AuthContext>) -> Option<(&str, Quota)> {
+    if let Some((prefix, quota)) = self.route_quotas.iter().find(|(prefix, _)| is_within(path, prefix)) {
+      return Some((prefix.as_str(), *quota));
+    }
+    let kind = auth_context.map(|a| subject_kind(&a.subject)).unwrap_or("anonymous");
+
+    match self.subject_quotas.get(kind) {
+      Some(quota) => Some((kind, *quota)),
+      None => self.default_quota.map(|quota| (kind, quota)),
+    }
+  }
+
+  pub fn check(&self, req: &ServiceRequest) -> Option<RateLimitDecision> {
+    let extensions = req.extensions();
+    let auth_context = extensions.get::<AuthContext>();
+    let (action, quota) = self.quota_for(req.path(), auth_context)?;
+    let key = format!("{}|{}", action, self.key_for(req, auth_context));
+    let decision = self.check_at(key, quota, Instant::now());
+
+    match decision {
+      RateLimitDecision::Allowed { .. } => self.metrics.count(action, "allowed"),
+      RateLimitDecision::Rejected { .. } => self.metrics.count(action, "rejected"),
+    }
+    Some(decision)
+  }
+
+  fn check_at(&self, key: String, quota: Quota, now: Instant) -> RateLimitDecision {
+    let interval = quota.emission_interval();
+    let mut arrivals = match self.theoretical_arrivals.lock() {
+      Ok(arrivals) => arrivals,
+      Err(poisoned) => poisoned.into_inner(),
+    };
+    if now >= arrivals.next_sweep {
+      arrivals.by_key.retain(|_, tat| *tat > now);
+      arrivals.next_sweep = now + SWEEP_INTERVAL;
+    }
+
+    let tat = arrivals
+      .by_key
+      .get(&key)
+      .copied()
+      .filter(|tat| *tat > now)
+      .unwrap_or(now);
+    let new_tat = tat + interval;
+    let backlog = new_tat - now;
+
+    if backlog > quota.period {
+      RateLimitDecision::Rejected {
+        limit: quota.burst,
+        retry_after: backlog - quota.period,
+      }
+    } else {
+      arrivals.by_key.insert(key, new_tat);
+      RateLimitDecision::Allowed {
+        limit: quota.burst,
+        remaining: ((quota.period - backlog).as_nanos() / interval.as_nanos().max(1)) as u32,
+        reset: backlog,
+      }
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct RateLimitMiddlewareFactory {
+  limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitMiddlewareFactory {
+  pub fn new(limiter: Arc<RateLimiter>) -> RateLimitMiddlewareFactory {
+    RateLimitMiddlewareFactory { limiter }
+  }
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimitMiddlewareFactory
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Problem> + 'static,
+{
+  type Response = ServiceResponse<BoxBody>;
+  type Error = Problem;
+  type InitError = ();
+  type Transform = RateLimitMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(RateLimitMiddleware {
+      service,
+      limiter: self.limiter.clone(),
+    })
+  }
+}
+
+pub struct RateLimitMiddleware<S> {
+  service: S,
+  limiter: Arc<RateLimiter>,
+}
+
+impl<S> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Problem> + 'static,
+{
+  type Response = ServiceResponse<BoxBody>;
+  type Error = Problem;
+  type Future = AsyncBusinessResult<Self::Response>;
+
+  fn poll_ready(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(cx)
+  }
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let decision = self.limiter.check(&req);
+
+    if let Some(decision @ RateLimitDecision::Rejected { retry_after, .. }) = &decision {
+      let problem = Problem::too_many_requests().with_details(format!("Retry after {}s", ceil_secs(*retry_after)));
+      let mut response = problem.error_response();
+      decision.apply_headers(response.headers_mut());
+
+      return Box::pin(ok(req.into_response(response)));
+    }
+
+    let fut = self.service.call(req);
+
+    Box::pin(async move {
+      let mut res = fut.await?;
+      if let Some(decision) = decision {
+        decision.apply_headers(res.headers_mut());
+      }
+      Ok(res)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::auth_middleware::UntrustedIdentityHeaders;
+  use actix_web::dev::fn_service;
+  use actix_web::test::TestRequest;
+  use actix_web::HttpResponse;
+  use spectral::prelude::*;
+  use std::collections::BTreeMap;
+
+  fn api_request(name: &str) -> ServiceRequest {
+    let req = TestRequest::get().uri("/orders").to_srv_request();
+    req.extensions_mut().insert(AuthContext {
+      subject: Subject::Api(name.to_string()),
+      token: "token".to_string(),
+      organization: None,
+      scopes: BTreeMap::new(),
+      real_subject: None,
+    });
+    req
+  }
+
+  #[test]
+  fn allows_burst_then_rejects_until_replenished() {
+    let limiter = RateLimiter::new("test_gcra_requests", "help", RateLimitKey::Subject);
+    let quota = Quota::new(2, Duration::from_secs(10));
+    let now = Instant::now();
+
+    assert_that(&limiter.check_at("k".to_string(), quota, now)).is_equal_to(RateLimitDecision::Allowed {
+      limit: 2,
+      remaining: 1,
+      reset: Duration::from_secs(5),
+    });
+    assert_that(&limiter.check_at("k".to_string(), quota, now)).is_equal_to(RateLimitDecision::Allowed {
+      limit: 2,
+      remaining: 0,
+      reset: Duration::from_secs(10),
+    });
+    assert_that(&limiter.check_at("k".to_string(), quota, now)).is_equal_to(RateLimitDecision::Rejected {
+      limit: 2,
+      retry_after: Duration::from_secs(5),
+    });
+    assert_that(&limiter.check_at("other".to_string(), quota, now))
+      .matches(|d| matches!(d, RateLimitDecision::Allowed { .. }));
+    assert_that(&limiter.check_at("k".to_string(), quota, now + Duration::from_secs(5)))
+      .matches(|d| matches!(d, RateLimitDecision::Allowed { .. }));
+  }
+
+  #[test]
+  fn route_quotas_match_whole_segments() {
+    let limiter = RateLimiter::new("test_route_quota_requests", "help", RateLimitKey::ClientIp)
+      .with_route_quota("/orders", Quota::per_second(1));
+
+    assert_that(&limiter.quota_for("/orders", None).map(|(action, _)| action)).is_equal_to(Some("/orders"));
+    assert_that(&limiter.quota_for("/orders/42", None).map(|(action, _)| action)).is_equal_to(Some("/orders"));
+    assert_that(&limiter.quota_for("/orders-archive", None)).is_none();
+  }
+
+  #[test]
+  fn forgets_replenished_keys_periodically() {
+    let limiter = RateLimiter::new("test_sweep_requests", "help", RateLimitKey::Subject);
+    let quota = Quota::per_second(1);
+    let now = Instant::now();
+
+    limiter.check_at("k".to_string(), quota, now);
+    limiter.check_at("other".to_string(), quota, now + Duration::from_secs(5));
+    let kept = limiter.theoretical_arrivals.lock().unwrap().by_key.len();
+    limiter.check_at(
+      "other".to_string(),
+      quota,
+      now + SWEEP_INTERVAL + Duration::from_secs(1),
+    );
+
+    assert_that(&kept).is_equal_to(2);
+    assert_that(&limiter.theoretical_arrivals.lock().unwrap().by_key.len()).is_equal_to(1);
+  }
+
+  #[test]
+  fn client_ips_are_forwarded_by_trusted_proxies() {
+    let limiter = RateLimiter::new("test_client_ip_requests", "help", RateLimitKey::ClientIp)
+      .with_trusted_proxies(TrustedProxies::new(&["10.0.0.0/8"], UntrustedIdentityHeaders::Strip).unwrap());
+    let req = TestRequest::get()
+      .peer_addr("10.0.0.1:1234".parse().unwrap())
+      .insert_header(("x-forwarded-for", "1.2.3.4"))
+      .to_srv_request();
+
+    assert_that(&limiter.key_for(&req, None).as_str()).is_equal_to("ip:1.2.3.4");
+  }
+
+  #[actix_web::test]
+  async fn rejects_with_too_many_requests() {
+    let limiter = RateLimiter::new("test_middleware_requests", "help", RateLimitKey::Subject)
+      .with_subject_quota(&Subject::Api(String::new()), Quota::per_minute(1));
+    let service = RateLimitMiddlewareFactory::new(Arc::new(limiter))
+      .new_transform(fn_service(|req: ServiceRequest| async move {
+        Ok(req.into_response(HttpResponse::Ok().finish()))
+      }))
+      .await
+      .unwrap();
+
+    let allowed = service.call(api_request("key1")).await.unwrap();
+    let rejected = service.call(api_request("key1")).await.unwrap();
+    let other_key = service.call(api_request("key2")).await.unwrap();
+
+    assert_that(&allowed.status().as_u16()).is_equal_to(200);
+    assert_that(&allowed.headers().get("ratelimit-remaining").unwrap().to_str().unwrap()).is_equal_to("0");
+    assert_that(&rejected.status().as_u16()).is_equal_to(429);
+    assert_that(&rejected.headers().get("retry-after").unwrap().to_str().unwrap()).is_equal_to("60");
+    assert_that(&other_key.status().as_u16()).is_equal_to(200);
+  }
+}
@@ -0,0 +1,443 @@
+use crate::auth_middleware::AuthContext;
+use crate::subject::Subject;
+use crate::{BusinessResult, Problem};
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{err, ok, Ready};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+/// Roles and who holds them.
+///
+/// Permissions may end in `.*` to cover a whole group, `*` covers everything.
+/// Subjects are matched by their full name (`admin/alice`) or by kind (`admin/*`),
+/// scopes by `service:scope`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RbacConfig {
+  #[serde(default)]
+  pub roles: BTreeMap<String, Vec<String>>,
+  #[serde(default)]
+  pub subjects: BTreeMap<String, Vec<String>>,
+  #[serde(default)]
+  pub scopes: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+  pub permission: String,
+  pub subject: String,
+  /// Every role held by the subject and where it came from.
+  pub roles: Vec<(String, String)>,
+  pub granted_by: Option<String>,
+}
+
+impl Decision {
+  pub fn is_granted(&self) -> bool {
+    self.granted_by.is_some()
+  }
+}
+
+impl fmt::Display for Decision {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let roles = self
+      .roles
+      .iter()
+      .map(|(role, via)| format!("{} (via {})", role, via))
+      .collect::<Vec<_>>()
+      .join(", ");
+
+    match &self.granted_by {
+      Some(role) => write!(
+        f,
+        "Permission {} granted to {} by role {}",
+        self.permission, self.subject, role
+      ),
+      None if roles.is_empty() => write!(
+        f,
+        "Permission {} denied: {} holds no roles",
+        self.permission, self.subject
+      ),
+      None => write!(
+        f,
+        "Permission {} denied: none of the roles of {} grant it: {}",
+        self.permission, self.subject, roles
+      ),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rbac {
+  config: RbacConfig,
+}
+
+fn subject_kind_pattern(subject: &Subject) -> Option<&'static str> {
+  match subject {
+    Subject::Admin(_) => Some("admin/*"),
+    Subject::Customer(_) => Some("customer/*"),
+    Subject::Service(_) => Some("service/*"),
+    Subject::Api(_) => Some("api/*"),
+    Subject::Generic(_) => None,
+  }
+}
+
+fn permission_matches(granted: &str, permission: &str) -> bool {
+  match granted.strip_suffix('*') {
+    Some(prefix) => permission.starts_with(prefix),
+    None => granted == permission,
+  }
+}
+
+impl Rbac {
+  pub fn new(config: RbacConfig) -> Rbac {
+    Rbac { config }
+  }
+
+  #[cfg(feature = "with-toml")]
+  pub fn from_toml(toml: &str) -> BusinessResult<Rbac> {
+    toml::from_str(toml)
+      .map(Rbac::new)
+      .map_err(|e| Problem::internal_server_error().with_details(format!("Invalid RBAC config: {}", e)))
+  }
+
+  /// Reads the `RbacConfig` stored under `key`.
+  #[cfg(feature = "with-config")]
+  pub fn from_config(config: &config::Config, key: &str) -> BusinessResult<Rbac> {
+    config
+      .get::<RbacConfig>(key)
+      .map(Rbac::new)
+      .map_err(|e| Problem::internal_server_error().with_details(format!("Invalid RBAC config: {}", e)))
+  }
+
+  pub fn roles_of(&self, auth_context: &AuthContext) -> Vec<(String, String)> {
+    let subject = auth_context.subject.to_string();
+    let mut roles = BTreeSet::new();
+
+    for pattern in Some(subject.as_str())
+      .into_iter()
+      .chain(subject_kind_pattern(&auth_context.subject))
+    {
+      for role in self.config.subjects.get(pattern).into_iter().flatten() {
+        roles.insert((role.clone(), format!("subject {}", pattern)));
+      }
+    }
+    for (service, scopes) in &auth_context.scopes {
+      for scope in scopes {
+        let key = format!("{}:{}", service, scope);
+        for role in self.config.scopes.get(&key).into_iter().flatten() {
+          roles.insert((role.clone(), format!("scope {}", key)));
+        }
+      }
+    }
+
+    roles.into_iter().collect()
+  }
+
+  pub fn decide(&self, auth_context: &AuthContext, permission: &str) -> Decision {
+    let roles = self.roles_of(auth_context);
+    let granted_by = roles
+      .iter()
+      .find(|(role, _)| {
+        self
+          .config
+          .roles
+          .get(role)
+          .map(|permissions| {
+            permissions
+              .iter()
+              .any(|granted| permission_matches(granted, permission))
+          })
+          .unwrap_or(false)
+      })
+      .map(|(role, _)| role.clone());
+
+    Decision {
+      permission: permission.to_string(),
+      subject: auth_context.subject.to_string(),
+      roles,
+      granted_by,
+    }
+  }
+
+  pub fn has_permission(&self, auth_context: &AuthContext, permission: &str) -> bool {
+    self.decide(auth_context, permission).is_granted()
+  }
+
+  pub fn require_permission(&self, auth_context: &AuthContext, permission: &str) -> BusinessResult<()> {
+    let decision = self.decide(auth_context, permission);
+
+    match decision.is_granted() {
+      true => Ok(()),
+      false => Err(Problem::forbidden().with_details(decision)),
+    }
+  }
+}
+
+/// A permission that can be required per route via `Authorized`.
+pub trait Permission {
+  const NAME: &'static str;
+}
+
+/// Extracts the `AuthContext`, failing with 403 unless it holds permission `P`.
+///
+/// The policy is looked up as `web::Data<Rbac>` in the app data, without one every request fails with 500.
+pub struct Authorized<P: Permission> {
+  pub auth_context: AuthContext,
+  permission: PhantomData<P>,
+}
+
+impl<P: Permission> Deref for Authorized<P> {
+  type Target = AuthContext;
+
+  fn deref(&self) -> &AuthContext {
+    &self.auth_context
+  }
+}
+
+impl<P: Permission> FromRequest for Authorized<P> {
+  type Error = Problem;
+  type Future = Ready<Result<Authorized<P>, Problem>>;
+
+  fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+    let rbac = match registered_rbac(req) {
+      Ok(rbac) => rbac,
+      Err(problem) => return err(problem),
+    };
+
+    match req.extensions().get::<AuthContext>() {
+      Some(auth_context) => match rbac.require_permission(auth_context, P::NAME) {
+        Ok(()) => ok(Authorized {
+          auth_context: auth_context.to_owned(),
+          permission: PhantomData,
+        }),
+        Err(problem) => err(problem),
+      },
+      None => err(Problem::unauthorized()),
+    }
+  }
+}
+
+/// Extracts the `AuthContext` together with the policy, to check permissions within a handler via
+/// `auth_context.has_permission("orders.cancel")`.
+///
+/// The policy is looked up like for `Authorized`.
+pub struct Permissions {
+  pub auth_context: AuthContext,
+  rbac: web::Data<Rbac>,
+}
+
+impl Permissions {
+  pub fn has_permission(&self, permission: &str) -> bool {
+    self.rbac.has_permission(&self.auth_context, permission)
+  }
+
+  pub fn require_permission(&self, permission: &str) -> BusinessResult<()> {
+    self.rbac.require_permission(&self.auth_context, permission)
+  }
+}
+
+impl Deref for Permissions {
+  type Target = AuthContext;
+
+  fn deref(&self) -> &AuthContext {
+    &self.auth_context
+  }
+}
+
+impl FromRequest for Permissions {
+  type Error = Problem;
+  type Future = Ready<Result<Permissions, Problem>>;
+
+  fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+    let rbac = match registered_rbac(req) {
+      Ok(rbac) => rbac.clone(),
+      Err(problem) => return err(problem),
+    };
+
+    match req.extensions().get::<AuthContext>() {
+      Some(auth_context) => ok(Permissions {
+        auth_context: auth_context.to_owned(),
+        rbac,
+      }),
+      None => err(Problem::unauthorized()),
+    }
+  }
+}
+
+fn registered_rbac(req: &HttpRequest) -> Result<&web::Data<Rbac>, Problem> {
+  req.app_data::<web::Data<Rbac>>().ok_or_else(|| {
+    error!("No RBAC policy registered to check permissions for {}", req.path());
+    Problem::internal_server_error().with_details("No RBAC policy registered")
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::test::TestRequest;
+  use spectral::prelude::*;
+
+  fn rbac() -> Rbac {
+    let config: RbacConfig = serde_json::from_str(
+      r#"{
+        "roles": {
+          "viewer": ["orders.read"],
+          "support": ["orders.*"],
+          "superuser": ["*"]
+        },
+        "subjects": {
+          "customer/*": ["viewer"],
+          "admin/root": ["superuser"]
+        },
+        "scopes": {
+          "orders:support": ["support"]
+        }
+      }"#,
+    )
+    .unwrap();
+
+    Rbac::new(config)
+  }
+
+  fn auth_context(subject: Subject, scopes: &[(&str, &str)]) -> AuthContext {
+    let mut scope_map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (service, scope) in scopes {
+      scope_map
+        .entry(service.to_string())
+        .or_default()
+        .push(scope.to_string());
+    }
+
+    AuthContext {
+      subject,
+      token: "token".to_string(),
+      organization: None,
+      scopes: scope_map,
+      real_subject: None,
+    }
+  }
+
+  struct CancelOrders;
+
+  impl Permission for CancelOrders {
+    const NAME: &'static str = "orders.cancel";
+  }
+
+  #[test]
+  fn permissions_come_from_subjects_and_scopes() {
+    let customer = auth_context(Subject::Customer("bob".to_string()), &[]);
+    let support = auth_context(Subject::Admin("alice".to_string()), &[("orders", "support")]);
+    let root = auth_context(Subject::Admin("root".to_string()), &[]);
+    let rbac = rbac();
+
+    assert_that(&rbac.has_permission(&customer, "orders.read")).is_true();
+    assert_that(&rbac.has_permission(&customer, "orders.cancel")).is_false();
+    assert_that(&rbac.has_permission(&support, "orders.cancel")).is_true();
+    assert_that(&rbac.has_permission(&support, "invoices.read")).is_false();
+    assert_that(&rbac.has_permission(&root, "invoices.read")).is_true();
+  }
+
+  #[test]
+  fn denials_are_explained() {
+    let customer = auth_context(Subject::Customer("bob".to_string()), &[]);
+    let service = auth_context(Subject::Service("billing".to_string()), &[]);
+
+    assert_that(
+      &rbac()
+        .require_permission(&customer, "orders.cancel")
+        .unwrap_err()
+        .details,
+    )
+    .is_equal_to(Some(
+      "Permission orders.cancel denied: none of the roles of customer/bob grant it: viewer (via subject customer/*)"
+        .to_string(),
+    ));
+    assert_that(
+      &rbac()
+        .require_permission(&service, "orders.cancel")
+        .unwrap_err()
+        .details,
+    )
+    .is_equal_to(Some(
+      "Permission orders.cancel denied: service/billing holds no roles".to_string(),
+    ));
+  }
+
+  #[actix_web::test]
+  async fn authorized_extractor_enforces_permission() {
+    let request = || {
+      TestRequest::default()
+        .app_data(web::Data::new(rbac()))
+        .to_http_request()
+    };
+    let req = request();
+    req
+      .extensions_mut()
+      .insert(auth_context(Subject::Customer("bob".to_string()), &[]));
+    let denied = Authorized::<CancelOrders>::extract(&req).await;
+
+    let req = request();
+    req.extensions_mut().insert(auth_context(
+      Subject::Admin("alice".to_string()),
+      &[("orders", "support")],
+    ));
+    let granted = Authorized::<CancelOrders>::extract(&req).await;
+
+    let req = TestRequest::default().to_http_request();
+    req.extensions_mut().insert(auth_context(
+      Subject::Admin("alice".to_string()),
+      &[("orders", "support")],
+    ));
+    let without_policy = Authorized::<CancelOrders>::extract(&req).await;
+
+    assert_that(&denied.err().map(|p| p.code)).is_equal_to(Some(403));
+    assert_that(&granted.map(|a| a.subject.clone()).ok()).is_equal_to(Some(Subject::Admin("alice".to_string())));
+    assert_that(&without_policy.err().map(|p| p.code)).is_equal_to(Some(500));
+  }
+
+  #[actix_web::test]
+  async fn permissions_extractor_checks_within_handlers() {
+    let req = TestRequest::default()
+      .app_data(web::Data::new(rbac()))
+      .to_http_request();
+    req.extensions_mut().insert(auth_context(
+      Subject::Admin("alice".to_string()),
+      &[("orders", "support")],
+    ));
+    let auth_context = Permissions::extract(&req).await.unwrap();
+
+    let anonymous = Permissions::extract(
+      &TestRequest::default()
+        .app_data(web::Data::new(rbac()))
+        .to_http_request(),
+    )
+    .await;
+
+    assert_that(&auth_context.has_permission("orders.cancel")).is_true();
+    assert_that(&auth_context.has_permission("invoices.read")).is_false();
+    assert_that(&auth_context.require_permission("invoices.read").err().map(|p| p.code)).is_equal_to(Some(403));
+    assert_that(&anonymous.err().map(|p| p.code)).is_equal_to(Some(401));
+  }
+
+  #[cfg(feature = "with-toml")]
+  #[test]
+  fn rbac_config_from_toml() {
+    let rbac = Rbac::from_toml(
+      r#"
+        [roles]
+        viewer = ["orders.read"]
+
+        [subjects]
+        "customer/*" = ["viewer"]
+      "#,
+    )
+    .unwrap();
+    let customer = auth_context(Subject::Customer("bob".to_string()), &[]);
+
+    assert_that(&rbac.has_permission(&customer, "orders.read")).is_true();
+  }
+}
@@ -0,0 +1,203 @@
+use crate::{BusinessResult, IntoClientRequest, Problem};
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::TryStream;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::multipart::{self, Part};
+use reqwest::{Body, RequestBuilder};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Sends `T` URL-encoded as `application/x-www-form-urlencoded`.
+pub struct Form<T>(pub T);
+
+impl<T> IntoClientRequest for Form<T>
+where
+  T: Serialize,
+{
+  fn apply_body(self, request: RequestBuilder) -> RequestBuilder {
+    request.form(&self.0)
+  }
+}
+
+/// Sends plain text as `text/plain; charset=utf-8`.
+pub struct Text(pub String);
+
+impl IntoClientRequest for Text {
+  fn apply_body(self, request: RequestBuilder) -> RequestBuilder {
+    request.header(CONTENT_TYPE, "text/plain; charset=utf-8").body(self.0)
+  }
+}
+
+/// Sends bytes as they are with an explicit content type.
+pub struct Raw {
+  pub content_type: String,
+  pub body: Bytes,
+}
+
+impl Raw {
+  pub fn new<C: Into<String>, B: Into<Bytes>>(content_type: C, body: B) -> Raw {
+    Raw {
+      content_type: content_type.into(),
+      body: body.into(),
+    }
+  }
+}
+
+impl IntoClientRequest for Raw {
+  fn apply_body(self, request: RequestBuilder) -> RequestBuilder {
+    request.header(CONTENT_TYPE, self.content_type).body(self.body)
+  }
+}
+
+/// Sends `T` as gzip compressed JSON, for large payloads to services that accept `Content-Encoding: gzip`.
+pub struct GzipJson<T>(pub T);
+
+impl<T> IntoClientRequest for GzipJson<T>
+where
+  T: Serialize,
+{
+  fn apply_body(self, request: RequestBuilder) -> RequestBuilder {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    match serde_json::to_writer(&mut encoder, &self.0) {
+      Ok(()) => match encoder.finish() {
+        Ok(body) => request
+          .header(CONTENT_TYPE, "application/json")
+          .header(CONTENT_ENCODING, "gzip")
+          .body(body),
+        Err(_) => request.json(&self.0),
+      },
+      // lets reqwest report the serialization error when the request is sent
+      Err(_) => request.json(&self.0),
+    }
+  }
+}
+
+/// A `multipart/form-data` upload of text fields, files and streams.
+///
+/// Files and streams are sent as they are read, so such requests cannot be retried.
+#[derive(Default)]
+pub struct Multipart(multipart::Form);
+
+fn with_content_type(part: Part, content_type: &str) -> BusinessResult<Part> {
+  part
+    .mime_str(content_type)
+    .map_err(|e| Problem::internal_server_error().with_details(format!("Invalid content type {}: {}", content_type, e)))
+}
+
+impl Multipart {
+  pub fn new() -> Multipart {
+    Multipart::default()
+  }
+
+  pub fn text<N, V>(self, name: N, value: V) -> Self
+  where
+    N: Into<Cow<'static, str>>,
+    V: Into<Cow<'static, str>>,
+  {
+    Multipart(self.0.text(name, value))
+  }
+
+  pub fn bytes<N, F, B>(self, name: N, file_name: F, content_type: &str, bytes: B) -> BusinessResult<Self>
+  where
+    N: Into<Cow<'static, str>>,
+    F: Into<Cow<'static, str>>,
+    B: Into<Cow<'static, [u8]>>,
+  {
+    let part = with_content_type(Part::bytes(bytes).file_name(file_name), content_type)?;
+
+    Ok(Multipart(self.0.part(name, part)))
+  }
+
+  /// Streams the file at `path`, with a content type guessed from its extension.
+  pub async fn file<N, P>(self, name: N, path: P) -> BusinessResult<Self>
+  where
+    N: Into<Cow<'static, str>>,
+    P: AsRef<Path>,
+  {
+    let path = path.as_ref();
+    let file = tokio::fs::File::open(path).await?;
+    let length = file.metadata().await?.len();
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    let mut part = with_content_type(Part::stream_with_length(file, length), content_type.as_ref())?;
+    if let Some(file_name) = path.file_name() {
+      part = part.file_name(file_name.to_string_lossy().into_owned());
+    }
+
+    Ok(Multipart(self.0.part(name, part)))
+  }
+
+  pub fn stream<N, F, S>(self, name: N, file_name: F, content_type: &str, stream: S) -> BusinessResult<Self>
+  where
+    N: Into<Cow<'static, str>>,
+    F: Into<Cow<'static, str>>,
+    S: TryStream + Send + Sync + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    Bytes: From<S::Ok>,
+  {
+    let part = with_content_type(
+      Part::stream(Body::wrap_stream(stream)).file_name(file_name),
+      content_type,
+    )?;
+
+    Ok(Multipart(self.0.part(name, part)))
+  }
+}
+
+impl IntoClientRequest for Multipart {
+  fn apply_body(self, request: RequestBuilder) -> RequestBuilder {
+    request.multipart(self.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use flate2::read::GzDecoder;
+  use reqwest::header::HeaderName;
+  use reqwest::{Client, Request};
+  use serde_json::{json, Value};
+  use spectral::prelude::*;
+  use std::io::Read;
+
+  fn request<B: IntoClientRequest>(body: B) -> Request {
+    body
+      .apply_body(Client::new().post("http://localhost/upload"))
+      .build()
+      .unwrap()
+  }
+
+  fn header(request: &Request, name: HeaderName) -> &str {
+    request.headers()[name].to_str().unwrap()
+  }
+
+  fn body_of(request: &Request) -> &[u8] {
+    request.body().and_then(Body::as_bytes).unwrap()
+  }
+
+  #[test]
+  fn encodes_forms_text_and_raw_bodies() {
+    let form = request(Form([("name", "Jane Doe"), ("city", "Köln")]));
+    let text = request(Text("hello".to_string()));
+    let raw = request(Raw::new("application/pdf", &b"%PDF"[..]));
+
+    assert_that(&header(&form, CONTENT_TYPE)).is_equal_to("application/x-www-form-urlencoded");
+    assert_that(&body_of(&form)).is_equal_to(b"name=Jane+Doe&city=K%C3%B6ln".as_ref());
+    assert_that(&header(&text, CONTENT_TYPE)).is_equal_to("text/plain; charset=utf-8");
+    assert_that(&body_of(&text)).is_equal_to(b"hello".as_ref());
+    assert_that(&header(&raw, CONTENT_TYPE)).is_equal_to("application/pdf");
+    assert_that(&body_of(&raw)).is_equal_to(b"%PDF".as_ref());
+  }
+
+  #[test]
+  fn compresses_json() {
+    let request = request(GzipJson(json!({"id": 42})));
+    let mut decoded = String::new();
+    GzDecoder::new(body_of(&request)).read_to_string(&mut decoded).unwrap();
+
+    assert_that(&header(&request, CONTENT_ENCODING)).is_equal_to("gzip");
+    assert_that(&serde_json::from_str::<Value>(&decoded).unwrap()).is_equal_to(json!({"id": 42}));
+  }
+}
@@ -0,0 +1,196 @@
+use crate::{AsyncBusinessResult, Problem};
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use futures::future::{ok, Ready};
+use rand::RngCore;
+use serde_json::Value;
+use std::future::Future;
+use std::task::{Context, Poll};
+
+pub const REQUEST_ID_HEADER_NAME: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+  static CURRENT_REQUEST_ID: RequestId;
+}
+
+/// Correlates everything done on behalf of one user action, across services.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+  pub fn generate() -> RequestId {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    RequestId(hex::encode(bytes))
+  }
+
+  /// Accepts ids of visible ASCII characters only, so they can be logged and forwarded safely.
+  pub fn parse(value: &str) -> Option<RequestId> {
+    let valid =
+      !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH && value.chars().all(|c| c.is_ascii_graphic());
+
+    valid.then(|| RequestId(value.to_string()))
+  }
+
+  /// Runs `future` as if it was handling the request `request_id`, e.g. in background jobs.
+  pub async fn scope<F: Future>(request_id: RequestId, future: F) -> F::Output {
+    CURRENT_REQUEST_ID.scope(request_id, future).await
+  }
+
+  /// The id of the request currently being handled, if any.
+  pub fn current() -> Option<RequestId> {
+    CURRENT_REQUEST_ID.try_with(|id| id.clone()).ok()
+  }
+}
+
+/// Renders `problem` with the id of the failed request as `request_id`, keeping it as the error of the response.
+fn problem_response(request: HttpRequest, problem: Problem, request_id: &RequestId) -> ServiceResponse {
+  let mut body = serde_json::to_value(&problem).unwrap_or_default();
+  if let Value::Object(fields) = &mut body {
+    fields.insert("request_id".to_string(), Value::String(request_id.0.clone()));
+  }
+  let response = HttpResponse::from_error(problem).set_body(BoxBody::new(body.to_string()));
+
+  ServiceResponse::new(request, response)
+}
+
+/// Accepts or generates an `X-Request-Id`, echoes it on the response and adds it to problems.
+///
+/// Errors of the wrapped services are turned into responses here, so that they carry the id as well.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestIdMiddlewareFactory;
+
+impl<S> Transform<S, ServiceRequest> for RequestIdMiddlewareFactory
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Problem> + 'static,
+{
+  type Response = ServiceResponse<BoxBody>;
+  type Error = Problem;
+  type InitError = ();
+  type Transform = RequestIdMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(RequestIdMiddleware { service })
+  }
+}
+
+pub struct RequestIdMiddleware<S> {
+  service: S,
+}
+
+impl<S> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Problem> + 'static,
+{
+  type Response = ServiceResponse<BoxBody>;
+  type Error = Problem;
+  type Future = AsyncBusinessResult<Self::Response>;
+
+  fn poll_ready(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(cx)
+  }
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let request_id = req
+      .headers()
+      .get(REQUEST_ID_HEADER_NAME)
+      .and_then(|value| value.to_str().ok())
+      .and_then(RequestId::parse)
+      .unwrap_or_else(RequestId::generate);
+    req.extensions_mut().insert(request_id.clone());
+    let request = req.request().clone();
+    let fut = CURRENT_REQUEST_ID.scope(request_id.clone(), self.service.call(req));
+
+    Box::pin(async move {
+      let mut res = match fut.await {
+        Ok(res) => match res.response().error().and_then(|e| e.as_error::<Problem>()).cloned() {
+          Some(problem) => problem_response(res.into_parts().0, problem, &request_id),
+          None => res,
+        },
+        Err(problem) => problem_response(request, problem, &request_id),
+      };
+      if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+        res
+          .headers_mut()
+          .insert(HeaderName::from_static(REQUEST_ID_HEADER_NAME), value);
+      }
+
+      Ok(res)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::dev::fn_service;
+  use actix_web::test::TestRequest;
+  use spectral::prelude::*;
+
+  async fn handled(req: ServiceRequest) -> Result<ServiceResponse, Problem> {
+    let service = RequestIdMiddlewareFactory
+      .new_transform(fn_service(|req: ServiceRequest| async move {
+        let current = RequestId::current().map(|id| id.0).unwrap_or_default();
+        match req.path() {
+          "/fail" => Ok(req.error_response(Problem::conflict())),
+          "/err" => Err(Problem::forbidden()),
+          _ => Ok(req.into_response(HttpResponse::Ok().body(current))),
+        }
+      }))
+      .await
+      .unwrap();
+
+    service.call(req).await
+  }
+
+  #[actix_web::test]
+  async fn accepts_and_echoes_request_id() {
+    let req = TestRequest::get()
+      .insert_header((REQUEST_ID_HEADER_NAME, "abc-123"))
+      .to_srv_request();
+
+    let res = handled(req).await.unwrap();
+    let echoed = res.headers().get(REQUEST_ID_HEADER_NAME).cloned();
+    let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+
+    assert_that(&echoed).is_equal_to(Some(HeaderValue::from_static("abc-123")));
+    assert_that(&body.as_ref()).is_equal_to(b"abc-123".as_ref());
+  }
+
+  #[actix_web::test]
+  async fn generates_request_id_and_adds_it_to_problems() {
+    for (path, code) in [("/fail", 409), ("/err", 403)] {
+      let req = TestRequest::get()
+        .uri(path)
+        .insert_header((REQUEST_ID_HEADER_NAME, "not valid"))
+        .to_srv_request();
+
+      let res = handled(req).await.unwrap();
+      let echoed = res
+        .headers()
+        .get(REQUEST_ID_HEADER_NAME)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+      let error = res
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<Problem>())
+        .map(|p| p.code);
+      let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+      let problem: Value = serde_json::from_slice(&body).unwrap();
+
+      assert_that(&echoed.len()).is_equal_to(32);
+      assert_that(&error).is_equal_to(Some(code));
+      assert_that(&problem["code"]).is_equal_to(Value::from(code));
+      assert_that(&problem["request_id"]).is_equal_to(Value::String(echoed));
+    }
+  }
+}
@@ -0,0 +1,78 @@
+use crate::{ws_try::FromClientResponse, AsyncBusinessResult, IntoClientRequest};
+use reqwest::{IntoUrl, Method};
+use serde::Serialize;
+
+/// Calls to other services, implemented by `ServiceRequester` and by `MockRequester` for tests.
+///
+/// Code that is generic over `R: Requester` can be unit-tested without running an HTTP server.
+pub trait Requester {
+  fn with_body<U, I, O>(&self, method: Method, url: U, body: I) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    I: IntoClientRequest,
+    O: FromClientResponse<O> + 'static;
+
+  fn without_body<U, O>(&self, method: Method, url: U) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    O: FromClientResponse<O> + 'static;
+
+  /// Appends `query` to the query string of `url` as described in `url_template::query_pairs`.
+  fn without_body_with_query<U, Q, O>(&self, method: Method, url: U, query: &Q) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    Q: Serialize + ?Sized,
+    O: FromClientResponse<O> + 'static;
+
+  fn get<U, O>(&self, url: U) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.without_body(Method::GET, url)
+  }
+
+  fn get_with_query<U, Q, O>(&self, url: U, query: &Q) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    Q: Serialize + ?Sized,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.without_body_with_query(Method::GET, url, query)
+  }
+
+  fn post<U, I, O>(&self, url: U, body: I) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    I: IntoClientRequest,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.with_body(Method::POST, url, body)
+  }
+
+  fn patch<U, I, O>(&self, url: U, body: I) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    I: IntoClientRequest,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.with_body(Method::PATCH, url, body)
+  }
+
+  fn put<U, I, O>(&self, url: U, body: I) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    I: IntoClientRequest,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.with_body(Method::PUT, url, body)
+  }
+
+  fn delete<U, O>(&self, url: U) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.without_body(Method::DELETE, url)
+  }
+}
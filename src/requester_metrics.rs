@@ -0,0 +1,147 @@
+use prometheus::{register, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts};
+use reqwest::{Method, Response, StatusCode};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+struct RequesterMetrics {
+  duration: HistogramVec,
+  errors: IntCounterVec,
+  in_flight: IntGaugeVec,
+}
+
+fn metrics() -> &'static RequesterMetrics {
+  static METRICS: OnceLock<RequesterMetrics> = OnceLock::new();
+
+  METRICS.get_or_init(|| {
+    let duration = HistogramVec::new(
+      HistogramOpts::new(
+        "service_requester_duration_seconds",
+        "Latency of outbound service requests",
+      ),
+      &["target", "method", "route", "status"],
+    )
+    .unwrap();
+    let errors = IntCounterVec::new(
+      Opts::new(
+        "service_requester_errors_total",
+        "Outbound service requests failed without response",
+      ),
+      &["target", "method", "route", "kind"],
+    )
+    .unwrap();
+    let in_flight = IntGaugeVec::new(
+      Opts::new("service_requester_in_flight", "Outbound service requests in flight"),
+      &["target"],
+    )
+    .unwrap();
+
+    register(Box::new(duration.clone())).unwrap();
+    register(Box::new(errors.clone())).unwrap();
+    register(Box::new(in_flight.clone())).unwrap();
+
+    RequesterMetrics {
+      duration,
+      errors,
+      in_flight,
+    }
+  })
+}
+
+fn status_class(status: StatusCode) -> &'static str {
+  match status.as_u16() {
+    100..=199 => "1xx",
+    200..=299 => "2xx",
+    300..=399 => "3xx",
+    400..=499 => "4xx",
+    _ => "5xx",
+  }
+}
+
+/// The `route` label of calls without explicit route, see `RequestOptions::with_route`.
+pub const OTHER_ROUTE: &str = "other";
+
+/// Counts a call cancelled by the total timeout of the requester as a timeout of its current attempt.
+pub(crate) fn record_timeout(target: &str, method: &Method, route: &str) {
+  metrics()
+    .errors
+    .with_label_values(&[target, method.as_str(), route, "timeout"])
+    .inc();
+}
+
+/// Times a single outbound attempt and counts it as in flight until it is finished or dropped.
+pub(crate) struct AttemptTimer {
+  target: String,
+  method: Method,
+  route: String,
+  start: Instant,
+}
+
+impl AttemptTimer {
+  pub fn start(target: &str, method: &Method, route: String) -> AttemptTimer {
+    metrics().in_flight.with_label_values(&[target]).inc();
+
+    AttemptTimer {
+      target: target.to_string(),
+      method: method.clone(),
+      route,
+      start: Instant::now(),
+    }
+  }
+
+  pub fn finish(self, outcome: &Result<Response, reqwest::Error>) {
+    let labels = [self.target.as_str(), self.method.as_str(), self.route.as_str()];
+
+    match outcome {
+      Ok(response) => metrics()
+        .duration
+        .with_label_values(&[labels[0], labels[1], labels[2], status_class(response.status())])
+        .observe(self.start.elapsed().as_secs_f64()),
+      Err(error) => {
+        let kind = if error.is_timeout() {
+          "timeout"
+        } else if error.is_connect() {
+          "connect"
+        } else {
+          "transport"
+        };
+        metrics()
+          .errors
+          .with_label_values(&[labels[0], labels[1], labels[2], kind])
+          .inc();
+      }
+    }
+  }
+}
+
+impl Drop for AttemptTimer {
+  fn drop(&mut self) {
+    metrics().in_flight.with_label_values(&[&self.target]).dec();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn cancelled_calls_count_as_timeouts() {
+    let counter = metrics()
+      .errors
+      .with_label_values(&["timeout-test", "GET", OTHER_ROUTE, "timeout"]);
+
+    record_timeout("timeout-test", &Method::GET, OTHER_ROUTE);
+
+    assert_that(&counter.get()).is_equal_to(1);
+  }
+
+  #[test]
+  fn in_flight_is_tracked_until_dropped() {
+    let gauge = metrics().in_flight.with_label_values(&["in-flight-test"]);
+    let timer = AttemptTimer::start("in-flight-test", &Method::GET, "/".to_string());
+
+    assert_that(&gauge.get()).is_equal_to(1);
+    drop(timer);
+    assert_that(&gauge.get()).is_equal_to(0);
+  }
+}
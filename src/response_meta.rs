@@ -0,0 +1,142 @@
+use crate::{ws_try::FromClientResponse, AsyncBusinessResult};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, ETAG, LAST_MODIFIED, LINK, LOCATION};
+use reqwest::{Response, StatusCode};
+use std::time::SystemTime;
+use url::Url;
+
+/// The decoded body of a response together with its status and headers.
+#[derive(Clone, Debug)]
+pub struct WithMeta<T> {
+  pub status: StatusCode,
+  pub headers: HeaderMap,
+  /// The URL of the response, after redirects, which relative `Location` and `Link` URLs refer to.
+  pub url: Url,
+  pub body: T,
+}
+
+impl<T> WithMeta<T> {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.get(name).and_then(|value| value.to_str().ok())
+  }
+
+  pub fn location(&self) -> Option<Url> {
+    self
+      .header(LOCATION.as_str())
+      .and_then(|location| self.url.join(location).ok())
+  }
+
+  pub fn etag(&self) -> Option<&str> {
+    self.header(ETAG.as_str())
+  }
+
+  pub fn last_modified(&self) -> Option<SystemTime> {
+    self
+      .header(LAST_MODIFIED.as_str())
+      .and_then(|value| httpdate::parse_http_date(value).ok())
+  }
+
+  pub fn content_type(&self) -> Option<&str> {
+    self.header(CONTENT_TYPE.as_str())
+  }
+
+  pub fn content_length(&self) -> Option<u64> {
+    self
+      .header(CONTENT_LENGTH.as_str())
+      .and_then(|value| value.parse().ok())
+  }
+
+  /// The target of the `Link` header with relation `rel`, e.g. `next` for pagination.
+  pub fn link(&self, rel: &str) -> Option<Url> {
+    self
+      .headers
+      .get_all(LINK)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(parse_links)
+      .find(|(link_rel, _)| link_rel.eq_ignore_ascii_case(rel))
+      .and_then(|(_, target)| self.url.join(&target).ok())
+  }
+
+  pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> WithMeta<U> {
+    WithMeta {
+      status: self.status,
+      headers: self.headers,
+      url: self.url,
+      body: f(self.body),
+    }
+  }
+}
+
+impl<T> FromClientResponse<WithMeta<T>> for WithMeta<T>
+where
+  T: FromClientResponse<T> + 'static,
+{
+  fn from_response(response: Response) -> AsyncBusinessResult<WithMeta<T>> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let url = response.url().clone();
+
+    Box::pin(async move {
+      Ok(WithMeta {
+        status,
+        headers,
+        url,
+        body: T::from_response(response).await?,
+      })
+    })
+  }
+
+  fn from_not_found(response: &Response) -> Option<WithMeta<T>> {
+    T::from_not_found(response).map(|body| WithMeta {
+      status: response.status(),
+      headers: response.headers().clone(),
+      url: response.url().clone(),
+      body,
+    })
+  }
+}
+
+/// Splits a `Link` header like `<https://host/orders?page=2>; rel="next", </orders?page=9>; rel="last"` into
+/// pairs of relation and target.
+pub(crate) fn parse_links(value: &str) -> Vec<(String, String)> {
+  let mut links = Vec::new();
+  let mut rest = value;
+
+  while let Some(start) = rest.find('<') {
+    let Some(end) = rest[start..].find('>') else {
+      break;
+    };
+    let target = &rest[start + 1..start + end];
+    rest = &rest[start + end + 1..];
+    let params_end = rest.find('<').unwrap_or(rest.len());
+    for param in rest[..params_end].trim_end().trim_end_matches(',').split(';') {
+      if let Some((name, rels)) = param.split_once('=') {
+        if name.trim().eq_ignore_ascii_case("rel") {
+          for rel in rels.trim().trim_matches('"').split_whitespace() {
+            links.push((rel.to_string(), target.to_string()));
+          }
+        }
+      }
+    }
+    rest = &rest[params_end..];
+  }
+
+  links
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn parses_link_headers() {
+    let links = parse_links(r#"<https://host/orders?page=2&size=10>; rel="next", </orders?page=1>; rel="first prev""#);
+
+    assert_that(&links).is_equal_to(vec![
+      ("next".to_string(), "https://host/orders?page=2&size=10".to_string()),
+      ("first".to_string(), "/orders?page=1".to_string()),
+      ("prev".to_string(), "/orders?page=1".to_string()),
+    ]);
+  }
+}
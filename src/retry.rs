@@ -0,0 +1,187 @@
+use prometheus::{register, HistogramOpts, HistogramVec};
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Method, Response};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+/// When and how often `ServiceRequester` repeats a failed call.
+///
+/// Only idempotent methods are retried unless `retry_non_idempotent` is set, and requests
+/// with streaming bodies are never retried since they cannot be replayed.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  pub max_attempts: u32,
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+  pub multiplier: f64,
+  /// Fraction of each backoff that is randomized, between 0 and 1.
+  pub jitter: f64,
+  pub retryable_statuses: Vec<u16>,
+  pub retry_connect_errors: bool,
+  pub retry_timeouts: bool,
+  pub retry_non_idempotent: bool,
+  /// A `Retry-After` longer than this ends the retries instead of waiting.
+  pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_attempts: 3,
+      initial_backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_secs(5),
+      multiplier: 2.0,
+      jitter: 0.2,
+      retryable_statuses: vec![429, 502, 503, 504],
+      retry_connect_errors: true,
+      retry_timeouts: true,
+      retry_non_idempotent: false,
+      max_retry_after: Duration::from_secs(30),
+    }
+  }
+}
+
+impl RetryPolicy {
+  pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+    self.max_attempts = max_attempts.max(1);
+    self
+  }
+
+  pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+    self.initial_backoff = initial_backoff;
+    self.max_backoff = max_backoff;
+    self
+  }
+
+  pub fn with_jitter(mut self, jitter: f64) -> Self {
+    self.jitter = jitter.clamp(0.0, 1.0);
+    self
+  }
+
+  pub fn with_retryable_statuses(mut self, statuses: Vec<u16>) -> Self {
+    self.retryable_statuses = statuses;
+    self
+  }
+
+  pub fn retry_non_idempotent(mut self) -> Self {
+    self.retry_non_idempotent = true;
+    self
+  }
+
+  pub fn allows_method(&self, method: &Method) -> bool {
+    self.retry_non_idempotent || method.is_idempotent()
+  }
+
+  /// Backoff before attempt `attempt + 1`, without jitter.
+  pub fn backoff(&self, attempt: u32) -> Duration {
+    let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+
+    self.initial_backoff.mul_f64(factor).min(self.max_backoff)
+  }
+
+  fn jittered(&self, backoff: Duration) -> Duration {
+    if self.jitter <= 0.0 {
+      return backoff;
+    }
+    let spread = backoff.mul_f64(self.jitter);
+
+    backoff - spread + spread.mul_f64(rand::thread_rng().gen_range(0.0..=2.0))
+  }
+
+  /// How long to wait before the next attempt, `None` if the outcome is final.
+  pub fn retry_delay(&self, attempt: u32, outcome: &Result<Response, reqwest::Error>) -> Option<Duration> {
+    if attempt >= self.max_attempts {
+      return None;
+    }
+    let backoff = self.jittered(self.backoff(attempt));
+
+    match outcome {
+      Ok(response) if self.retryable_statuses.contains(&response.status().as_u16()) => {
+        match response.headers().get(RETRY_AFTER).and_then(|v| v.to_str().ok()) {
+          Some(retry_after) => match parse_retry_after(retry_after, SystemTime::now()) {
+            Some(retry_after) if retry_after > self.max_retry_after => None,
+            Some(retry_after) => Some(retry_after.max(backoff)),
+            None => Some(backoff),
+          },
+          None => Some(backoff),
+        }
+      }
+      Ok(_) => None,
+      Err(err) if err.is_timeout() => self.retry_timeouts.then_some(backoff),
+      Err(err) if err.is_connect() => self.retry_connect_errors.then_some(backoff),
+      Err(_) => None,
+    }
+  }
+}
+
+/// Parses either delta-seconds or an HTTP date.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+  match value.trim().parse::<u64>() {
+    Ok(seconds) => Some(Duration::from_secs(seconds)),
+    Err(_) => httpdate::parse_http_date(value.trim())
+      .ok()
+      .map(|date| date.duration_since(now).unwrap_or_default()),
+  }
+}
+
+pub(crate) fn attempts_histogram() -> &'static HistogramVec {
+  static HISTOGRAM: OnceLock<HistogramVec> = OnceLock::new();
+
+  HISTOGRAM.get_or_init(|| {
+    let opts = HistogramOpts::new(
+      "service_requester_attempts",
+      "Attempts needed per outbound service request",
+    )
+    .buckets(vec![1.0, 2.0, 3.0, 4.0, 5.0, 7.0, 10.0]);
+    let histogram = HistogramVec::new(opts, &["target", "outcome"]).unwrap();
+
+    register(Box::new(histogram.clone())).unwrap();
+
+    histogram
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn backoff_grows_exponentially_up_to_max() {
+    let policy = RetryPolicy::default().with_backoff(Duration::from_millis(100), Duration::from_millis(350));
+
+    assert_that(&policy.backoff(1)).is_equal_to(Duration::from_millis(100));
+    assert_that(&policy.backoff(2)).is_equal_to(Duration::from_millis(200));
+    assert_that(&policy.backoff(3)).is_equal_to(Duration::from_millis(350));
+  }
+
+  #[test]
+  fn jitter_stays_within_bounds() {
+    let policy = RetryPolicy::default().with_jitter(0.5);
+
+    for _ in 0..100 {
+      let jittered = policy.jittered(Duration::from_millis(100));
+      assert_that(&(jittered >= Duration::from_millis(50) && jittered <= Duration::from_millis(150))).is_true();
+    }
+  }
+
+  #[test]
+  fn only_idempotent_methods_are_retried_by_default() {
+    let policy = RetryPolicy::default();
+
+    assert_that(&policy.allows_method(&Method::GET)).is_true();
+    assert_that(&policy.allows_method(&Method::PUT)).is_true();
+    assert_that(&policy.allows_method(&Method::POST)).is_false();
+    assert_that(&policy.retry_non_idempotent().allows_method(&Method::POST)).is_true();
+  }
+
+  #[test]
+  fn retry_after_accepts_seconds_and_dates() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+
+    assert_that(&parse_retry_after("120", now)).is_equal_to(Some(Duration::from_secs(120)));
+    assert_that(&parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now)).is_equal_to(Some(Duration::from_secs(30)));
+    assert_that(&parse_retry_after("soon", now)).is_none();
+  }
+}
@@ -1,13 +1,40 @@
 use crate::{
-  ws_try::{default_error_handler, FromClientResponse, SendClientRequestExt},
-  BusinessResult, Problem,
+  auth_middleware::{
+    is_identity_header, AuthContext, ORGANIZATION_HEADER_NAME, REAL_SUBJECT_HEADER_NAME, SCOPES_HEADER_PREFIX,
+    SUBJECT_HEADER_NAME, TOKEN_HEADER_NAME,
+  },
+  bulkhead::Bulkheads,
+  circuit_breaker::{CircuitBreakers, CircuitPermit},
+  client_stream::ReadTimeout,
+  fixtures::Fixtures,
+  http_cache::{CacheLookup, HttpCache},
+  request_id::{RequestId, REQUEST_ID_HEADER_NAME},
+  requester::Requester,
+  requester_metrics::{record_timeout, AttemptTimer, OTHER_ROUTE},
+  retry::{attempts_histogram, RetryPolicy},
+  service_requester_builder::{ClientReloader, ServiceRequesterBuilder},
+  service_resolver::{ServiceDiscovery, SERVICE_SCHEME},
+  tls::{ClientIdentity, ClientTlsConfig},
+  trace::{Span, SpanKind, TRACEPARENT_HEADER_NAME, TRACESTATE_HEADER_NAME},
+  url_template::query_pairs,
+  ws_try::{default_error_handler, from_error_response, FromClientResponse},
+  AsyncBusinessResult, BusinessResult, Problem,
 };
+use actix_web::rt::task::JoinHandle;
+use actix_web::rt::time::{sleep, timeout};
 use bytes::Bytes;
-use reqwest::{redirect::Policy, Client, IntoUrl, Method, RequestBuilder, StatusCode};
+use log::{error, info};
+use reqwest::{
+  header::{HeaderMap, HeaderName, HeaderValue},
+  Client, IntoUrl, Method, Request, RequestBuilder, Response, StatusCode,
+};
 use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use url::form_urlencoded::byte_serialize;
+use url::{form_urlencoded::byte_serialize, Url};
 
+/// Form encodes `value` for use in a query string, see `url_template::encode_path_segment` for paths.
 pub fn encode_url_component<S: AsRef<[u8]>>(value: S) -> String {
   byte_serialize(value.as_ref()).collect::<String>()
 }
@@ -25,28 +52,117 @@ where
   }
 }
 
+pub type ErrorHandler = Arc<dyn Fn(StatusCode, Result<Bytes, reqwest::Error>) -> Problem + Send + Sync>;
+
+/// Options for a single call, see `ServiceRequester::with_options`.
+#[derive(Clone, Debug, Default)]
+pub struct RequestOptions {
+  pub timeout: Option<Duration>,
+  pub headers: HeaderMap,
+  /// Only a `404` with a problem of this type is `None` for `Option` responses, instead of any `404`.
+  pub not_found_problem_type: Option<String>,
+  /// The `route` of metrics and span names, e.g. from `UrlTemplate::route`, by default `other`.
+  pub route: Option<String>,
+}
+
+impl RequestOptions {
+  /// Limits each attempt and the call as a whole, including retries, to `timeout`.
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  /// Adds a header to the call, identity headers (`x-auth-*`) are rejected as they are set by the requester.
+  pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+    self.headers.append(name, value);
+    self
+  }
+
+  pub fn with_not_found_problem_type<S: Into<String>>(mut self, problem_type: S) -> Self {
+    self.not_found_problem_type = Some(problem_type.into());
+    self
+  }
+
+  pub fn with_route<S: Into<String>>(mut self, route: S) -> Self {
+    self.route = Some(route.into());
+    self
+  }
+
+  fn route(&self) -> &str {
+    self.route.as_deref().unwrap_or(OTHER_ROUTE)
+  }
+}
+
 #[derive(Clone)]
 pub struct ServiceRequester {
-  client: Client,
-  service_name: &'static str,
-  error_handler: &'static (dyn Fn(StatusCode, Result<Bytes, reqwest::Error>) -> Problem + Sync),
+  client: Arc<RwLock<Client>>,
+  reloader: Option<Arc<ClientReloader>>,
+  service_name: Arc<str>,
+  error_handler: ErrorHandler,
+  total_timeout: Option<Duration>,
+  read_timeout: Option<Duration>,
+  options: RequestOptions,
+  auth_context: Option<AuthContext>,
+  retry_policy: Option<RetryPolicy>,
+  circuit_breakers: Option<Arc<CircuitBreakers>>,
+  bulkheads: Option<Arc<Bulkheads>>,
+  service_discovery: Option<Arc<ServiceDiscovery>>,
+  cache: Option<Arc<HttpCache>>,
+  fixtures: Option<Arc<Fixtures>>,
 }
 
 impl ServiceRequester {
+  pub fn builder<S: Into<String>>(service_name: S) -> ServiceRequesterBuilder {
+    ServiceRequesterBuilder::new(service_name)
+  }
+
   pub fn with_service_auth(service_name: &'static str) -> BusinessResult<Self> {
     ServiceRequester::with_service_auth_with_timeout(service_name, 120)
   }
 
   pub fn with_service_auth_with_timeout(service_name: &'static str, timeout_seconds: u16) -> BusinessResult<Self> {
-    Ok(ServiceRequester {
-      client: Client::builder()
-        .connect_timeout(Duration::from_secs(timeout_seconds as u64))
-        .timeout(Duration::from_secs(timeout_seconds as u64))
-        .redirect(Policy::none())
-        .build()?,
-      service_name,
-      error_handler: &default_error_handler,
-    })
+    ServiceRequester::builder(service_name)
+      .connect_timeout(Duration::from_secs(timeout_seconds as u64))
+      .request_timeout(Duration::from_secs(timeout_seconds as u64))
+      .build()
+  }
+
+  /// Presents the client certificate in `cert_path` (with its PKCS#8 key in `key_path`), e.g. for mutual TLS.
+  pub fn with_client_identity<S: Into<String>, P: AsRef<Path>>(
+    service_name: S,
+    cert_path: P,
+    key_path: P,
+  ) -> BusinessResult<Self> {
+    ServiceRequester::builder(service_name)
+      .tls(ClientTlsConfig::default().with_identity(ClientIdentity::Pem {
+        cert_path: cert_path.as_ref().to_path_buf(),
+        key_path: key_path.as_ref().to_path_buf(),
+      }))
+      .build()
+  }
+
+  pub(crate) fn new(
+    service_name: String,
+    client: Client,
+    total_timeout: Option<Duration>,
+    reloader: Option<Arc<ClientReloader>>,
+  ) -> Self {
+    ServiceRequester {
+      client: Arc::new(RwLock::new(client)),
+      reloader,
+      service_name: service_name.into(),
+      error_handler: Arc::new(default_error_handler),
+      total_timeout,
+      read_timeout: None,
+      options: RequestOptions::default(),
+      auth_context: None,
+      retry_policy: None,
+      circuit_breakers: None,
+      bulkheads: None,
+      service_discovery: None,
+      cache: None,
+      fixtures: None,
+    }
   }
 
   pub fn with_error_handler(
@@ -54,9 +170,151 @@ impl ServiceRequester {
     error_handler: &'static (dyn Fn(StatusCode, Result<Bytes, reqwest::Error>) -> Problem + Sync),
   ) -> Self {
     ServiceRequester {
-      client: self.client,
-      service_name: self.service_name,
-      error_handler,
+      error_handler: Arc::new(error_handler),
+      ..self
+    }
+  }
+
+  pub(crate) fn with_shared_error_handler(self, error_handler: ErrorHandler) -> Self {
+    ServiceRequester { error_handler, ..self }
+  }
+
+  fn client(&self) -> Client {
+    match self.client.read() {
+      Ok(client) => client.clone(),
+      Err(poisoned) => poisoned.into_inner().clone(),
+    }
+  }
+
+  /// Re-reads the TLS files configured via `ServiceRequesterBuilder::tls` if they changed, shared by all clones
+  /// of this requester. Returns whether the client was replaced.
+  pub fn reload_tls(&self) -> BusinessResult<bool> {
+    let client = match &self.reloader {
+      Some(reloader) => reloader.reload_if_changed()?,
+      None => None,
+    };
+
+    match (client, self.client.write()) {
+      (Some(client), Ok(mut current)) => {
+        *current = client;
+        Ok(true)
+      }
+      (Some(_), Err(_)) => Err(Problem::internal_server_error().with_details("HTTP client lock poisoned")),
+      (None, _) => Ok(false),
+    }
+  }
+
+  /// Checks for changed TLS files every `interval`, to be called from within the actix runtime.
+  pub fn spawn_tls_reloader(&self, interval: Duration) -> JoinHandle<()> {
+    let requester = self.clone();
+
+    actix_web::rt::spawn(async move {
+      loop {
+        sleep(interval).await;
+        match requester.reload_tls() {
+          Ok(true) => info!("Reloaded TLS configuration of {}", requester.service_name),
+          Ok(false) => (),
+          Err(problem) => error!("Failed to reload TLS configuration: {}", problem),
+        }
+      }
+    })
+  }
+
+  /// Applies `options` to every call made with the returned requester.
+  pub fn with_options(&self, options: RequestOptions) -> Self {
+    ServiceRequester {
+      options,
+      ..self.clone()
+    }
+  }
+
+  /// Retries failed requests according to `retry_policy`, by default every request is attempted once.
+  pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+    ServiceRequester {
+      retry_policy: Some(retry_policy),
+      ..self
+    }
+  }
+
+  /// Fails fast while the circuit breaker of a target is open, `circuit_breakers` may be shared between requesters.
+  pub fn with_circuit_breakers(self, circuit_breakers: Arc<CircuitBreakers>) -> Self {
+    ServiceRequester {
+      circuit_breakers: Some(circuit_breakers),
+      ..self
+    }
+  }
+
+  /// Resolves `service://<name>/...` URLs to instances of the named service.
+  pub fn with_service_discovery(self, service_discovery: Arc<ServiceDiscovery>) -> Self {
+    ServiceRequester {
+      service_discovery: Some(service_discovery),
+      ..self
+    }
+  }
+
+  /// Limits concurrent calls per target, `bulkheads` may be shared between requesters.
+  pub fn with_bulkheads(self, bulkheads: Arc<Bulkheads>) -> Self {
+    ServiceRequester {
+      bulkheads: Some(bulkheads),
+      ..self
+    }
+  }
+
+  /// Fails streamed bodies, like `ByteStream`, once no chunk arrived for `read_timeout`.
+  pub fn with_read_timeout(self, read_timeout: Duration) -> Self {
+    ServiceRequester {
+      read_timeout: Some(read_timeout),
+      ..self
+    }
+  }
+
+  /// Caches `GET` responses as allowed by their headers, `cache` may be shared between requesters.
+  pub fn with_cache(self, cache: Arc<HttpCache>) -> Self {
+    ServiceRequester {
+      cache: Some(cache),
+      ..self
+    }
+  }
+
+  /// Records responses to or replays them from `fixtures` instead of only sending requests, for integration tests.
+  pub fn with_fixtures(self, fixtures: Arc<Fixtures>) -> Self {
+    ServiceRequester {
+      fixtures: Some(fixtures),
+      ..self
+    }
+  }
+
+  /// Forwards the identity of `auth_context` instead of the service identity, including the admin impersonating it.
+  pub fn on_behalf_of(&self, auth_context: &AuthContext) -> Self {
+    ServiceRequester {
+      auth_context: Some(auth_context.clone()),
+      ..self.clone()
+    }
+  }
+
+  fn apply_auth(&self, request: RequestBuilder) -> RequestBuilder {
+    match &self.auth_context {
+      Some(auth_context) => {
+        let mut request = request
+          .header(SUBJECT_HEADER_NAME, auth_context.subject.to_string())
+          .header(TOKEN_HEADER_NAME, auth_context.token.as_str());
+
+        if let Some(organization) = &auth_context.organization {
+          request = request.header(ORGANIZATION_HEADER_NAME, organization.as_str());
+        }
+        if let Some(real_subject) = &auth_context.real_subject {
+          request = request.header(REAL_SUBJECT_HEADER_NAME, real_subject.to_string());
+        }
+        for (service, scopes) in &auth_context.scopes {
+          for scope in scopes {
+            request = request.header(format!("{}{}", SCOPES_HEADER_PREFIX, service), scope.as_str());
+          }
+        }
+        request
+      }
+      None => request
+        .header("X-Auth-Sub", format!("service/{}", self.service_name))
+        .header("X-Auth-Token", "internal-token"),
     }
   }
 
@@ -114,15 +372,8 @@ impl ServiceRequester {
     I: IntoClientRequest,
     O: FromClientResponse<O> + 'static,
   {
-    body
-      .apply_body(
-        self
-          .client
-          .request(method, url)
-          .header("X-Auth-Sub", format!("service/{}", self.service_name))
-          .header("X-Auth-Token", "internal-token"),
-      )
-      .expect_success_with_error(self.error_handler)
+    self
+      .execute(body.apply_body(self.apply_auth(self.client().request(method, url))))
       .await
   }
 
@@ -131,12 +382,297 @@ impl ServiceRequester {
     U: IntoUrl,
     O: FromClientResponse<O> + 'static,
   {
+    self.execute(self.apply_auth(self.client().request(method, url))).await
+  }
+
+  /// Like `get`, with `query` appended to the query string as described in `url_template::query_pairs`.
+  #[inline]
+  pub async fn get_with_query<U, Q, O>(&self, url: U, query: &Q) -> BusinessResult<O>
+  where
+    U: IntoUrl,
+    Q: Serialize + ?Sized,
+    O: FromClientResponse<O> + 'static,
+  {
+    self.without_body_with_query(Method::GET, url, query).await
+  }
+
+  pub async fn without_body_with_query<U, Q, O>(&self, method: Method, url: U, query: &Q) -> BusinessResult<O>
+  where
+    U: IntoUrl,
+    Q: Serialize + ?Sized,
+    O: FromClientResponse<O> + 'static,
+  {
+    let query = query_pairs(query)?;
+
     self
-      .client
-      .request(method, url)
-      .header("X-Auth-Sub", format!("service/{}", self.service_name))
-      .header("X-Auth-Token", "internal-token")
-      .expect_success_with_error(self.error_handler)
+      .execute(self.apply_auth(self.client().request(method, url).query(&query)))
       .await
   }
+
+  async fn execute<O>(&self, request: RequestBuilder) -> BusinessResult<O>
+  where
+    O: FromClientResponse<O> + 'static,
+  {
+    if let Some(name) = self
+      .options
+      .headers
+      .keys()
+      .find(|name| is_identity_header(name.as_str()))
+    {
+      return Err(Problem::internal_server_error().with_details(format!(
+        "Header {} of the request options would override the caller identity",
+        name
+      )));
+    }
+    let mut request = match RequestId::current() {
+      Some(request_id) => request.header(REQUEST_ID_HEADER_NAME, request_id.0),
+      None => request,
+    }
+    .headers(self.options.headers.clone())
+    .build()?;
+    if let Some(timeout) = self.options.timeout {
+      *request.timeout_mut() = Some(timeout);
+    }
+
+    match self.options.timeout.or(self.total_timeout) {
+      Some(total_timeout) => {
+        let url = request.url().clone();
+        let method = request.method().clone();
+        timeout(total_timeout, self.dispatch(request))
+          .await
+          .unwrap_or_else(|_| {
+            record_timeout(&target_of(&url), &method, self.options.route());
+            Err(Problem::for_status(504, "Gateway timeout").with_details(format!(
+              "No response from {} within {} ms",
+              url,
+              total_timeout.as_millis()
+            )))
+          })
+      }
+      None => self.dispatch(request).await,
+    }
+  }
+
+  async fn dispatch<O>(&self, mut request: Request) -> BusinessResult<O>
+  where
+    O: FromClientResponse<O> + 'static,
+  {
+    let target = target_of(request.url());
+    let lookup = match &self.cache {
+      Some(cache) => cache.lookup(&mut request, &target),
+      None => CacheLookup::Bypass,
+    };
+    if let CacheLookup::Fresh(response) = lookup {
+      return O::from_response(response).await;
+    }
+    let (outcome, attempts) = self.send(request, &target).await?;
+    let outcome = match (&self.cache, lookup, outcome) {
+      (Some(cache), CacheLookup::Miss(key, stale), Ok(response)) => {
+        Ok(cache.update(&key, stale, response, &target).await?)
+      }
+      (Some(cache), CacheLookup::Unsafe(url), Ok(response)) => {
+        if response.status().is_success() {
+          cache.invalidate_prefix(url.as_str());
+        }
+        Ok(response)
+      }
+      (_, _, outcome) => outcome,
+    };
+    let histogram = attempts_histogram();
+
+    let problem = match outcome {
+      Ok(response) if response.status().is_success() => {
+        histogram
+          .with_label_values(&[&target, "success"])
+          .observe(attempts as f64);
+        return O::from_response(response).await;
+      }
+      Ok(response) => {
+        let not_found_problem_type = self.options.not_found_problem_type.as_deref();
+        match from_error_response(response, not_found_problem_type, self.error_handler.as_ref()).await {
+          Ok(absent) => {
+            histogram
+              .with_label_values(&[&target, "success"])
+              .observe(attempts as f64);
+            return Ok(absent);
+          }
+          Err(problem) => {
+            histogram
+              .with_label_values(&[&target, "failure"])
+              .observe(attempts as f64);
+            problem
+          }
+        }
+      }
+      Err(error) => {
+        histogram
+          .with_label_values(&[&target, "error"])
+          .observe(attempts as f64);
+        Problem::from(error)
+      }
+    };
+
+    Err(match attempts {
+      1 => problem,
+      _ => problem.with_details(format!("Gave up after {} attempts", attempts)),
+    })
+  }
+
+  /// Sends `request`, repeating it as long as the retry policy and the circuit breaker of `target` allow.
+  ///
+  /// Each attempt holds a bulkhead slot of `target`, the slot of the last attempt is moved into the response so that
+  /// streamed bodies keep it until they are dropped.
+  async fn send(&self, mut request: Request, target: &str) -> BusinessResult<(Result<Response, reqwest::Error>, u32)> {
+    let policy = self
+      .retry_policy
+      .as_ref()
+      .filter(|policy| policy.allows_method(request.method()));
+    let route = self.options.route().to_string();
+    let mut attempt = 1;
+
+    let mut circuit = self.acquire_circuit(target)?;
+    loop {
+      let next = request.try_clone();
+      let permit = match &self.bulkheads {
+        Some(bulkheads) => Some(bulkheads.acquire(target).await?),
+        None => None,
+      };
+      let instance = self.resolve_instance(&mut request).await?;
+      let span = start_client_span(&mut request, &route);
+      let timer = AttemptTimer::start(target, request.method(), route.clone());
+      let mut outcome = match &self.fixtures {
+        Some(fixtures) => {
+          fixtures
+            .execute(request, |request| self.client().execute(request))
+            .await?
+        }
+        None => self.client().execute(request).await,
+      };
+      timer.finish(&outcome);
+      if let Some(mut span) = span {
+        match &outcome {
+          Ok(response) => span.set_attribute("http.status_code", response.status().as_u16()),
+          Err(error) => span.set_attribute("error.message", error),
+        }
+        span.set_error(!matches!(&outcome, Ok(response) if !response.status().is_server_error()));
+        span.end();
+      }
+      let healthy = matches!(&outcome, Ok(response) if !response.status().is_server_error());
+      if let Some(circuit) = circuit.take() {
+        circuit.record(healthy);
+      }
+      if let (Some(service_discovery), Some(instance)) = (&self.service_discovery, instance) {
+        service_discovery.report(&instance, healthy);
+      }
+
+      match (next, policy.and_then(|policy| policy.retry_delay(attempt, &outcome))) {
+        (Some(next), Some(delay)) => {
+          drop(permit);
+          sleep(delay).await;
+          circuit = match self.acquire_circuit(target) {
+            Ok(circuit) => circuit,
+            Err(_) => return Ok((outcome, attempt)),
+          };
+          request = next;
+          attempt += 1;
+        }
+        _ => {
+          if let Ok(response) = outcome.as_mut() {
+            if let Some(permit) = permit {
+              response.extensions_mut().insert(permit);
+            }
+            if let Some(read_timeout) = self.read_timeout {
+              response.extensions_mut().insert(ReadTimeout(read_timeout));
+            }
+          }
+          return Ok((outcome, attempt));
+        }
+      }
+    }
+  }
+
+  /// Replaces a `service://` URL with one of an instance of that service, returning the instance.
+  async fn resolve_instance(&self, request: &mut Request) -> BusinessResult<Option<Url>> {
+    if request.url().scheme() != SERVICE_SCHEME {
+      return Ok(None);
+    }
+    let service_discovery = self.service_discovery.as_ref().ok_or_else(|| {
+      Problem::internal_server_error().with_details(format!("No service discovery to resolve {}", request.url()))
+    })?;
+    let (instance, url) = service_discovery.resolve_url(request.url()).await?;
+
+    *request.url_mut() = url;
+    Ok(Some(instance))
+  }
+
+  fn acquire_circuit(&self, target: &str) -> BusinessResult<Option<CircuitPermit<'_>>> {
+    self
+      .circuit_breakers
+      .as_ref()
+      .map(|circuit_breakers| circuit_breakers.acquire(target))
+      .transpose()
+  }
+}
+
+impl Requester for ServiceRequester {
+  fn with_body<U, I, O>(&self, method: Method, url: U, body: I) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    I: IntoClientRequest,
+    O: FromClientResponse<O> + 'static,
+  {
+    let request = body.apply_body(self.apply_auth(self.client().request(method, url)));
+    let requester = self.clone();
+
+    Box::pin(async move { requester.execute(request).await })
+  }
+
+  fn without_body<U, O>(&self, method: Method, url: U) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    O: FromClientResponse<O> + 'static,
+  {
+    let request = self.apply_auth(self.client().request(method, url));
+    let requester = self.clone();
+
+    Box::pin(async move { requester.execute(request).await })
+  }
+
+  fn without_body_with_query<U, Q, O>(&self, method: Method, url: U, query: &Q) -> AsyncBusinessResult<O>
+  where
+    U: IntoUrl,
+    Q: Serialize + ?Sized,
+    O: FromClientResponse<O> + 'static,
+  {
+    let request = query_pairs(query).map(|query| self.apply_auth(self.client().request(method, url).query(&query)));
+    let requester = self.clone();
+
+    Box::pin(async move { requester.execute(request?).await })
+  }
+}
+
+/// Starts a client span if a trace is active and passes its context on in `traceparent` and `tracestate`.
+fn start_client_span(request: &mut Request, route: &str) -> Option<Span> {
+  let mut span = Span::start_child(format!("{} {}", request.method(), route), SpanKind::Client)?;
+  span.set_attribute("http.method", request.method());
+  let mut url = request.url().clone();
+  url.set_query(None);
+  url.set_fragment(None);
+  span.set_attribute("http.url", url);
+  let context = span.context();
+  if let Ok(traceparent) = HeaderValue::from_str(&context.traceparent()) {
+    request.headers_mut().insert(TRACEPARENT_HEADER_NAME, traceparent);
+  }
+  if let Some(trace_state) = context.trace_state.as_ref().and_then(|s| HeaderValue::from_str(s).ok()) {
+    request.headers_mut().insert(TRACESTATE_HEADER_NAME, trace_state);
+  }
+
+  Some(span)
+}
+
+fn target_of(url: &Url) -> String {
+  match (url.host_str(), url.port()) {
+    (Some(host), Some(port)) => format!("{}:{}", host, port),
+    (host, _) => host.unwrap_or_default().to_string(),
+  }
 }
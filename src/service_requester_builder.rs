@@ -0,0 +1,287 @@
+use crate::{
+  bulkhead::Bulkheads, circuit_breaker::CircuitBreakers, fixtures::Fixtures, http_cache::HttpCache, retry::RetryPolicy,
+  service_requester::ErrorHandler, service_resolver::ServiceDiscovery, tls::ClientTlsConfig, BusinessResult, Problem,
+  ServiceRequester,
+};
+use bytes::Bytes;
+use reqwest::{
+  header::{HeaderMap, HeaderName, HeaderValue},
+  redirect::Policy,
+  Client, Identity, Proxy, StatusCode,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct ClientSettings {
+  connect_timeout: Duration,
+  request_timeout: Option<Duration>,
+  default_headers: HeaderMap,
+  user_agent: Option<String>,
+  max_redirects: usize,
+  proxy: Option<Proxy>,
+  pool_max_idle_per_host: Option<usize>,
+  pool_idle_timeout: Option<Duration>,
+  identity: Option<Identity>,
+  tls: Option<ClientTlsConfig>,
+}
+
+impl ClientSettings {
+  fn build(&self) -> BusinessResult<Client> {
+    let redirect = match self.max_redirects {
+      0 => Policy::none(),
+      max_redirects => Policy::limited(max_redirects),
+    };
+    let mut client = Client::builder()
+      .connect_timeout(self.connect_timeout)
+      .redirect(redirect)
+      .default_headers(self.default_headers.clone());
+
+    if let Some(request_timeout) = self.request_timeout {
+      client = client.timeout(request_timeout);
+    }
+    if let Some(user_agent) = &self.user_agent {
+      client = client.user_agent(user_agent);
+    }
+    if let Some(proxy) = &self.proxy {
+      client = client.proxy(proxy.clone());
+    }
+    if let Some(max_idle) = self.pool_max_idle_per_host {
+      client = client.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = self.pool_idle_timeout {
+      client = client.pool_idle_timeout(idle_timeout);
+    }
+    if let Some(identity) = &self.identity {
+      client = client.identity(identity.clone());
+    }
+    if let Some(tls) = &self.tls {
+      client = tls.apply(client)?;
+    }
+
+    Ok(client.build()?)
+  }
+}
+
+/// Rebuilds the client of a `ServiceRequester` once its TLS files change.
+pub(crate) struct ClientReloader {
+  settings: ClientSettings,
+  fingerprint: Mutex<Vec<u8>>,
+}
+
+impl ClientReloader {
+  fn new(settings: ClientSettings) -> BusinessResult<ClientReloader> {
+    let fingerprint = match &settings.tls {
+      Some(tls) => tls.fingerprint()?,
+      None => vec![],
+    };
+
+    Ok(ClientReloader {
+      settings,
+      fingerprint: Mutex::new(fingerprint),
+    })
+  }
+
+  /// A new client if the TLS files differ from those last loaded.
+  pub fn reload_if_changed(&self) -> BusinessResult<Option<Client>> {
+    let fingerprint = match &self.settings.tls {
+      Some(tls) => tls.fingerprint()?,
+      None => return Ok(None),
+    };
+    let mut loaded = self
+      .fingerprint
+      .lock()
+      .map_err(|_| Problem::internal_server_error().with_details("TLS reload lock poisoned"))?;
+    if *loaded == fingerprint {
+      return Ok(None);
+    }
+    let client = self.settings.build()?;
+    *loaded = fingerprint;
+
+    Ok(Some(client))
+  }
+}
+
+/// Builds a `ServiceRequester` from owned configuration, e.g. read at runtime.
+///
+/// The connect timeout applies to establishing each connection and the request timeout to each attempt as a whole,
+/// from connecting until its response body is read. The read timeout limits the wait for each chunk of a streamed
+/// body, while buffered bodies are bounded by the request timeout. The total timeout covers a whole call, including
+/// retries and their backoff.
+pub struct ServiceRequesterBuilder {
+  service_name: String,
+  client: ClientSettings,
+  total_timeout: Option<Duration>,
+  read_timeout: Option<Duration>,
+  error_handler: Option<ErrorHandler>,
+  retry_policy: Option<RetryPolicy>,
+  circuit_breakers: Option<Arc<CircuitBreakers>>,
+  bulkheads: Option<Arc<Bulkheads>>,
+  service_discovery: Option<Arc<ServiceDiscovery>>,
+  cache: Option<Arc<HttpCache>>,
+  fixtures: Option<Arc<Fixtures>>,
+}
+
+impl ServiceRequesterBuilder {
+  pub fn new<S: Into<String>>(service_name: S) -> ServiceRequesterBuilder {
+    ServiceRequesterBuilder {
+      service_name: service_name.into(),
+      client: ClientSettings {
+        connect_timeout: Duration::from_secs(120),
+        request_timeout: Some(Duration::from_secs(120)),
+        default_headers: HeaderMap::new(),
+        user_agent: None,
+        max_redirects: 0,
+        proxy: None,
+        pool_max_idle_per_host: None,
+        pool_idle_timeout: None,
+        identity: None,
+        tls: None,
+      },
+      total_timeout: None,
+      read_timeout: None,
+      error_handler: None,
+      retry_policy: None,
+      circuit_breakers: None,
+      bulkheads: None,
+      service_discovery: None,
+      cache: None,
+      fixtures: None,
+    }
+  }
+
+  pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+    self.client.connect_timeout = timeout;
+    self
+  }
+
+  pub fn request_timeout(mut self, timeout: Duration) -> Self {
+    self.client.request_timeout = Some(timeout);
+    self
+  }
+
+  pub fn total_timeout(mut self, timeout: Duration) -> Self {
+    self.total_timeout = Some(timeout);
+    self
+  }
+
+  pub fn read_timeout(mut self, timeout: Duration) -> Self {
+    self.read_timeout = Some(timeout);
+    self
+  }
+
+  pub fn default_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+    self.client.default_headers.append(name, value);
+    self
+  }
+
+  pub fn user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+    self.client.user_agent = Some(user_agent.into());
+    self
+  }
+
+  /// Follows up to `max_redirects` redirects, by default redirects are returned as they are.
+  pub fn follow_redirects(mut self, max_redirects: usize) -> Self {
+    self.client.max_redirects = max_redirects;
+    self
+  }
+
+  pub fn proxy(mut self, proxy: Proxy) -> Self {
+    self.client.proxy = Some(proxy);
+    self
+  }
+
+  pub fn pool_max_idle_per_host(mut self, max_idle: usize) -> Self {
+    self.client.pool_max_idle_per_host = Some(max_idle);
+    self
+  }
+
+  pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+    self.client.pool_idle_timeout = Some(timeout);
+    self
+  }
+
+  pub fn identity(mut self, identity: Identity) -> Self {
+    self.client.identity = Some(identity);
+    self
+  }
+
+  /// Trust and identity from certificate files, which can be reloaded via `ServiceRequester::reload_tls`.
+  pub fn tls(mut self, tls: ClientTlsConfig) -> Self {
+    self.client.tls = Some(tls);
+    self
+  }
+
+  pub fn error_handler<F>(mut self, error_handler: F) -> Self
+  where
+    F: Fn(StatusCode, Result<Bytes, reqwest::Error>) -> Problem + Send + Sync + 'static,
+  {
+    self.error_handler = Some(Arc::new(error_handler));
+    self
+  }
+
+  pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+    self.retry_policy = Some(retry_policy);
+    self
+  }
+
+  pub fn circuit_breakers(mut self, circuit_breakers: Arc<CircuitBreakers>) -> Self {
+    self.circuit_breakers = Some(circuit_breakers);
+    self
+  }
+
+  pub fn bulkheads(mut self, bulkheads: Arc<Bulkheads>) -> Self {
+    self.bulkheads = Some(bulkheads);
+    self
+  }
+
+  pub fn service_discovery(mut self, service_discovery: Arc<ServiceDiscovery>) -> Self {
+    self.service_discovery = Some(service_discovery);
+    self
+  }
+
+  pub fn cache(mut self, cache: Arc<HttpCache>) -> Self {
+    self.cache = Some(cache);
+    self
+  }
+
+  pub fn fixtures(mut self, fixtures: Arc<Fixtures>) -> Self {
+    self.fixtures = Some(fixtures);
+    self
+  }
+
+  pub fn build(self) -> BusinessResult<ServiceRequester> {
+    let reloader = match self.client.tls {
+      Some(_) => Some(Arc::new(ClientReloader::new(self.client.clone())?)),
+      None => None,
+    };
+    let mut requester = ServiceRequester::new(self.service_name, self.client.build()?, self.total_timeout, reloader);
+
+    if let Some(read_timeout) = self.read_timeout {
+      requester = requester.with_read_timeout(read_timeout);
+    }
+    if let Some(error_handler) = self.error_handler {
+      requester = requester.with_shared_error_handler(error_handler);
+    }
+    if let Some(retry_policy) = self.retry_policy {
+      requester = requester.with_retry_policy(retry_policy);
+    }
+    if let Some(circuit_breakers) = self.circuit_breakers {
+      requester = requester.with_circuit_breakers(circuit_breakers);
+    }
+    if let Some(bulkheads) = self.bulkheads {
+      requester = requester.with_bulkheads(bulkheads);
+    }
+    if let Some(service_discovery) = self.service_discovery {
+      requester = requester.with_service_discovery(service_discovery);
+    }
+    if let Some(cache) = self.cache {
+      requester = requester.with_cache(cache);
+    }
+    if let Some(fixtures) = self.fixtures {
+      requester = requester.with_fixtures(fixtures);
+    }
+
+    Ok(requester)
+  }
+}
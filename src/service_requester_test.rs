@@ -1,5 +1,35 @@
-use crate::service_requester::encode_url_component;
+use crate::auth_middleware::AuthContext;
+use crate::bulkhead::{BulkheadConfig, Bulkheads};
+use crate::circuit_breaker::{CircuitBreakerConfig, CircuitBreakers, CircuitState};
+use crate::client_stream::{ByteStream, JsonLines};
+use crate::fixtures::Fixtures;
+use crate::http_cache::HttpCache;
+use crate::pagination::Pagination;
+use crate::request_body::Multipart;
+use crate::request_id::{RequestId, REQUEST_ID_HEADER_NAME};
+use crate::response_meta::WithMeta;
+use crate::retry::RetryPolicy;
+use crate::service_requester::{encode_url_component, RequestOptions, ServiceRequester};
+use crate::service_resolver::{ServiceDiscovery, StaticResolver};
+use crate::subject::Subject;
+use crate::tls::tests::{generate_certificate, write_pem};
+use crate::tls::{server_config_with_client_auth, ClientIdentity, ClientTlsConfig};
+use crate::trace::tests::tracer;
+use crate::trace::{SpanKind, TRACEPARENT_HEADER_NAME};
+use crate::types::Done;
+use crate::url_template::UrlTemplate;
+use crate::BusinessResult;
+use actix_web::{web, App, HttpServer};
+use futures::StreamExt;
+use reqwest::header::{HeaderName, HeaderValue};
 use spectral::prelude::*;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use url::Url;
 
 #[test]
 fn test_encode_urlcomponent() {
@@ -8,3 +38,737 @@ fn test_encode_urlcomponent() {
   assert_that(&encode_url_component("abc de f\\/?&").as_str()).is_equal_to("abc+de+f%5C%2F%3F%26");
   assert_that(&encode_url_component("äbcdü").as_str()).is_equal_to("%C3%A4bcd%C3%BC");
 }
+
+#[test]
+fn test_client_identity_from_pem() {
+  let (ca, ca_key) = generate_certificate(Some("ca"), None, None);
+  let (cert, key) = generate_certificate(Some("orders"), None, Some((&ca, &ca_key)));
+  let cert_path = write_pem("requester-identity-cert", cert.to_pem().unwrap());
+  let key_path = write_pem("requester-identity-key", key.private_key_to_pem_pkcs8().unwrap());
+
+  assert_that(&ServiceRequester::with_client_identity("orders", &cert_path, &key_path).is_ok()).is_true();
+  assert_that(&ServiceRequester::with_client_identity("orders", &key_path, &cert_path).is_err()).is_true();
+}
+
+/// Serves the canned `responses` in order, one per connection, and records the requests received.
+pub(crate) fn stub_server(responses: Vec<&'static str>) -> (String, Arc<Mutex<Vec<String>>>) {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let recorded = requests.clone();
+
+  thread::spawn(move || {
+    for response in responses {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut reader = BufReader::new(stream.try_clone().unwrap());
+      let mut content_length = 0;
+      let mut head = String::new();
+      loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        head.push_str(&line);
+        if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+          content_length = length.trim().parse().unwrap();
+        }
+        if line.trim().is_empty() {
+          break;
+        }
+      }
+      let mut body = vec![0; content_length];
+      reader.read_exact(&mut body).unwrap();
+      head.push_str(&String::from_utf8_lossy(&body));
+      recorded.lock().unwrap().push(head);
+      stream.write_all(response.as_bytes()).unwrap();
+    }
+  });
+
+  (url, requests)
+}
+
+pub(crate) fn http_response(status: &str, headers: &[(&str, &str)], body: &str) -> &'static str {
+  let headers = headers
+    .iter()
+    .map(|(name, value)| format!("{}: {}\r\n", name, value))
+    .collect::<String>();
+
+  Box::leak(
+    format!(
+      "HTTP/1.1 {}\r\nconnection: close\r\ncontent-length: {}\r\n{}\r\n{}",
+      status,
+      body.len(),
+      headers,
+      body
+    )
+    .into_boxed_str(),
+  )
+}
+
+fn fast_retries() -> RetryPolicy {
+  RetryPolicy::default()
+    .with_backoff(Duration::from_millis(1), Duration::from_millis(10))
+    .with_jitter(0.0)
+}
+
+#[actix_web::test]
+async fn test_retries_until_success() {
+  let (url, hits) = stub_server(vec![
+    http_response("503 Service Unavailable", &[], ""),
+    http_response("502 Bad Gateway", &[("retry-after", "0")], ""),
+    http_response("200 OK", &[("content-type", "application/json")], "42"),
+  ]);
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_retry_policy(fast_retries());
+
+  let result: BusinessResult<u32> = requester.get(url).await;
+
+  assert_that(&result.ok()).is_equal_to(Some(42));
+  assert_that(&hits.lock().unwrap().len()).is_equal_to(3);
+}
+
+#[actix_web::test]
+async fn test_gives_up_after_max_attempts() {
+  let (url, hits) = stub_server(vec![
+    http_response("503 Service Unavailable", &[], "down"),
+    http_response("503 Service Unavailable", &[], "still down"),
+  ]);
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_retry_policy(fast_retries().with_max_attempts(2));
+
+  let problem = requester.get::<_, u32>(url).await.unwrap_err();
+
+  assert_that(&problem.code).is_equal_to(503);
+  assert_that(&problem.details).is_equal_to(Some("still down: Gave up after 2 attempts".to_string()));
+  assert_that(&hits.lock().unwrap().len()).is_equal_to(2);
+}
+
+#[actix_web::test]
+async fn test_does_not_retry_non_idempotent_methods() {
+  let (url, hits) = stub_server(vec![http_response("503 Service Unavailable", &[], "")]);
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_retry_policy(fast_retries());
+
+  let problem = requester.post::<_, _, u32>(url, "body").await.unwrap_err();
+
+  assert_that(&problem.code).is_equal_to(503);
+  assert_that(&hits.lock().unwrap().len()).is_equal_to(1);
+}
+
+#[actix_web::test]
+async fn test_open_circuit_fails_fast() {
+  let (url, hits) = stub_server(vec![
+    http_response("500 Internal Server Error", &[], ""),
+    http_response("500 Internal Server Error", &[], ""),
+  ]);
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_circuit_breakers(Arc::new(CircuitBreakers::new(CircuitBreakerConfig {
+      minimum_calls: 2,
+      window_size: 2,
+      ..CircuitBreakerConfig::default()
+    })));
+
+  for _ in 0..2 {
+    assert_that(&requester.get::<_, u32>(url.as_str()).await.unwrap_err().code).is_equal_to(500);
+  }
+  let problem = requester.get::<_, u32>(url.as_str()).await.unwrap_err();
+
+  assert_that(&problem.code).is_equal_to(503);
+  assert_that(&hits.lock().unwrap().len()).is_equal_to(2);
+}
+
+#[actix_web::test]
+async fn test_full_bulkhead_fails_after_queue_timeout() {
+  let (url, hits) = stub_server(vec![http_response("200 OK", &[], "42")]);
+  let bulkheads = Arc::new(
+    Bulkheads::new(BulkheadConfig {
+      max_concurrency: 1,
+      queue_timeout: Duration::from_millis(50),
+    })
+    .unwrap(),
+  );
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_bulkheads(bulkheads.clone());
+
+  let permit = bulkheads.acquire(url.trim_start_matches("http://")).await.unwrap();
+  let problem = requester.get::<_, u32>(url.as_str()).await.unwrap_err();
+  drop(permit);
+  let result = requester.get::<_, u32>(url.as_str()).await;
+
+  assert_that(&problem.code).is_equal_to(503);
+  assert_that(&result.ok()).is_equal_to(Some(42));
+  assert_that(&hits.lock().unwrap().len()).is_equal_to(1);
+}
+
+#[actix_web::test]
+async fn test_streamed_bodies_hold_the_bulkhead_slot() {
+  let (url, hits) = stub_server(vec![
+    http_response("200 OK", &[], "42"),
+    http_response("200 OK", &[], "42"),
+  ]);
+  let bulkheads = Arc::new(
+    Bulkheads::new(BulkheadConfig {
+      max_concurrency: 1,
+      queue_timeout: Duration::from_millis(50),
+    })
+    .unwrap(),
+  );
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_bulkheads(bulkheads.clone());
+
+  let body = requester.get::<_, ByteStream>(url.as_str()).await.unwrap();
+  let problem = requester.get::<_, u32>(url.as_str()).await.unwrap_err();
+  drop(body);
+  let result = requester.get::<_, u32>(url.as_str()).await;
+
+  assert_that(&problem.code).is_equal_to(503);
+  assert_that(&result.ok()).is_equal_to(Some(42));
+  assert_that(&hits.lock().unwrap().len()).is_equal_to(2);
+}
+
+#[actix_web::test]
+async fn test_streamed_bodies_fail_when_stalling() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut head = [0; 1024];
+    let _ = stream.read(&mut head).unwrap();
+    stream
+      .write_all(b"HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 4\r\n\r\n42")
+      .unwrap();
+    thread::sleep(Duration::from_millis(500));
+  });
+  let requester = ServiceRequester::builder("test")
+    .read_timeout(Duration::from_millis(50))
+    .build()
+    .unwrap();
+
+  let chunks = requester
+    .get::<_, ByteStream>(url.as_str())
+    .await
+    .unwrap()
+    .collect::<Vec<_>>()
+    .await;
+
+  assert_that(&chunks.len()).is_equal_to(2);
+  assert_that(&chunks[0].as_ref().ok()).is_equal_to(Some(&bytes::Bytes::from_static(b"42")));
+  assert_that(&chunks[1].as_ref().err().map(|problem| problem.code)).is_equal_to(Some(504));
+}
+
+#[actix_web::test]
+async fn test_resolves_logical_service_urls() {
+  let (url, hits) = stub_server(vec![http_response(
+    "200 OK",
+    &[("content-type", "application/json")],
+    "7",
+  )]);
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_service_discovery(Arc::new(ServiceDiscovery::new(
+      StaticResolver::new().with_service("orders", &[&url]).unwrap(),
+    )));
+
+  let result: BusinessResult<u32> = requester.get("service://orders/api/v1/orders/1").await;
+  let unknown = requester.get::<_, u32>("service://billing/api").await.unwrap_err();
+
+  assert_that(&result.ok()).is_equal_to(Some(7));
+  assert_that(&hits.lock().unwrap().len()).is_equal_to(1);
+  assert_that(&unknown.details).is_equal_to(Some("Unknown service: billing".to_string()));
+}
+
+#[actix_web::test]
+async fn test_forwards_current_request_id() {
+  let (url, requests) = stub_server(vec![http_response("204 No Content", &[], "")]);
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+
+  let result: BusinessResult<Done> = RequestId::scope(RequestId("abc-123".to_string()), requester.get(url)).await;
+
+  assert_that(&result.is_ok()).is_true();
+  assert_that(&requests.lock().unwrap()[0]).contains(format!("{}: abc-123", REQUEST_ID_HEADER_NAME).as_str());
+}
+
+#[actix_web::test]
+async fn test_forwards_impersonated_identity() {
+  let (url, requests) = stub_server(vec![http_response("204 No Content", &[], "")]);
+  let auth_context = AuthContext {
+    subject: Subject::Customer("bob".to_string()),
+    token: "token".to_string(),
+    organization: None,
+    scopes: BTreeMap::new(),
+    real_subject: Some(Subject::Admin("alice".to_string())),
+  };
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .on_behalf_of(&auth_context);
+
+  let result: BusinessResult<Done> = requester.get(url).await;
+  let request = requests.lock().unwrap()[0].clone();
+
+  assert_that(&result.is_ok()).is_true();
+  assert_that(&request).contains("x-auth-sub: customer/bob");
+  assert_that(&request).contains("x-auth-real-sub: admin/alice");
+}
+
+#[actix_web::test]
+async fn test_propagates_trace_context() {
+  let (url, requests) = stub_server(vec![http_response("204 No Content", &[], "")]);
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+  let (tracer, exporter) = tracer();
+  let span = tracer.start_span("handler", SpanKind::Server, None);
+
+  let orders = UrlTemplate::new(format!("{}/orders/{{id}}", url)).param("id", 42);
+  let result: BusinessResult<Done> = span
+    .in_scope(
+      requester
+        .with_options(RequestOptions::default().with_route(orders.route()))
+        .get(orders.expand().unwrap()),
+    )
+    .await;
+  let spans = exporter.spans();
+
+  assert_that(&result.is_ok()).is_true();
+  assert_that(&spans.len()).is_equal_to(1);
+  assert_that(&spans[0].name.as_str()).is_equal_to("GET /orders/{id}");
+  assert_that(&spans[0].parent_span_id.as_ref()).is_equal_to(Some(&span.context().span_id));
+  assert_that(&requests.lock().unwrap()[0]).contains(
+    format!(
+      "{}: 00-{}-{}-01",
+      TRACEPARENT_HEADER_NAME, spans[0].trace_id, spans[0].span_id
+    )
+    .as_str(),
+  );
+}
+
+#[actix_web::test]
+async fn test_builder_and_request_options_set_headers() {
+  let (url, requests) = stub_server(vec![http_response("204 No Content", &[], "")]);
+  let requester = ServiceRequester::builder(String::from("orders"))
+    .user_agent("orders/1.0")
+    .default_header(HeaderName::from_static("x-tenant"), HeaderValue::from_static("default"))
+    .build()
+    .unwrap();
+  let options =
+    RequestOptions::default().with_header(HeaderName::from_static("x-tenant"), HeaderValue::from_static("acme"));
+
+  let result: BusinessResult<Done> = requester.with_options(options).get(url).await;
+  let request = requests.lock().unwrap()[0].clone();
+
+  assert_that(&result.is_ok()).is_true();
+  assert_that(&request).contains("user-agent: orders/1.0");
+  assert_that(&request).contains("x-tenant: acme");
+  assert_that(&request.contains("x-tenant: default")).is_false();
+  assert_that(&request).contains("x-auth-sub: service/orders");
+}
+
+#[actix_web::test]
+async fn test_request_options_cannot_override_identity() {
+  let (url, requests) = stub_server(vec![]);
+  let requester = ServiceRequester::with_service_auth("orders").unwrap();
+  let options = RequestOptions::default().with_header(
+    HeaderName::from_static("x-auth-sub"),
+    HeaderValue::from_static("admin/root"),
+  );
+
+  let problem = requester.with_options(options).get::<_, Done>(url).await.unwrap_err();
+
+  assert_that(&problem.code).is_equal_to(500);
+  assert_that(&requests.lock().unwrap().len()).is_equal_to(0);
+}
+
+#[actix_web::test]
+async fn test_total_timeout_covers_retries() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  let requester = ServiceRequester::builder("orders")
+    .request_timeout(Duration::from_millis(50))
+    .total_timeout(Duration::from_millis(120))
+    .retry_policy(fast_retries().with_max_attempts(10))
+    .build()
+    .unwrap();
+
+  let problem = requester.get::<_, u32>(url.as_str()).await.unwrap_err();
+  let quick = requester
+    .with_options(RequestOptions::default().with_timeout(Duration::from_millis(10)))
+    .get::<_, u32>(url.as_str())
+    .await
+    .unwrap_err();
+
+  assert_that(&problem.code).is_equal_to(504);
+  assert_that(&quick.code).is_equal_to(504);
+}
+
+#[actix_web::test]
+async fn test_private_ca_client_identity_and_reload() {
+  let (ca, ca_key) = generate_certificate(Some("ca"), None, None);
+  let (other_ca, _) = generate_certificate(Some("other-ca"), None, None);
+  let (server, server_key) = generate_certificate(Some("localhost"), None, Some((&ca, &ca_key)));
+  let (client, client_key) = generate_certificate(Some("orders"), None, Some((&ca, &ca_key)));
+  let ca_path = write_pem("tls-reload-ca", ca.to_pem().unwrap());
+  let trusted_path = write_pem("tls-reload-trusted", other_ca.to_pem().unwrap());
+  let server_cert_path = write_pem("tls-reload-server-cert", server.to_pem().unwrap());
+  let server_key_path = write_pem("tls-reload-server-key", server_key.private_key_to_pem_pkcs8().unwrap());
+  let client_cert_path = write_pem("tls-reload-client-cert", client.to_pem().unwrap());
+  let client_key_path = write_pem("tls-reload-client-key", client_key.private_key_to_pem_pkcs8().unwrap());
+  let identity = ClientIdentity::Pem {
+    cert_path: client_cert_path.to_path_buf(),
+    key_path: client_key_path.to_path_buf(),
+  };
+  let server = HttpServer::new(|| App::new().route("/", web::get().to(|| async { "42" })))
+    .workers(1)
+    .bind_rustls(
+      ("127.0.0.1", 0),
+      server_config_with_client_auth(&server_cert_path, &server_key_path, &ca_path).unwrap(),
+    )
+    .unwrap();
+  let url = format!("https://localhost:{}/", server.addrs()[0].port());
+  actix_web::rt::spawn(server.run());
+  let tls = ClientTlsConfig::default()
+    .with_root_ca(trusted_path.to_path_buf())
+    .only_custom_roots();
+  let requester = ServiceRequester::builder("orders")
+    .tls(tls.clone().with_identity(identity))
+    .build()
+    .unwrap();
+  let anonymous = ServiceRequester::builder("orders")
+    .tls(tls.with_root_ca(ca_path.to_path_buf()))
+    .build()
+    .unwrap();
+
+  let untrusted = requester.get::<_, u32>(url.as_str()).await;
+  let unchanged = requester.reload_tls().unwrap();
+  std::fs::write(&trusted_path, ca.to_pem().unwrap()).unwrap();
+  let reloaded = requester.reload_tls().unwrap();
+  let trusted = requester.get::<_, u32>(url.as_str()).await;
+  let without_identity = anonymous.get::<_, u32>(url.as_str()).await;
+
+  assert_that(&untrusted.is_err()).is_true();
+  assert_that(&unchanged).is_false();
+  assert_that(&reloaded).is_true();
+  assert_that(&trusted.ok()).is_equal_to(Some(42));
+  assert_that(&without_identity.is_err()).is_true();
+}
+
+#[actix_web::test]
+async fn test_streams_json_lines() {
+  let (url, _) = stub_server(vec![
+    http_response("200 OK", &[("content-type", "application/x-ndjson")], "1\n2\n3\n"),
+    "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 100\r\n\r\n4\n5",
+  ]);
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+
+  let complete = requester.get::<_, JsonLines<u32>>(url.as_str()).await.unwrap();
+  let complete = complete.collect::<Vec<_>>().await;
+  let truncated = requester.get::<_, JsonLines<u32>>(url.as_str()).await.unwrap();
+  let truncated = truncated.collect::<Vec<_>>().await;
+
+  assert_that(&complete.into_iter().collect::<BusinessResult<Vec<_>>>().ok()).is_equal_to(Some(vec![1, 2, 3]));
+  assert_that(&truncated.len()).is_equal_to(2);
+  assert_that(&truncated[0].as_ref().ok()).is_equal_to(Some(&4));
+  assert_that(&truncated[1].is_err()).is_true();
+}
+
+#[actix_web::test]
+async fn test_uploads_multipart_files() {
+  let (url, requests) = stub_server(vec![http_response("204 No Content", &[], "")]);
+  let path = std::env::temp_dir().join("service-requester-upload.csv");
+  std::fs::write(&path, "id,total\n42,9.99\n").unwrap();
+  let upload = Multipart::new()
+    .text("kind", "orders")
+    .file("file", &path)
+    .await
+    .unwrap();
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+
+  let result: BusinessResult<Done> = requester.post(url, upload).await;
+  let request = requests.lock().unwrap()[0].clone();
+
+  assert_that(&result.is_ok()).is_true();
+  assert_that(&request.contains("content-type: multipart/form-data; boundary=")).is_true();
+  assert_that(&request.contains("filename=\"service-requester-upload.csv\"\r\nContent-Type: text/csv")).is_true();
+  assert_that(&request.contains("id,total\n42,9.99\n")).is_true();
+}
+
+#[actix_web::test]
+async fn test_returns_status_and_headers_with_body() {
+  let (url, _) = stub_server(vec![
+    http_response(
+      "201 Created",
+      &[
+        ("content-type", "application/json"),
+        ("location", "/api/v1/orders/42"),
+        ("etag", "\"v1\""),
+        ("link", "</api/v1/orders?page=2>; rel=\"next\""),
+      ],
+      "42",
+    ),
+    http_response("204 No Content", &[], ""),
+  ]);
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+
+  let created: WithMeta<u32> = requester.post(url.as_str(), "order").await.unwrap();
+  let deleted: WithMeta<Done> = requester.delete(url.as_str()).await.unwrap();
+
+  assert_that(&created.status.as_u16()).is_equal_to(201);
+  assert_that(&created.body).is_equal_to(42);
+  assert_that(&created.location().map(String::from)).is_equal_to(Some(format!("{}/api/v1/orders/42", url)));
+  assert_that(&created.etag()).is_equal_to(Some("\"v1\""));
+  assert_that(&created.link("next").map(String::from)).is_equal_to(Some(format!("{}/api/v1/orders?page=2", url)));
+  assert_that(&deleted.status.as_u16()).is_equal_to(204);
+}
+
+#[actix_web::test]
+async fn test_caches_and_revalidates_responses() {
+  let (url, requests) = stub_server(vec![
+    http_response("200 OK", &[("cache-control", "max-age=60")], "1"),
+    http_response("200 OK", &[("etag", "\"v2\""), ("cache-control", "no-cache")], "2"),
+    http_response("304 Not Modified", &[("etag", "\"v2\"")], ""),
+    http_response("204 No Content", &[], ""),
+    http_response("200 OK", &[], "3"),
+  ]);
+  let cache = Arc::new(HttpCache::new(1024 * 1024));
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_cache(cache.clone());
+  let fresh_url = format!("{}/fresh", url);
+  let validated_url = format!("{}/validated", url);
+
+  let fresh = requester.get::<_, u32>(fresh_url.as_str()).await.unwrap();
+  let cached = requester.get::<_, u32>(fresh_url.as_str()).await.unwrap();
+  let validated = requester.get::<_, u32>(validated_url.as_str()).await.unwrap();
+  let revalidated = requester.get::<_, u32>(validated_url.as_str()).await.unwrap();
+  requester.delete::<_, Done>(fresh_url.as_str()).await.unwrap();
+  let refetched = requester.get::<_, u32>(fresh_url.as_str()).await.unwrap();
+  let requests = requests.lock().unwrap();
+
+  assert_that(&vec![fresh, cached, validated, revalidated, refetched]).is_equal_to(vec![1, 1, 2, 2, 3]);
+  assert_that(&requests.len()).is_equal_to(5);
+  assert_that(&requests[2].contains("if-none-match: \"v2\"")).is_true();
+  assert_that(&cache.size()).is_greater_than(0);
+}
+
+#[actix_web::test]
+async fn test_not_found_is_none_for_optional_responses() {
+  let order_not_found =
+    r#"{"code":404,"type":"https://example.com/order-not-found","reason":"Not found","details":null}"#;
+  let (url, _) = stub_server(vec![
+    http_response("404 Not Found", &[], ""),
+    http_response("200 OK", &[], "42"),
+    http_response("404 Not Found", &[], ""),
+    http_response(
+      "404 Not Found",
+      &[("content-type", "application/json")],
+      order_not_found,
+    ),
+    http_response("404 Not Found", &[], "no such route"),
+  ]);
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+  let typed = requester
+    .with_options(RequestOptions::default().with_not_found_problem_type("https://example.com/order-not-found"));
+
+  let missing = requester.get::<_, Option<u32>>(url.as_str()).await;
+  let found = requester.get::<_, Option<u32>>(url.as_str()).await;
+  let required = requester.get::<_, u32>(url.as_str()).await;
+  let typed_missing = typed.get::<_, Option<u32>>(url.as_str()).await;
+  let wrong_route = typed.get::<_, Option<u32>>(url.as_str()).await;
+
+  assert_that(&missing.ok()).is_equal_to(Some(None));
+  assert_that(&found.ok()).is_equal_to(Some(Some(42)));
+  assert_that(&required.map_err(|problem| problem.code)).is_equal_to(Err(404));
+  assert_that(&typed_missing.ok()).is_equal_to(Some(None));
+  assert_that(&wrong_route.map_err(|problem| problem.details)).is_equal_to(Err(Some("no such route".to_string())));
+}
+
+#[actix_web::test]
+async fn test_sends_typed_queries_to_templated_urls() {
+  let (url, requests) = stub_server(vec![http_response("200 OK", &[], "[]")]);
+  let orders = UrlTemplate::new(format!("{}/api/v1/customers/{{customer}}/orders", url))
+    .param("customer", "jane doe")
+    .expand()
+    .unwrap();
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+
+  let result: BusinessResult<Vec<u32>> = requester
+    .get_with_query(
+      orders,
+      &serde_json::json!({"status": ["open", "paid"], "q": "a&b", "limit": null}),
+    )
+    .await;
+  let request = requests.lock().unwrap()[0].clone();
+
+  assert_that(&result.ok()).is_equal_to(Some(vec![]));
+  assert_that(&request.starts_with("GET /api/v1/customers/jane%20doe/orders?q=a%26b&status=open&status=paid "))
+    .is_true();
+}
+
+#[actix_web::test]
+async fn test_paginates_via_links_offsets_and_cursors() {
+  let (url, requests) = stub_server(vec![
+    http_response("200 OK", &[("link", "</orders?page=2>; rel=\"next\"")], "[1,2]"),
+    http_response("200 OK", &[], "[3]"),
+    http_response("200 OK", &[], "[1,2]"),
+    http_response("200 OK", &[], "[3,4]"),
+    http_response("200 OK", &[], r#"{"items":[1,2],"next":"abc"}"#),
+    http_response("200 OK", &[], r#"{"items":[3],"next":null}"#),
+  ]);
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+  let orders = Url::parse(&format!("{}/orders?status=open", url)).unwrap();
+
+  let linked = requester
+    .paginate::<u32>(orders.clone(), Pagination::link_header())
+    .collect::<Vec<_>>()
+    .await;
+  let capped = requester
+    .paginate::<u32>(
+      orders.clone(),
+      Pagination::offset_limit("offset", "limit", 2).with_max_items(3),
+    )
+    .collect::<Vec<_>>()
+    .await;
+  let cursored = requester
+    .paginate::<u32>(orders, Pagination::cursor("next", "cursor").with_prefetch(2))
+    .collect::<Vec<_>>()
+    .await;
+  let paths = requests
+    .lock()
+    .unwrap()
+    .iter()
+    .map(|request| request.split(' ').nth(1).unwrap_or_default().to_string())
+    .collect::<Vec<_>>();
+
+  assert_that(&linked.into_iter().collect::<BusinessResult<Vec<_>>>().ok()).is_equal_to(Some(vec![1, 2, 3]));
+  assert_that(&capped.into_iter().collect::<BusinessResult<Vec<_>>>().ok()).is_equal_to(Some(vec![1, 2, 3]));
+  assert_that(&cursored.into_iter().collect::<BusinessResult<Vec<_>>>().ok()).is_equal_to(Some(vec![1, 2, 3]));
+  assert_that(&paths).is_equal_to(vec![
+    "/orders?status=open".to_string(),
+    "/orders?page=2".to_string(),
+    "/orders?status=open&offset=0&limit=2".to_string(),
+    "/orders?status=open&offset=2&limit=2".to_string(),
+    "/orders?status=open".to_string(),
+    "/orders?status=open&cursor=abc".to_string(),
+  ]);
+}
+
+#[test]
+fn test_paginates_outside_actix_within_the_current_request() {
+  let (url, requests) = stub_server(vec![
+    http_response("200 OK", &[], r#"{"items":[1],"next":"abc"}"#),
+    http_response("200 OK", &[], r#"{"items":[2],"next":null}"#),
+  ]);
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+  let orders = Url::parse(&format!("{}/orders", url)).unwrap();
+  let runtime = tokio::runtime::Builder::new_current_thread()
+    .enable_all()
+    .build()
+    .unwrap();
+
+  let items = runtime.block_on(RequestId::scope(
+    RequestId("abc-123".to_string()),
+    requester
+      .paginate::<u32>(orders, Pagination::cursor("next", "cursor").with_prefetch(2))
+      .collect::<Vec<_>>(),
+  ));
+
+  assert_that(&items.into_iter().collect::<BusinessResult<Vec<_>>>().ok()).is_equal_to(Some(vec![1, 2]));
+  assert_that(
+    &requests
+      .lock()
+      .unwrap()
+      .iter()
+      .all(|request| request.contains("abc-123")),
+  )
+  .is_true();
+}
+
+#[actix_web::test]
+async fn test_stops_paginating_at_repeated_pages() {
+  let (url, requests) = stub_server(vec![
+    http_response("200 OK", &[], r#"{"items":[1],"next":"abc"}"#),
+    http_response("200 OK", &[], r#"{"items":[2],"next":"abc"}"#),
+  ]);
+  let requester = ServiceRequester::with_service_auth("test").unwrap();
+  let orders = Url::parse(&format!("{}/orders", url)).unwrap();
+
+  let items = requester
+    .paginate::<u32>(orders, Pagination::cursor("next", "cursor"))
+    .collect::<Vec<_>>()
+    .await;
+
+  assert_that(&items.into_iter().collect::<BusinessResult<Vec<_>>>().ok()).is_equal_to(Some(vec![1, 2]));
+  assert_that(&requests.lock().unwrap().len()).is_equal_to(2);
+}
+
+#[actix_web::test]
+async fn test_records_and_replays_fixtures() {
+  let (url, requests) = stub_server(vec![
+    http_response("200 OK", &[("content-type", "application/json")], r#"{"id":42}"#),
+    http_response("201 Created", &[], "43"),
+  ]);
+  let path = std::env::temp_dir().join(format!("microtools-{}-fixtures.json", std::process::id()));
+  let order_url = format!("{}/orders/42?access_token=secret", url);
+  let orders_url = format!("{}/orders", url);
+  let recording = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_fixtures(Arc::new(Fixtures::record(&path)));
+
+  let recorded_order: serde_json::Value = recording.get(order_url.as_str()).await.unwrap();
+  let recorded_id: u32 = recording
+    .post(orders_url.as_str(), serde_json::json!({"total": 10, "currency": "EUR"}))
+    .await
+    .unwrap();
+  let unsaved = path.exists();
+  drop(recording);
+  let fixture = std::fs::read_to_string(&path).unwrap();
+  let replaying = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_fixtures(Arc::new(Fixtures::replay(&path).unwrap().matching_body()));
+  let replayed_order: serde_json::Value = replaying.get(order_url.as_str()).await.unwrap();
+  let replayed_id: u32 = replaying
+    .post(orders_url.as_str(), serde_json::json!({"currency": "EUR", "total": 10}))
+    .await
+    .unwrap();
+  let unknown = replaying
+    .post::<_, _, u32>(orders_url.as_str(), serde_json::json!({"total": 20}))
+    .await;
+  let _ = std::fs::remove_file(&path);
+
+  assert_that(&unsaved).is_false();
+  assert_that(&requests.lock().unwrap().len()).is_equal_to(2);
+  assert_that(&fixture.contains("internal-token")).is_false();
+  assert_that(&fixture.contains("secret")).is_false();
+  assert_that(&fixture.contains("REDACTED")).is_true();
+  assert_that(&replayed_order).is_equal_to(recorded_order);
+  assert_that(&replayed_id).is_equal_to(recorded_id);
+  assert_that(&unknown.err().and_then(|problem| problem.details)).is_equal_to(Some(format!(
+    "No recorded response for POST {} in {}",
+    orders_url,
+    path.display()
+  )));
+}
+
+#[actix_web::test]
+async fn test_missing_fixtures_are_neither_retried_nor_counted() {
+  let path = std::env::temp_dir().join(format!("microtools-{}-empty-fixtures.json", std::process::id()));
+  std::fs::write(&path, "[]").unwrap();
+  let circuit_breakers = Arc::new(CircuitBreakers::new(CircuitBreakerConfig {
+    minimum_calls: 1,
+    window_size: 1,
+    ..CircuitBreakerConfig::default()
+  }));
+  let requester = ServiceRequester::with_service_auth("test")
+    .unwrap()
+    .with_retry_policy(fast_retries())
+    .with_circuit_breakers(circuit_breakers.clone())
+    .with_fixtures(Arc::new(Fixtures::replay(&path).unwrap()));
+
+  let problem = requester.get::<_, u32>("http://orders/orders/42").await.unwrap_err();
+  let _ = std::fs::remove_file(&path);
+
+  assert_that(&problem.reason.as_str()).is_equal_to("Fixture missing");
+  assert_that(&problem.details.unwrap_or_default().contains("Gave up")).is_false();
+  assert_that(&circuit_breakers.state("orders")).is_equal_to(CircuitState::Closed);
+}
@@ -0,0 +1,429 @@
+use crate::{AsyncBusinessResult, BusinessResult, Problem};
+use futures::future;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Scheme of URLs that address a logical service, e.g. `service://orders/api/v1/orders/42`.
+pub const SERVICE_SCHEME: &str = "service";
+
+/// Looks up the base URLs of all instances of a logical service.
+pub trait ServiceResolver: Send + Sync {
+  fn resolve(&self, service: &str) -> AsyncBusinessResult<Vec<Url>>;
+}
+
+fn unknown_service(service: &str) -> Problem {
+  Problem::internal_server_error().with_details(format!("Unknown service: {}", service))
+}
+
+fn parse_base_url(service: &str, url: &str) -> BusinessResult<Url> {
+  Url::parse(url.trim())
+    .map_err(|e| Problem::internal_server_error().with_details(format!("Invalid URL for service {}: {}", service, e)))
+}
+
+/// Fixed instances per service, e.g. from a config file.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+  services: HashMap<String, Vec<Url>>,
+}
+
+impl StaticResolver {
+  pub fn new() -> StaticResolver {
+    StaticResolver::default()
+  }
+
+  pub fn with_service<S: Into<String>>(mut self, service: S, instances: &[&str]) -> BusinessResult<Self> {
+    let service = service.into();
+    let instances = instances
+      .iter()
+      .map(|url| parse_base_url(&service, url))
+      .collect::<BusinessResult<Vec<_>>>()?;
+
+    self.services.insert(service, instances);
+    Ok(self)
+  }
+
+  /// Reads a table of service names to lists of instance URLs stored under `key`.
+  #[cfg(feature = "with-config")]
+  pub fn from_config(config: &config::Config, key: &str) -> BusinessResult<StaticResolver> {
+    let services = config
+      .get::<HashMap<String, Vec<String>>>(key)
+      .map_err(|e| Problem::internal_server_error().with_details(format!("Invalid service config: {}", e)))?;
+
+    services
+      .iter()
+      .try_fold(StaticResolver::new(), |resolver, (service, instances)| {
+        resolver.with_service(
+          service.as_str(),
+          &instances.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+      })
+  }
+}
+
+impl ServiceResolver for StaticResolver {
+  fn resolve(&self, service: &str) -> AsyncBusinessResult<Vec<Url>> {
+    Box::pin(future::ready(
+      self
+        .services
+        .get(service)
+        .cloned()
+        .ok_or_else(|| unknown_service(service)),
+    ))
+  }
+}
+
+/// Reads comma separated instance URLs from `<SERVICE>_SERVICE_URL`, e.g. `ORDERS_SERVICE_URL` for `orders`.
+#[derive(Clone, Copy, Debug)]
+pub struct EnvResolver {
+  lookup: fn(&str) -> Option<String>,
+}
+
+impl Default for EnvResolver {
+  fn default() -> Self {
+    EnvResolver {
+      lookup: |name| std::env::var(name).ok(),
+    }
+  }
+}
+
+impl EnvResolver {
+  pub fn new() -> EnvResolver {
+    EnvResolver::default()
+  }
+
+  /// Reads variables via `lookup` instead of the process environment.
+  pub fn with_lookup(lookup: fn(&str) -> Option<String>) -> EnvResolver {
+    EnvResolver { lookup }
+  }
+
+  pub fn variable_name(service: &str) -> String {
+    format!("{}_SERVICE_URL", service.to_uppercase().replace('-', "_"))
+  }
+}
+
+impl ServiceResolver for EnvResolver {
+  fn resolve(&self, service: &str) -> AsyncBusinessResult<Vec<Url>> {
+    let result = match (self.lookup)(&EnvResolver::variable_name(service)) {
+      Some(urls) => urls.split(',').map(|url| parse_base_url(service, url)).collect(),
+      None => Err(unknown_service(service)),
+    };
+
+    Box::pin(future::ready(result))
+  }
+}
+
+/// Asks each resolver in turn until one knows the service, failing with the first error other than an unknown
+/// service, e.g. of an unreachable DNS server.
+pub struct ChainedResolver(pub Vec<Box<dyn ServiceResolver>>);
+
+impl ServiceResolver for ChainedResolver {
+  fn resolve(&self, service: &str) -> AsyncBusinessResult<Vec<Url>> {
+    let lookups = self
+      .0
+      .iter()
+      .map(|resolver| resolver.resolve(service))
+      .collect::<Vec<_>>();
+    let service = service.to_string();
+
+    Box::pin(async move {
+      let unknown = unknown_service(&service);
+      let mut failure = None;
+      for lookup in lookups {
+        match lookup.await {
+          Ok(instances) => return Ok(instances),
+          Err(problem) if problem == unknown => (),
+          Err(problem) => {
+            failure.get_or_insert(problem);
+          }
+        }
+      }
+      Err(failure.unwrap_or(unknown))
+    })
+  }
+}
+
+/// Resolves `orders` via the SRV record `_orders._tcp.<domain>` using the system DNS configuration.
+///
+/// Only the targets of the lowest priority are used, repeated according to their weights so that the round-robin
+/// of `ServiceDiscovery` spreads calls in proportion to the weights.
+#[cfg(feature = "with-dns")]
+pub struct DnsSrvResolver {
+  resolver: trust_dns_resolver::TokioAsyncResolver,
+  domain: String,
+  scheme: &'static str,
+}
+
+#[cfg(feature = "with-dns")]
+impl DnsSrvResolver {
+  pub fn from_system_conf<S: Into<String>>(domain: S) -> BusinessResult<DnsSrvResolver> {
+    let resolver = trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf()
+      .map_err(|e| Problem::internal_server_error().with_details(format!("DNS resolver: {}", e)))?;
+
+    Ok(DnsSrvResolver {
+      resolver,
+      domain: domain.into(),
+      scheme: "http",
+    })
+  }
+
+  pub fn with_scheme(self, scheme: &'static str) -> Self {
+    DnsSrvResolver { scheme, ..self }
+  }
+}
+
+#[cfg(feature = "with-dns")]
+impl ServiceResolver for DnsSrvResolver {
+  fn resolve(&self, service: &str) -> AsyncBusinessResult<Vec<Url>> {
+    let resolver = self.resolver.clone();
+    let name = format!("_{}._tcp.{}", service, self.domain);
+    let scheme = self.scheme;
+    let service = service.to_string();
+
+    Box::pin(async move {
+      let lookup = resolver
+        .srv_lookup(name)
+        .await
+        .map_err(|e| Problem::internal_server_error().with_details(format!("Unknown service: {}: {}", service, e)))?;
+      let priority = lookup.iter().map(|srv| srv.priority()).min();
+      let instances = lookup
+        .iter()
+        .filter(|srv| Some(srv.priority()) == priority)
+        .map(|srv| {
+          let target = srv.target().to_utf8();
+          let url = parse_base_url(
+            &service,
+            &format!("{}://{}:{}", scheme, target.trim_end_matches('.'), srv.port()),
+          )?;
+          Ok((url, srv.weight()))
+        })
+        .collect::<BusinessResult<Vec<_>>>()?;
+
+      Ok(weighted_round_robin(instances))
+    })
+  }
+}
+
+#[cfg(feature = "with-dns")]
+const MAX_WEIGHTED_SLOTS: u32 = 100;
+
+/// Repeats each instance in proportion to its weight, interleaved (smooth weighted round-robin).
+///
+/// Weights are scaled down to at most `MAX_WEIGHTED_SLOTS` slots, every instance gets at least one.
+#[cfg(feature = "with-dns")]
+fn weighted_round_robin(instances: Vec<(Url, u16)>) -> Vec<Url> {
+  let total = instances.iter().map(|(_, weight)| u32::from(*weight)).sum::<u32>();
+  let slots = instances
+    .iter()
+    .map(|(_, weight)| match total {
+      0 => 1,
+      total => (u32::from(*weight) * MAX_WEIGHTED_SLOTS / total).max(1),
+    })
+    .collect::<Vec<_>>();
+  let total_slots = slots.iter().sum::<u32>() as i64;
+  let mut current = vec![0i64; instances.len()];
+
+  (0..total_slots)
+    .filter_map(|_| {
+      for (current, slots) in current.iter_mut().zip(&slots) {
+        *current += i64::from(*slots);
+      }
+      let (next, _) = current
+        .iter()
+        .enumerate()
+        .max_by_key(|(index, current)| (**current, std::cmp::Reverse(*index)))?;
+      current[next] -= total_slots;
+      Some(instances[next].0.clone())
+    })
+    .collect()
+}
+
+/// Picks instances round-robin, skipping instances that recently failed for `ejection_duration`.
+/// If every instance is ejected, all of them are used again.
+pub struct ServiceDiscovery {
+  resolver: Box<dyn ServiceResolver>,
+  ejection_duration: Duration,
+  cursors: Mutex<HashMap<String, usize>>,
+  ejected: Mutex<HashMap<Url, Instant>>,
+}
+
+impl ServiceDiscovery {
+  pub fn new<R: ServiceResolver + 'static>(resolver: R) -> ServiceDiscovery {
+    ServiceDiscovery {
+      resolver: Box::new(resolver),
+      ejection_duration: Duration::from_secs(30),
+      cursors: Mutex::new(HashMap::new()),
+      ejected: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn with_ejection_duration(self, ejection_duration: Duration) -> Self {
+    ServiceDiscovery {
+      ejection_duration,
+      ..self
+    }
+  }
+
+  pub async fn select(&self, service: &str) -> BusinessResult<Url> {
+    let instances = self.resolver.resolve(service).await?;
+    let now = Instant::now();
+    let healthy = match self.ejected.lock() {
+      Ok(ejected) => instances
+        .iter()
+        .filter(|instance| {
+          ejected
+            .get(*instance)
+            .map(|since| now.duration_since(*since) >= self.ejection_duration)
+            .unwrap_or(true)
+        })
+        .collect::<Vec<_>>(),
+      Err(_) => instances.iter().collect(),
+    };
+    let candidates = if healthy.is_empty() {
+      instances.iter().collect()
+    } else {
+      healthy
+    };
+    if candidates.is_empty() {
+      return Err(Problem::service_unavailable().with_details(format!("No instances of service {}", service)));
+    }
+    let index = match self.cursors.lock() {
+      Ok(mut cursors) => {
+        let cursor = cursors.entry(service.to_string()).or_insert(0);
+        let index = *cursor;
+        *cursor = cursor.wrapping_add(1);
+        index
+      }
+      Err(_) => 0,
+    };
+
+    Ok(candidates[index % candidates.len()].clone())
+  }
+
+  pub fn report(&self, instance: &Url, healthy: bool) {
+    if let Ok(mut ejected) = self.ejected.lock() {
+      if healthy {
+        ejected.remove(instance);
+      } else {
+        ejected.insert(instance.clone(), Instant::now());
+      }
+    }
+  }
+
+  /// Maps `service://orders/path?query` onto an instance of `orders`, returning the instance and the resolved URL.
+  pub async fn resolve_url(&self, url: &Url) -> BusinessResult<(Url, Url)> {
+    let service = url
+      .host_str()
+      .ok_or_else(|| Problem::internal_server_error().with_details(format!("No service in URL {}", url)))?;
+    let instance = self.select(service).await?;
+    let mut resolved = instance.clone();
+
+    resolved.set_path(&format!("{}{}", instance.path().trim_end_matches('/'), url.path()));
+    resolved.set_query(url.query());
+    resolved.set_fragment(url.fragment());
+
+    Ok((instance, resolved))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  fn discovery() -> ServiceDiscovery {
+    ServiceDiscovery::new(
+      StaticResolver::new()
+        .with_service("orders", &["http://orders-1:8080/", "http://orders-2:8080/base"])
+        .unwrap(),
+    )
+  }
+
+  #[actix_web::test]
+  async fn resolves_service_urls_round_robin() {
+    let discovery = discovery();
+    let url = Url::parse("service://orders/api/v1/orders/42?expand=items").unwrap();
+
+    let (_, first) = discovery.resolve_url(&url).await.unwrap();
+    let (_, second) = discovery.resolve_url(&url).await.unwrap();
+
+    assert_that(&first.as_str()).is_equal_to("http://orders-1:8080/api/v1/orders/42?expand=items");
+    assert_that(&second.as_str()).is_equal_to("http://orders-2:8080/base/api/v1/orders/42?expand=items");
+  }
+
+  #[actix_web::test]
+  async fn skips_unhealthy_instances() {
+    let discovery = discovery();
+    let unhealthy = Url::parse("http://orders-1:8080/").unwrap();
+    discovery.report(&unhealthy, false);
+
+    for _ in 0..3 {
+      assert_that(&discovery.select("orders").await.unwrap().as_str()).is_equal_to("http://orders-2:8080/base");
+    }
+    discovery.report(&unhealthy, true);
+    let selected = [
+      discovery.select("orders").await.unwrap(),
+      discovery.select("orders").await.unwrap(),
+    ];
+    assert_that(&selected.contains(&unhealthy)).is_true();
+  }
+
+  #[actix_web::test]
+  async fn unknown_services_are_reported() {
+    let problem = discovery().select("billing").await.unwrap_err();
+
+    assert_that(&problem.details).is_equal_to(Some("Unknown service: billing".to_string()));
+  }
+
+  #[actix_web::test]
+  async fn chained_resolvers_report_the_first_failure() {
+    let broken =
+      || -> Box<dyn ServiceResolver> { Box::new(EnvResolver::with_lookup(|_| Some("not a url".to_string()))) };
+    let empty = || -> Box<dyn ServiceResolver> { Box::new(EnvResolver::with_lookup(|_| None)) };
+
+    let failed = ChainedResolver(vec![empty(), broken(), empty()])
+      .resolve("orders")
+      .await;
+    let unknown = ChainedResolver(vec![empty(), empty()]).resolve("orders").await;
+
+    assert_that(&failed.unwrap_err().details.unwrap()).starts_with("Invalid URL for service orders");
+    assert_that(&unknown.unwrap_err().details).is_equal_to(Some("Unknown service: orders".to_string()));
+  }
+
+  #[actix_web::test]
+  async fn env_resolver_follows_naming_convention() {
+    let resolver = EnvResolver::with_lookup(|name| match name {
+      "ORDER_HISTORY_SERVICE_URL" => Some("http://history-1, http://history-2".to_string()),
+      _ => None,
+    });
+
+    let instances = resolver.resolve("order-history").await.unwrap();
+    let unknown = resolver.resolve("orders").await;
+
+    assert_that(&instances.len()).is_equal_to(2);
+    assert_that(&instances[1].as_str()).is_equal_to("http://history-2/");
+    assert_that(&unknown.is_err()).is_true();
+  }
+
+  #[cfg(feature = "with-dns")]
+  #[test]
+  fn srv_weights_spread_instances() {
+    let url = |host: &str| Url::parse(&format!("http://{}:8080", host)).unwrap();
+
+    let weighted = weighted_round_robin(vec![(url("a"), 30), (url("b"), 10)]);
+    let unweighted = weighted_round_robin(vec![(url("a"), 0), (url("b"), 0)]);
+    let count = |host: &str| {
+      weighted
+        .iter()
+        .filter(|instance| instance.host_str() == Some(host))
+        .count()
+    };
+
+    assert_that(&count("a")).is_equal_to(75);
+    assert_that(&count("b")).is_equal_to(25);
+    assert_that(&weighted[..4].iter().filter_map(Url::host_str).collect::<Vec<_>>())
+      .is_equal_to(vec!["a", "a", "b", "a"]);
+    assert_that(&unweighted.len()).is_equal_to(2);
+  }
+}
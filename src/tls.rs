@@ -0,0 +1,371 @@
+use crate::auth_middleware::{AuthContext, AuthSource};
+use crate::subject::Subject;
+use crate::{BusinessResult, Problem};
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::dev::{Extensions, ServiceRequest};
+use actix_web::rt::net::TcpStream;
+use openssl::nid::Nid;
+use openssl::x509::X509;
+use reqwest::{ClientBuilder, Identity};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+pub fn load_certificates<P: AsRef<Path>>(path: P) -> BusinessResult<Vec<Certificate>> {
+  let mut reader = BufReader::new(File::open(path)?);
+
+  Ok(
+    rustls_pemfile::certs(&mut reader)?
+      .into_iter()
+      .map(Certificate)
+      .collect(),
+  )
+}
+
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> BusinessResult<PrivateKey> {
+  let mut reader = BufReader::new(File::open(path)?);
+
+  while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+    match item {
+      Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+      _ => (),
+    }
+  }
+  Err(Problem::internal_server_error().with_details("No private key found"))
+}
+
+/// Server config that only accepts clients presenting a certificate signed by one of the CAs in `client_ca_path`.
+pub fn server_config_with_client_auth<P: AsRef<Path>>(
+  cert_chain_path: P,
+  private_key_path: P,
+  client_ca_path: P,
+) -> BusinessResult<ServerConfig> {
+  let mut client_roots = RootCertStore::empty();
+  for ca in load_certificates(client_ca_path)? {
+    client_roots
+      .add(&ca)
+      .map_err(|err| Problem::internal_server_error().with_details(format!("Invalid client CA: {}", err)))?;
+  }
+
+  ServerConfig::builder()
+    .with_safe_defaults()
+    .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_roots))
+    .with_single_cert(load_certificates(cert_chain_path)?, load_private_key(private_key_path)?)
+    .map_err(|err| Problem::internal_server_error().with_details(format!("Invalid server certificate: {}", err)))
+}
+
+/// The verified certificate chain of the peer, leaf first.
+#[derive(Clone, Debug)]
+pub struct PeerCertificates(pub Vec<Certificate>);
+
+/// To be registered via `HttpServer::on_connect` so that `ClientCertificateAuth` can see the peer certificates.
+pub fn client_certificate_on_connect(connection: &dyn Any, data: &mut Extensions) {
+  if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+    if let Some(certificates) = stream.get_ref().1.peer_certificates() {
+      data.insert(PeerCertificates(certificates.to_vec()));
+    }
+  }
+}
+
+/// Service name of a certificate: the common name, or the first DNS subject alternative name if there is none.
+pub fn service_name_from_certificate(certificate: &Certificate) -> BusinessResult<String> {
+  let x509 = X509::from_der(&certificate.0)
+    .map_err(|err| Problem::unauthorized().with_details(format!("Invalid client certificate: {}", err)))?;
+
+  let common_name = x509
+    .subject_name()
+    .entries_by_nid(Nid::COMMONNAME)
+    .next()
+    .and_then(|entry| entry.data().as_utf8().ok())
+    .map(|cn| cn.to_string());
+  let alt_name = || {
+    x509
+      .subject_alt_names()
+      .and_then(|names| names.iter().find_map(|name| name.dnsname().map(|dns| dns.to_string())))
+  };
+
+  common_name
+    .or_else(alt_name)
+    .ok_or_else(|| Problem::unauthorized().with_details("Client certificate has neither CN nor SAN"))
+}
+
+/// Maps a verified client certificate to `Subject::Service`.
+///
+/// Sources are consulted in order, so after `GatewayHeaders` a certificate only counts for requests without
+/// gateway headers. Use `AuthMiddlewareFactory::with_client_certificate_auth` to consult it first.
+pub struct ClientCertificateAuth;
+
+impl AuthSource for ClientCertificateAuth {
+  fn authenticate(&self, req: &ServiceRequest) -> BusinessResult<Option<AuthContext>> {
+    let service_name = match req.conn_data::<PeerCertificates>().and_then(|c| c.0.first()) {
+      Some(certificate) => service_name_from_certificate(certificate)?,
+      None => return Ok(None),
+    };
+
+    Ok(Some(AuthContext {
+      subject: Subject::Service(service_name),
+      token: String::new(),
+      organization: None,
+      scopes: BTreeMap::new(),
+      real_subject: None,
+    }))
+  }
+}
+
+/// Client certificate presented by a `ServiceRequester`.
+#[derive(Clone, Debug)]
+pub enum ClientIdentity {
+  /// Certificate chain and PKCS#8 private key as PEM files.
+  Pem {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+  },
+  Pkcs12 {
+    path: PathBuf,
+    password: String,
+  },
+}
+
+/// TLS trust and identity of a `ServiceRequester`, read from files whenever its client is (re)built.
+#[derive(Clone, Debug, Default)]
+pub struct ClientTlsConfig {
+  pub root_ca_paths: Vec<PathBuf>,
+  /// Trust only `root_ca_paths`, not the system roots.
+  pub only_custom_roots: bool,
+  pub identity: Option<ClientIdentity>,
+}
+
+impl ClientTlsConfig {
+  pub fn with_root_ca<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.root_ca_paths.push(path.into());
+    self
+  }
+
+  pub fn only_custom_roots(mut self) -> Self {
+    self.only_custom_roots = true;
+    self
+  }
+
+  pub fn with_identity(mut self, identity: ClientIdentity) -> Self {
+    self.identity = Some(identity);
+    self
+  }
+
+  fn paths(&self) -> Vec<&Path> {
+    let identity_paths = match &self.identity {
+      Some(ClientIdentity::Pem { cert_path, key_path }) => vec![cert_path.as_path(), key_path.as_path()],
+      Some(ClientIdentity::Pkcs12 { path, .. }) => vec![path.as_path()],
+      None => vec![],
+    };
+
+    self
+      .root_ca_paths
+      .iter()
+      .map(PathBuf::as_path)
+      .chain(identity_paths)
+      .collect()
+  }
+
+  /// Digest over all files involved, to notice when they are replaced.
+  pub fn fingerprint(&self) -> BusinessResult<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    for path in self.paths() {
+      hasher.update(fs::read(path)?);
+    }
+
+    Ok(hasher.finalize().to_vec())
+  }
+
+  pub fn apply(&self, mut builder: ClientBuilder) -> BusinessResult<ClientBuilder> {
+    for path in &self.root_ca_paths {
+      let mut reader = BufReader::new(File::open(path)?);
+      for der in rustls_pemfile::certs(&mut reader)? {
+        builder = builder.add_root_certificate(reqwest::Certificate::from_der(&der)?);
+      }
+    }
+    if self.only_custom_roots {
+      builder = builder.tls_built_in_root_certs(false);
+    }
+    let identity = match &self.identity {
+      Some(ClientIdentity::Pem { cert_path, key_path }) => {
+        Some(Identity::from_pkcs8_pem(&fs::read(cert_path)?, &fs::read(key_path)?)?)
+      }
+      Some(ClientIdentity::Pkcs12 { path, password }) => Some(Identity::from_pkcs12_der(&fs::read(path)?, password)?),
+      None => None,
+    };
+
+    Ok(match identity {
+      Some(identity) => builder.identity(identity),
+      None => builder,
+    })
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::ServiceRequester;
+  use actix_web::{web, App, HttpResponse, HttpServer};
+  use futures::future::ok;
+  use openssl::asn1::Asn1Time;
+  use openssl::bn::{BigNum, MsbOption};
+  use openssl::hash::MessageDigest;
+  use openssl::pkey::{PKey, Private};
+  use openssl::rsa::Rsa;
+  use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+  use openssl::x509::{X509Builder, X509NameBuilder};
+  use spectral::prelude::*;
+
+  pub fn generate_certificate(
+    common_name: Option<&str>,
+    alt_name: Option<&str>,
+    issuer: Option<(&X509, &PKey<Private>)>,
+  ) -> (X509, PKey<Private>) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("O", "microtools").unwrap();
+    if let Some(common_name) = common_name {
+      name.append_entry_by_text("CN", common_name).unwrap();
+    }
+    let name = name.build();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder
+      .set_issuer_name(issuer.map(|(cert, _)| cert.subject_name()).unwrap_or(&name))
+      .unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    match issuer {
+      Some(_) => {
+        let mut san = SubjectAlternativeName::new();
+        san.dns(alt_name.unwrap_or("localhost"));
+        let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+      }
+      None => builder
+        .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap(),
+    }
+    builder
+      .sign(issuer.map(|(_, key)| key).unwrap_or(&key), MessageDigest::sha256())
+      .unwrap();
+
+    (builder.build(), key)
+  }
+
+  /// A file in the temp dir, removed when dropped.
+  pub struct TempFile(PathBuf);
+
+  impl TempFile {
+    pub fn new(name: &str) -> TempFile {
+      let path = std::env::temp_dir().join(format!("microtools-{}-{}", std::process::id(), name));
+      let _ = fs::remove_file(&path);
+      TempFile(path)
+    }
+  }
+
+  impl std::ops::Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+      &self.0
+    }
+  }
+
+  impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+      &self.0
+    }
+  }
+
+  impl Drop for TempFile {
+    fn drop(&mut self) {
+      let _ = fs::remove_file(&self.0);
+    }
+  }
+
+  pub fn write_pem(name: &str, pem: Vec<u8>) -> TempFile {
+    let file = TempFile::new(&format!("{}.pem", name));
+    fs::write(&file, pem).unwrap();
+    file
+  }
+
+  #[test]
+  fn service_name_prefers_common_name() {
+    let (ca, ca_key) = generate_certificate(Some("ca"), None, None);
+    let (with_cn, _) = generate_certificate(Some("orders"), Some("orders.internal"), Some((&ca, &ca_key)));
+    let (without_cn, _) = generate_certificate(None, Some("billing.internal"), Some((&ca, &ca_key)));
+
+    assert_that(&service_name_from_certificate(&Certificate(with_cn.to_der().unwrap())).unwrap())
+      .is_equal_to("orders".to_string());
+    assert_that(&service_name_from_certificate(&Certificate(without_cn.to_der().unwrap())).unwrap())
+      .is_equal_to("billing.internal".to_string());
+  }
+
+  #[test]
+  fn server_config_loads_pem_files() {
+    let (ca, ca_key) = generate_certificate(Some("ca"), None, None);
+    let (server, server_key) = generate_certificate(Some("localhost"), None, Some((&ca, &ca_key)));
+    let ca_path = write_pem("server-config-ca", ca.to_pem().unwrap());
+    let cert_path = write_pem("server-config-cert", server.to_pem().unwrap());
+    let key_path = write_pem("server-config-key", server_key.private_key_to_pem_pkcs8().unwrap());
+
+    assert_that(&server_config_with_client_auth(&cert_path, &key_path, &ca_path).is_ok()).is_true();
+    assert_that(&server_config_with_client_auth(&cert_path, &ca_path, &ca_path).is_err()).is_true();
+  }
+
+  #[actix_web::test]
+  async fn client_certificates_authenticate_services() {
+    let (ca, ca_key) = generate_certificate(Some("ca"), None, None);
+    let (server, server_key) = generate_certificate(Some("localhost"), None, Some((&ca, &ca_key)));
+    let (client, client_key) = generate_certificate(None, Some("billing.internal"), Some((&ca, &ca_key)));
+    let ca_path = write_pem("client-auth-ca", ca.to_pem().unwrap());
+    let server_cert_path = write_pem("client-auth-server-cert", server.to_pem().unwrap());
+    let server_key_path = write_pem("client-auth-server-key", server_key.private_key_to_pem_pkcs8().unwrap());
+    let client_cert_path = write_pem("client-auth-client-cert", client.to_pem().unwrap());
+    let client_key_path = write_pem("client-auth-client-key", client_key.private_key_to_pem_pkcs8().unwrap());
+    let config = server_config_with_client_auth(&server_cert_path, &server_key_path, &ca_path).unwrap();
+    let server = HttpServer::new(|| {
+      App::new()
+        .wrap_fn(|req, _| {
+          let subject = match ClientCertificateAuth.authenticate(&req) {
+            Ok(Some(auth_context)) => auth_context.subject.to_string(),
+            Ok(None) => "anonymous".to_string(),
+            Err(problem) => problem.to_string(),
+          };
+          ok(req.into_response(HttpResponse::Ok().json(subject)))
+        })
+        .route("/", web::get().to(HttpResponse::Ok))
+    })
+    .on_connect(client_certificate_on_connect)
+    .workers(1)
+    .bind_rustls(("127.0.0.1", 0), config)
+    .unwrap();
+    let url = format!("https://localhost:{}/", server.addrs()[0].port());
+    actix_web::rt::spawn(server.run());
+    let tls = ClientTlsConfig::default()
+      .with_root_ca(ca_path.to_path_buf())
+      .only_custom_roots()
+      .with_identity(ClientIdentity::Pem {
+        cert_path: client_cert_path.to_path_buf(),
+        key_path: client_key_path.to_path_buf(),
+      });
+    let requester = ServiceRequester::builder("billing").tls(tls).build().unwrap();
+
+    let subject = requester.get::<_, String>(url.as_str()).await;
+
+    assert_that(&subject.ok()).is_equal_to(Some("service/billing.internal".to_string()));
+  }
+}
@@ -0,0 +1,727 @@
+use crate::line_writer::LineWriter;
+use crate::{AsyncBusinessResult, Problem};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use futures::channel::oneshot;
+use futures::future::{ok, Ready};
+use log::error;
+use rand::RngCore;
+use reqwest::Client;
+use serde_derive::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const TRACEPARENT_HEADER_NAME: &str = "traceparent";
+pub const TRACESTATE_HEADER_NAME: &str = "tracestate";
+
+tokio::task_local! {
+  static CURRENT_SPAN: ActiveSpan;
+}
+
+fn random_hex<const N: usize>() -> String {
+  let mut bytes = [0u8; N];
+  while bytes.iter().all(|b| *b == 0) {
+    rand::thread_rng().fill_bytes(&mut bytes);
+  }
+  hex::encode(bytes)
+}
+
+fn is_valid_id(id: &str, len: usize) -> bool {
+  id.len() == len && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) && id.chars().any(|c| c != '0')
+}
+
+/// W3C trace context of a span, as carried in `traceparent` and `tracestate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+  pub trace_id: String,
+  pub span_id: String,
+  pub sampled: bool,
+  pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+  pub fn new_root() -> TraceContext {
+    TraceContext {
+      trace_id: random_hex::<16>(),
+      span_id: random_hex::<8>(),
+      sampled: true,
+      trace_state: None,
+    }
+  }
+
+  /// Parses a version 00 `traceparent`, later versions are read as far as 00 defines them.
+  pub fn parse(traceparent: &str, trace_state: Option<&str>) -> Option<TraceContext> {
+    let parts = traceparent.trim().split('-').collect::<Vec<_>>();
+    let (version, trace_id, span_id, flags) = match parts.as_slice() {
+      [version, trace_id, span_id, flags, ..] => (*version, *trace_id, *span_id, *flags),
+      _ => return None,
+    };
+    if version.len() != 2 || version == "ff" || (version == "00" && parts.len() != 4) {
+      return None;
+    }
+    if !is_valid_id(trace_id, 32) || !is_valid_id(span_id, 16) || flags.len() != 2 {
+      return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+
+    Some(TraceContext {
+      trace_id: trace_id.to_string(),
+      span_id: span_id.to_string(),
+      sampled: flags & 1 == 1,
+      trace_state: trace_state.map(str::to_string).filter(|s| !s.is_empty()),
+    })
+  }
+
+  pub fn child(&self) -> TraceContext {
+    TraceContext {
+      span_id: random_hex::<8>(),
+      ..self.clone()
+    }
+  }
+
+  pub fn traceparent(&self) -> String {
+    format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, u8::from(self.sampled))
+  }
+
+  /// Context of the span currently active on this task, if any.
+  pub fn current() -> Option<TraceContext> {
+    CURRENT_SPAN.try_with(|active| active.context.clone()).ok()
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpanKind {
+  Internal,
+  Server,
+  Client,
+}
+
+impl SpanKind {
+  fn otlp_kind(&self) -> u8 {
+    match self {
+      SpanKind::Internal => 1,
+      SpanKind::Server => 2,
+      SpanKind::Client => 3,
+    }
+  }
+}
+
+/// A finished span as handed to a `SpanExporter`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SpanData {
+  pub service: String,
+  pub trace_id: String,
+  pub span_id: String,
+  pub parent_span_id: Option<String>,
+  pub name: String,
+  pub kind: SpanKind,
+  pub start_time_unix_nano: u64,
+  pub end_time_unix_nano: u64,
+  pub attributes: BTreeMap<String, String>,
+  pub error: bool,
+}
+
+pub trait SpanExporter: Send + Sync {
+  fn export(&self, span: SpanData);
+}
+
+/// Appends one JSON object per span to a file, written from a background thread.
+pub struct FileSpanExporter {
+  lines: LineWriter,
+}
+
+impl FileSpanExporter {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<FileSpanExporter, Problem> {
+    Ok(FileSpanExporter {
+      lines: LineWriter::open(path)?,
+    })
+  }
+}
+
+impl SpanExporter for FileSpanExporter {
+  fn export(&self, span: SpanData) {
+    match serde_json::to_vec(&span) {
+      Ok(line) => self.lines.write(line),
+      Err(err) => error!("Failed to export span: {}", err),
+    }
+  }
+}
+
+/// Keeps spans in memory, e.g. for tests.
+#[derive(Default)]
+pub struct MemorySpanExporter {
+  spans: Mutex<Vec<SpanData>>,
+}
+
+impl MemorySpanExporter {
+  pub fn spans(&self) -> Vec<SpanData> {
+    self.spans.lock().map(|spans| spans.clone()).unwrap_or_default()
+  }
+}
+
+impl SpanExporter for MemorySpanExporter {
+  fn export(&self, span: SpanData) {
+    if let Ok(mut spans) = self.spans.lock() {
+      spans.push(span);
+    }
+  }
+}
+
+/// Sends spans in batches of `batch_size` as OTLP/HTTP JSON to `endpoint`, e.g. `http://localhost:4318/v1/traces`.
+///
+/// Spans are sent from a background thread once a batch is full, every `flush_interval` (by default 5 s) and when
+/// the exporter is dropped, so it works with or without an async runtime.
+pub struct OtlpHttpExporter {
+  client: Client,
+  endpoint: String,
+  batch_size: usize,
+  flush_interval: Duration,
+  buffer: Arc<Mutex<Vec<SpanData>>>,
+  worker: OnceLock<Worker>,
+}
+
+struct Worker {
+  commands: mpsc::Sender<Command>,
+  thread: JoinHandle<()>,
+}
+
+enum Command {
+  Send,
+  Flush(oneshot::Sender<()>),
+  Shutdown,
+}
+
+impl OtlpHttpExporter {
+  pub fn new<S: Into<String>>(endpoint: S) -> OtlpHttpExporter {
+    OtlpHttpExporter {
+      client: Client::new(),
+      endpoint: endpoint.into(),
+      batch_size: 64,
+      flush_interval: Duration::from_secs(5),
+      buffer: Arc::new(Mutex::new(Vec::new())),
+      worker: OnceLock::new(),
+    }
+  }
+
+  pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+    self.batch_size = batch_size.max(1);
+    self
+  }
+
+  pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+    self.flush_interval = flush_interval;
+    self
+  }
+
+  fn worker(&self) -> &Worker {
+    self.worker.get_or_init(|| {
+      let (commands, received) = mpsc::channel();
+      let (client, endpoint, buffer) = (self.client.clone(), self.endpoint.clone(), self.buffer.clone());
+      let flush_interval = self.flush_interval;
+      let thread = thread::spawn(move || send_batches(client, endpoint, buffer, flush_interval, received));
+
+      Worker { commands, thread }
+    })
+  }
+
+  fn command(&self, command: Command) {
+    if self.worker().commands.send(command).is_err() {
+      error!("Span export to {} has stopped", self.endpoint);
+    }
+  }
+
+  /// Sends all buffered spans.
+  pub async fn flush(&self) {
+    let (done, flushed) = oneshot::channel();
+    self.command(Command::Flush(done));
+    let _ = flushed.await;
+  }
+}
+
+impl SpanExporter for OtlpHttpExporter {
+  fn export(&self, span: SpanData) {
+    self.worker();
+    let full = match self.buffer.lock() {
+      Ok(mut buffer) => {
+        buffer.push(span);
+        buffer.len() >= self.batch_size
+      }
+      Err(_) => false,
+    };
+    if full {
+      self.command(Command::Send);
+    }
+  }
+}
+
+impl Drop for OtlpHttpExporter {
+  fn drop(&mut self) {
+    if let Some(worker) = self.worker.take() {
+      let _ = worker.commands.send(Command::Shutdown);
+      let _ = worker.thread.join();
+    }
+  }
+}
+
+fn send_batches(
+  client: Client,
+  endpoint: String,
+  buffer: Arc<Mutex<Vec<SpanData>>>,
+  flush_interval: Duration,
+  commands: mpsc::Receiver<Command>,
+) {
+  let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+    Ok(runtime) => runtime,
+    Err(err) => {
+      error!("Failed to start span export to {}: {}", endpoint, err);
+      return;
+    }
+  };
+  loop {
+    let command = commands.recv_timeout(flush_interval);
+    let spans = buffer
+      .lock()
+      .map(|mut buffer| std::mem::take(&mut *buffer))
+      .unwrap_or_default();
+    runtime.block_on(send_otlp(client.clone(), endpoint.clone(), spans));
+    match command {
+      Ok(Command::Flush(done)) => {
+        let _ = done.send(());
+      }
+      Ok(Command::Send) | Err(RecvTimeoutError::Timeout) => (),
+      Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => return,
+    }
+  }
+}
+
+fn otlp_request(spans: &[SpanData]) -> serde_json::Value {
+  let mut by_service: BTreeMap<&str, Vec<serde_json::Value>> = BTreeMap::new();
+  for span in spans {
+    by_service.entry(span.service.as_str()).or_default().push(json!({
+      "traceId": span.trace_id,
+      "spanId": span.span_id,
+      "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+      "name": span.name,
+      "kind": span.kind.otlp_kind(),
+      "startTimeUnixNano": span.start_time_unix_nano.to_string(),
+      "endTimeUnixNano": span.end_time_unix_nano.to_string(),
+      "attributes": span.attributes.iter().map(|(key, value)| json!({
+        "key": key,
+        "value": { "stringValue": value },
+      })).collect::<Vec<_>>(),
+      "status": { "code": if span.error { 2 } else { 0 } },
+    }));
+  }
+
+  json!({
+    "resourceSpans": by_service.into_iter().map(|(service, spans)| json!({
+      "resource": {
+        "attributes": [{ "key": "service.name", "value": { "stringValue": service } }],
+      },
+      "scopeSpans": [{ "scope": { "name": "microtools" }, "spans": spans }],
+    })).collect::<Vec<_>>(),
+  })
+}
+
+async fn send_otlp(client: Client, endpoint: String, spans: Vec<SpanData>) {
+  if spans.is_empty() {
+    return;
+  }
+  let result = client
+    .post(&endpoint)
+    .json(&otlp_request(&spans))
+    .send()
+    .await
+    .and_then(|response| response.error_for_status());
+
+  if let Err(err) = result {
+    error!("Failed to export {} spans to {}: {}", spans.len(), endpoint, err);
+  }
+}
+
+/// Names the local service in its spans and hands them to the exporter.
+pub struct Tracer {
+  service_name: String,
+  exporter: Arc<dyn SpanExporter>,
+}
+
+impl Tracer {
+  pub fn new<S: Into<String>>(service_name: S, exporter: Arc<dyn SpanExporter>) -> Tracer {
+    Tracer {
+      service_name: service_name.into(),
+      exporter,
+    }
+  }
+
+  /// Starts a span continuing `parent`, or a new trace without one.
+  pub fn start_span<S: Into<String>>(self: &Arc<Self>, name: S, kind: SpanKind, parent: Option<&TraceContext>) -> Span {
+    let context = match parent {
+      Some(parent) => parent.child(),
+      None => TraceContext::new_root(),
+    };
+
+    Span {
+      data: SpanData {
+        service: self.service_name.clone(),
+        trace_id: context.trace_id.clone(),
+        span_id: context.span_id.clone(),
+        parent_span_id: parent.map(|p| p.span_id.clone()),
+        name: name.into(),
+        kind,
+        start_time_unix_nano: unix_nanos(SystemTime::now()),
+        end_time_unix_nano: 0,
+        attributes: BTreeMap::new(),
+        error: false,
+      },
+      active: ActiveSpan {
+        context,
+        tracer: self.clone(),
+      },
+      start: Instant::now(),
+    }
+  }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_nanos() as u64)
+    .unwrap_or_default()
+}
+
+#[derive(Clone)]
+struct ActiveSpan {
+  context: TraceContext,
+  tracer: Arc<Tracer>,
+}
+
+/// A span in progress, exported by `end` if its trace is sampled.
+pub struct Span {
+  data: SpanData,
+  active: ActiveSpan,
+  start: Instant,
+}
+
+impl Span {
+  /// Starts a child of the span active on this task, `None` outside of a traced request.
+  pub fn start_child<S: Into<String>>(name: S, kind: SpanKind) -> Option<Span> {
+    CURRENT_SPAN
+      .try_with(|active| active.tracer.start_span(name, kind, Some(&active.context)))
+      .ok()
+  }
+
+  pub fn context(&self) -> &TraceContext {
+    &self.active.context
+  }
+
+  pub fn set_attribute<K: Into<String>, V: ToString>(&mut self, key: K, value: V) {
+    self.data.attributes.insert(key.into(), value.to_string());
+  }
+
+  pub fn set_error(&mut self, error: bool) {
+    self.data.error = error;
+  }
+
+  /// Runs `future` with this span as the parent of spans started within.
+  pub async fn in_scope<F: Future>(&self, future: F) -> F::Output {
+    CURRENT_SPAN.scope(self.active.clone(), future).await
+  }
+
+  /// Like `in_scope` for synchronous code.
+  pub fn in_scope_sync<F: FnOnce() -> R, R>(&self, f: F) -> R {
+    CURRENT_SPAN.sync_scope(self.active.clone(), f)
+  }
+
+  pub fn end(mut self) {
+    self.data.end_time_unix_nano = self.data.start_time_unix_nano + self.start.elapsed().as_nanos() as u64;
+    if self.active.context.sampled {
+      self.active.tracer.exporter.export(self.data);
+    }
+  }
+}
+
+/// Continues the trace of incoming `traceparent` headers with a server span per request.
+pub struct TraceMiddlewareFactory {
+  tracer: Arc<Tracer>,
+}
+
+impl TraceMiddlewareFactory {
+  pub fn new(tracer: Arc<Tracer>) -> TraceMiddlewareFactory {
+    TraceMiddlewareFactory { tracer }
+  }
+}
+
+impl<S, B: 'static> Transform<S, ServiceRequest> for TraceMiddlewareFactory
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Problem> + 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = Problem;
+  type InitError = ();
+  type Transform = TraceMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(TraceMiddleware {
+      service,
+      tracer: self.tracer.clone(),
+    })
+  }
+}
+
+pub struct TraceMiddleware<S> {
+  service: S,
+  tracer: Arc<Tracer>,
+}
+
+impl<S, B: 'static> Service<ServiceRequest> for TraceMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Problem> + 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = Problem;
+  type Future = AsyncBusinessResult<Self::Response>;
+
+  fn poll_ready(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(cx)
+  }
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let header = |name| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let parent = header(TRACEPARENT_HEADER_NAME).and_then(|tp| TraceContext::parse(tp, header(TRACESTATE_HEADER_NAME)));
+    let route = req.match_pattern();
+    let mut span = self.tracer.start_span(
+      format!("{} {}", req.method(), route.as_deref().unwrap_or_else(|| req.path())),
+      SpanKind::Server,
+      parent.as_ref(),
+    );
+    span.set_attribute("http.method", req.method());
+    span.set_attribute("http.target", req.path());
+    if let Some(route) = route {
+      span.set_attribute("http.route", route);
+    }
+    let fut = CURRENT_SPAN.scope(span.active.clone(), self.service.call(req));
+
+    Box::pin(async move {
+      let result = fut.await;
+      let status = match &result {
+        Ok(res) => res.status().as_u16(),
+        Err(problem) => problem.code,
+      };
+      span.set_attribute("http.status_code", status);
+      span.set_error(status >= 500);
+      span.end();
+
+      result
+    })
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::metrics::TimedActions;
+  use crate::service_requester_test::{http_response, stub_server};
+  use actix_web::dev::fn_service;
+  use actix_web::test::TestRequest;
+  use actix_web::{web, App, HttpResponse};
+  use spectral::prelude::*;
+  use std::rc::Rc;
+
+  #[test]
+  fn parses_and_formats_traceparent() {
+    let context = TraceContext::parse(
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+      Some("vendor=x"),
+    )
+    .unwrap();
+
+    assert_that(&context.trace_id.as_str()).is_equal_to("4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_that(&context.sampled).is_true();
+    assert_that(&context.trace_state).is_equal_to(Some("vendor=x".to_string()));
+    assert_that(&context.traceparent().as_str()).is_equal_to("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+  }
+
+  #[test]
+  fn rejects_invalid_traceparent() {
+    for traceparent in [
+      "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+      "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+      "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+      "garbage",
+    ] {
+      assert_that(&TraceContext::parse(traceparent, None)).is_none();
+    }
+    assert_that(&TraceContext::parse(
+      "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra",
+      None,
+    ))
+    .is_some();
+  }
+
+  pub fn tracer() -> (Arc<Tracer>, Arc<MemorySpanExporter>) {
+    let exporter = Arc::new(MemorySpanExporter::default());
+
+    (Arc::new(Tracer::new("test", exporter.clone())), exporter)
+  }
+
+  #[actix_web::test]
+  async fn server_spans_continue_incoming_trace() {
+    let (tracer, exporter) = tracer();
+    let service = TraceMiddlewareFactory::new(tracer)
+      .new_transform(fn_service(|req: ServiceRequest| async move {
+        let child = Span::start_child("lookup", SpanKind::Internal).unwrap();
+        child.end();
+        Ok(req.into_response(HttpResponse::Ok().finish()))
+      }))
+      .await
+      .unwrap();
+    let req = TestRequest::get()
+      .uri("/orders")
+      .insert_header((
+        TRACEPARENT_HEADER_NAME,
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+      ))
+      .to_srv_request();
+
+    service.call(req).await.unwrap();
+    let spans = exporter.spans();
+
+    assert_that(&spans.len()).is_equal_to(2);
+    assert_that(&spans[0].name.as_str()).is_equal_to("lookup");
+    assert_that(&spans[0].parent_span_id).is_equal_to(Some(spans[1].span_id.clone()));
+    assert_that(&spans[1].kind).is_equal_to(SpanKind::Server);
+    assert_that(&spans[1].trace_id.as_str()).is_equal_to("4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_that(&spans[1].parent_span_id).is_equal_to(Some("00f067aa0ba902b7".to_string()));
+    assert_that(&spans[1].attributes.get("http.status_code")).is_equal_to(Some(&"200".to_string()));
+  }
+
+  #[actix_web::test]
+  async fn failed_requests_are_named_by_route() {
+    let (tracer, exporter) = tracer();
+    let service = Rc::new(
+      TraceMiddlewareFactory::new(tracer)
+        .new_transform(fn_service(|_: ServiceRequest| async {
+          Err::<ServiceResponse, _>(Problem::not_found())
+        }))
+        .await
+        .unwrap(),
+    );
+    let app = actix_web::test::init_service(
+      App::new()
+        .wrap_fn(move |req, _| {
+          let service = service.clone();
+          async move { service.call(req).await.map_err(actix_web::Error::from) }
+        })
+        .route("/orders/{id}", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let _ = actix_web::test::try_call_service(&app, TestRequest::get().uri("/orders/42").to_request()).await;
+    let spans = exporter.spans();
+
+    assert_that(&spans[0].name.as_str()).is_equal_to("GET /orders/{id}");
+    assert_that(&spans[0].attributes.get("http.route")).is_equal_to(Some(&"/orders/{id}".to_string()));
+    assert_that(&spans[0].attributes.get("http.status_code")).is_equal_to(Some(&"404".to_string()));
+  }
+
+  #[actix_web::test]
+  async fn otlp_exporter_posts_batches() {
+    let (url, requests) = stub_server(vec![http_response("200 OK", &[], "{}")]);
+    let exporter = Arc::new(OtlpHttpExporter::new(format!("{}/v1/traces", url)));
+    let tracer = Arc::new(Tracer::new("orders", exporter.clone()));
+
+    tracer.start_span("first", SpanKind::Internal, None).end();
+    tracer.start_span("second", SpanKind::Client, None).end();
+    exporter.flush().await;
+    let requests = requests.lock().unwrap();
+
+    assert_that(&requests.len()).is_equal_to(1);
+    assert_that(&requests[0]).starts_with("POST /v1/traces");
+    assert_that(&requests[0]).contains(r#""stringValue":"orders""#);
+    assert_that(&requests[0]).contains(r#""name":"second""#);
+  }
+
+  #[test]
+  fn otlp_exporter_sends_without_runtime() {
+    let (url, requests) = stub_server(vec![http_response("200 OK", &[], "{}")]);
+    let exporter = Arc::new(OtlpHttpExporter::new(format!("{}/v1/traces", url)));
+    let tracer = Arc::new(Tracer::new("orders", exporter.clone()));
+
+    tracer.start_span("first", SpanKind::Internal, None).end();
+    tracer.start_span("second", SpanKind::Internal, None).end();
+    drop(tracer);
+    drop(exporter);
+    let requests = requests.lock().unwrap();
+
+    assert_that(&requests.len()).is_equal_to(1);
+    assert_that(&requests[0]).contains(r#""name":"first""#);
+    assert_that(&requests[0]).contains(r#""name":"second""#);
+  }
+
+  #[test]
+  fn otlp_exporter_flushes_periodically() {
+    let (url, requests) = stub_server(vec![http_response("200 OK", &[], "{}")]);
+    let exporter =
+      Arc::new(OtlpHttpExporter::new(format!("{}/v1/traces", url)).with_flush_interval(Duration::from_millis(20)));
+    let tracer = Arc::new(Tracer::new("orders", exporter.clone()));
+
+    tracer.start_span("first", SpanKind::Internal, None).end();
+    thread::sleep(Duration::from_millis(300));
+
+    assert_that(&requests.lock().unwrap().len()).is_equal_to(1);
+  }
+
+  #[test]
+  fn file_exporter_writes_ndjson() {
+    let path = std::env::temp_dir().join(format!("microtools-{}-spans.ndjson", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let tracer = Arc::new(Tracer::new("orders", Arc::new(FileSpanExporter::open(&path).unwrap())));
+
+    tracer.start_span("first", SpanKind::Internal, None).end();
+    tracer.start_span("second", SpanKind::Internal, None).end();
+    drop(tracer);
+    let lines = std::fs::read_to_string(&path).unwrap();
+
+    assert_that(&lines.lines().count()).is_equal_to(2);
+    assert_that(&lines).contains(r#""name":"second""#);
+  }
+
+  #[actix_web::test]
+  async fn timed_actions_are_traced() {
+    let (tracer, exporter) = tracer();
+    let timed_actions = TimedActions::new("trace_test_timed_actions", "Timed actions in trace tests");
+    let span = tracer.start_span("handler", SpanKind::Server, None);
+
+    let _ = span
+      .in_scope(timed_actions.time_async("load", async { Err::<(), _>(Problem::conflict()) }))
+      .await;
+    let _ = span.in_scope_sync(|| timed_actions.time_sync("parse", || Ok::<_, Problem>(())));
+    let spans = exporter.spans();
+
+    assert_that(&spans.len()).is_equal_to(2);
+    assert_that(&spans[0].name.as_str()).is_equal_to("load");
+    assert_that(&spans[0].error).is_true();
+    assert_that(&spans[1].name.as_str()).is_equal_to("parse");
+    assert_that(&spans[1].error).is_false();
+  }
+
+  #[test]
+  fn unsampled_spans_are_not_exported() {
+    let (tracer, exporter) = tracer();
+    let parent = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00", None).unwrap();
+
+    tracer.start_span("ignored", SpanKind::Internal, Some(&parent)).end();
+
+    assert_that(&exporter.spans().len()).is_equal_to(0);
+  }
+}
@@ -0,0 +1,462 @@
+use crate::{BusinessResult, Problem};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::ser::{self, Impossible};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Everything but the characters RFC 3986 allows unencoded in a path segment.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+  .add(b' ')
+  .add(b'"')
+  .add(b'#')
+  .add(b'%')
+  .add(b'/')
+  .add(b'<')
+  .add(b'>')
+  .add(b'?')
+  .add(b'[')
+  .add(b'\\')
+  .add(b']')
+  .add(b'^')
+  .add(b'`')
+  .add(b'{')
+  .add(b'|')
+  .add(b'}');
+
+/// Percent-encodes `value` as a single path segment, e.g. `a b/c` becomes `a%20b%2Fc`.
+///
+/// `.` and `..` stay dot segments that URL parsing removes, even encoded; `UrlTemplate` rejects them.
+pub fn encode_path_segment<S: AsRef<str>>(value: S) -> String {
+  utf8_percent_encode(value.as_ref(), PATH_SEGMENT).to_string()
+}
+
+/// Builds URLs from templates like `http://orders/api/v1/users/{id}/orders`, encoding each parameter as a path
+/// segment.
+#[derive(Clone, Debug)]
+pub struct UrlTemplate {
+  template: String,
+  params: Vec<(String, String)>,
+}
+
+impl UrlTemplate {
+  pub fn new<S: Into<String>>(template: S) -> UrlTemplate {
+    UrlTemplate {
+      template: template.into(),
+      params: vec![],
+    }
+  }
+
+  pub fn param<N: Into<String>, V: ToString>(mut self, name: N, value: V) -> Self {
+    self.params.push((name.into(), value.to_string()));
+    self
+  }
+
+  /// The path of the template, e.g. `/api/v1/users/{id}/orders`, to be passed to `RequestOptions::with_route`.
+  pub fn route(&self) -> &str {
+    let path = match self.template.find("://") {
+      Some(scheme_end) => {
+        let authority = &self.template[scheme_end + 3..];
+        authority.find('/').map(|start| &authority[start..]).unwrap_or("/")
+      }
+      None => self.template.as_str(),
+    };
+
+    path.split('?').next().unwrap_or(path)
+  }
+
+  pub fn expand(&self) -> BusinessResult<String> {
+    let mut url = String::with_capacity(self.template.len());
+    let mut rest = self.template.as_str();
+
+    while let Some(start) = rest.find('{') {
+      let end = rest[start..]
+        .find('}')
+        .ok_or_else(|| invalid_template(&self.template, "unclosed {"))?;
+      let name = &rest[start + 1..start + end];
+      let (_, value) = self
+        .params
+        .iter()
+        .find(|(param, _)| param == name)
+        .ok_or_else(|| invalid_template(&self.template, &format!("missing parameter {}", name)))?;
+      if value == "." || value == ".." {
+        return Err(invalid_template(
+          &self.template,
+          &format!("parameter {} is a dot segment", name),
+        ));
+      }
+      url.push_str(&rest[..start]);
+      url.push_str(&encode_path_segment(value));
+      rest = &rest[start + end + 1..];
+    }
+    url.push_str(rest);
+
+    Ok(url)
+  }
+}
+
+fn invalid_template(template: &str, reason: &str) -> Problem {
+  Problem::internal_server_error().with_details(format!("Invalid URL template {}: {}", template, reason))
+}
+
+/// Flattens a struct, map or sequence of `(name, value)` pairs into query parameters in order: `None` values are
+/// left out and sequences become repeated parameters, e.g. `status=open&status=paid`. Nested structs are not
+/// supported.
+pub fn query_pairs<Q: Serialize + ?Sized>(query: &Q) -> BusinessResult<Vec<(String, String)>> {
+  let mut pairs = vec![];
+  query
+    .serialize(QuerySerializer {
+      pairs: &mut pairs,
+      name: None,
+    })
+    .map_err(|e| invalid_query(&e.to_string()))?;
+
+  Ok(pairs)
+}
+
+fn invalid_query(reason: &str) -> Problem {
+  Problem::internal_server_error().with_details(format!("Invalid query: {}", reason))
+}
+
+/// Collects the fields, entries or pairs of a query without reordering them, values are converted via `serde_json`.
+struct QuerySerializer<'a> {
+  pairs: &'a mut Vec<(String, String)>,
+  name: Option<String>,
+}
+
+impl<'a> QuerySerializer<'a> {
+  fn push<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), serde_json::Error> {
+    match serde_json::to_value(value)? {
+      Value::Array(values) => {
+        for value in values {
+          self.push_value(name, value)?;
+        }
+        Ok(())
+      }
+      value => self.push_value(name, value),
+    }
+  }
+
+  fn push_value(&mut self, name: &str, value: Value) -> Result<(), serde_json::Error> {
+    let value = match value {
+      Value::Null => return Ok(()),
+      Value::Bool(value) => value.to_string(),
+      Value::Number(value) => value.to_string(),
+      Value::String(value) => value,
+      Value::Array(_) | Value::Object(_) => return Err(ser::Error::custom(format!("{} is nested", name))),
+    };
+    self.pairs.push((name.to_string(), value));
+
+    Ok(())
+  }
+}
+
+fn unsupported() -> serde_json::Error {
+  ser::Error::custom("expected a struct, map or sequence of pairs")
+}
+
+impl<'a> ser::Serializer for QuerySerializer<'a> {
+  type Ok = ();
+  type Error = serde_json::Error;
+  type SerializeSeq = Self;
+  type SerializeTuple = Self;
+  type SerializeTupleStruct = Impossible<(), serde_json::Error>;
+  type SerializeTupleVariant = Impossible<(), serde_json::Error>;
+  type SerializeMap = Self;
+  type SerializeStruct = Self;
+  type SerializeStructVariant = Impossible<(), serde_json::Error>;
+
+  fn serialize_bool(self, _: bool) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_i8(self, _: i8) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_i16(self, _: i16) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_i32(self, _: i32) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_i64(self, _: i64) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_u8(self, _: u8) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_u16(self, _: u16) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_u32(self, _: u32) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_u64(self, _: u64) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_f32(self, _: f32) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_f64(self, _: f64) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_char(self, _: char) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_str(self, _: &str) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_bytes(self, _: &[u8]) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_none(self) -> Result<(), Self::Error> {
+    Ok(())
+  }
+
+  fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Self::Error> {
+    value.serialize(self)
+  }
+
+  fn serialize_unit(self) -> Result<(), Self::Error> {
+    Ok(())
+  }
+
+  fn serialize_unit_struct(self, _: &'static str) -> Result<(), Self::Error> {
+    Ok(())
+  }
+
+  fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<(), Self::Error> {
+    value.serialize(self)
+  }
+
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self,
+    _: &'static str,
+    _: u32,
+    _: &'static str,
+    _: &T,
+  ) -> Result<(), Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_seq(self, _: Option<usize>) -> Result<Self, Self::Error> {
+    Ok(self)
+  }
+
+  fn serialize_tuple(self, _: usize) -> Result<Self, Self::Error> {
+    Ok(self)
+  }
+
+  fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    _: &'static str,
+    _: u32,
+    _: &'static str,
+    _: usize,
+  ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+    Err(unsupported())
+  }
+
+  fn serialize_map(self, _: Option<usize>) -> Result<Self, Self::Error> {
+    Ok(self)
+  }
+
+  fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, Self::Error> {
+    Ok(self)
+  }
+
+  fn serialize_struct_variant(
+    self,
+    _: &'static str,
+    _: u32,
+    _: &'static str,
+    _: usize,
+  ) -> Result<Self::SerializeStructVariant, Self::Error> {
+    Err(unsupported())
+  }
+}
+
+impl<'a> ser::SerializeSeq for QuerySerializer<'a> {
+  type Ok = ();
+  type Error = serde_json::Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, pair: &T) -> Result<(), Self::Error> {
+    match serde_json::to_value(pair)? {
+      Value::Array(pair) => match <[Value; 2]>::try_from(pair) {
+        Ok([Value::String(name), value]) => self.push(&name, &value),
+        _ => Err(unsupported()),
+      },
+      _ => Err(unsupported()),
+    }
+  }
+
+  fn end(self) -> Result<(), Self::Error> {
+    Ok(())
+  }
+}
+
+impl<'a> ser::SerializeTuple for QuerySerializer<'a> {
+  type Ok = ();
+  type Error = serde_json::Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, pair: &T) -> Result<(), Self::Error> {
+    ser::SerializeSeq::serialize_element(self, pair)
+  }
+
+  fn end(self) -> Result<(), Self::Error> {
+    Ok(())
+  }
+}
+
+impl<'a> ser::SerializeMap for QuerySerializer<'a> {
+  type Ok = ();
+  type Error = serde_json::Error;
+
+  fn serialize_key<T: Serialize + ?Sized>(&mut self, name: &T) -> Result<(), Self::Error> {
+    self.name = match serde_json::to_value(name)? {
+      Value::String(name) => Some(name),
+      Value::Number(name) => Some(name.to_string()),
+      Value::Bool(name) => Some(name.to_string()),
+      _ => return Err(unsupported()),
+    };
+    Ok(())
+  }
+
+  fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+    let name = self.name.take().unwrap_or_default();
+    self.push(&name, value)
+  }
+
+  fn end(self) -> Result<(), Self::Error> {
+    Ok(())
+  }
+}
+
+impl<'a> ser::SerializeStruct for QuerySerializer<'a> {
+  type Ok = ();
+  type Error = serde_json::Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, name: &'static str, value: &T) -> Result<(), Self::Error> {
+    self.push(name, value)
+  }
+
+  fn end(self) -> Result<(), Self::Error> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_derive::Serialize;
+  use spectral::prelude::*;
+
+  #[derive(Serialize)]
+  struct OrderQuery {
+    customer: &'static str,
+    status: Vec<&'static str>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+  }
+
+  #[test]
+  fn expands_templates_with_encoded_segments() {
+    let url = UrlTemplate::new("http://orders/api/v1/users/{id}/orders/{order}")
+      .param("id", "jane doe/ä?")
+      .param("order", 42)
+      .expand();
+    let missing = UrlTemplate::new("/users/{id}").expand();
+
+    assert_that(&url.ok()).is_equal_to(Some(
+      "http://orders/api/v1/users/jane%20doe%2F%C3%A4%3F/orders/42".to_string(),
+    ));
+    assert_that(&missing.map_err(|problem| problem.details)).is_equal_to(Err(Some(
+      "Invalid URL template /users/{id}: missing parameter id".to_string(),
+    )));
+  }
+
+  #[test]
+  fn routes_are_template_paths() {
+    assert_that(&UrlTemplate::new("http://orders/api/v1/users/{id}/orders?status=open").route())
+      .is_equal_to("/api/v1/users/{id}/orders");
+    assert_that(&UrlTemplate::new("service://orders").route()).is_equal_to("/");
+    assert_that(&UrlTemplate::new("/users/{id}").route()).is_equal_to("/users/{id}");
+  }
+
+  #[test]
+  fn rejects_dot_segments() {
+    let template = UrlTemplate::new("http://orders/api/v1/users/{id}/orders");
+
+    for id in [".", ".."] {
+      assert_that(
+        &template
+          .clone()
+          .param("id", id)
+          .expand()
+          .map_err(|problem| problem.details),
+      )
+      .is_equal_to(Err(Some(
+        "Invalid URL template http://orders/api/v1/users/{id}/orders: parameter id is a dot segment".to_string(),
+      )));
+    }
+    assert_that(&template.param("id", "...").expand().is_ok()).is_true();
+  }
+
+  #[test]
+  fn flattens_queries() {
+    let query = OrderQuery {
+      customer: "Jane Doe",
+      status: vec!["open", "paid"],
+      limit: Some(10),
+      offset: None,
+    };
+
+    assert_that(&query_pairs(&query).unwrap()).is_equal_to(vec![
+      ("customer".to_string(), "Jane Doe".to_string()),
+      ("status".to_string(), "open".to_string()),
+      ("status".to_string(), "paid".to_string()),
+      ("limit".to_string(), "10".to_string()),
+    ]);
+  }
+
+  #[test]
+  fn flattens_pairs_in_order() {
+    let pairs = [("status", "paid"), ("customer", "Jane Doe"), ("status", "open")];
+
+    assert_that(&query_pairs(&pairs).unwrap()).is_equal_to(vec![
+      ("status".to_string(), "paid".to_string()),
+      ("customer".to_string(), "Jane Doe".to_string()),
+      ("status".to_string(), "open".to_string()),
+    ]);
+    assert_that(&query_pairs(&vec![("limit", 10)]).unwrap()).is_equal_to(vec![("limit".to_string(), "10".to_string())]);
+  }
+
+  #[test]
+  fn rejects_nested_and_scalar_queries() {
+    let nested = serde_json::json!({"filter": {"status": "open"}});
+
+    assert_that(&query_pairs(&nested).map_err(|problem| problem.details))
+      .is_equal_to(Err(Some("Invalid query: filter is nested".to_string())));
+    assert_that(&query_pairs("status=open").is_err()).is_true();
+  }
+}
@@ -1,14 +1,19 @@
-use crate::{types::Done, AsyncBusinessResult, Problem};
+use crate::{types::Done, AsyncBusinessResult, BusinessResult, Problem};
 use bytes::Bytes;
 use futures::{future, FutureExt, StreamExt};
 use reqwest::{RequestBuilder, Response, StatusCode};
-use serde::de::DeserializeOwned;
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
 use std::str;
 
 // const JSON_RESPONSE_LIMIT: usize = 100 * 1024 * 1024;
 
 pub trait FromClientResponse<T> {
   fn from_response(response: Response) -> AsyncBusinessResult<T>;
+
+  /// The value for a `404 Not Found` response, if `T` can represent absence like `Option` does.
+  fn from_not_found(_response: &Response) -> Option<T> {
+    None
+  }
 }
 
 impl FromClientResponse<Done> for Done {
@@ -29,6 +34,69 @@ where
   fn from_response(response: Response) -> AsyncBusinessResult<T> {
     Box::pin(response.json().map(|r| r.map_err(Problem::from)))
   }
+
+  fn from_not_found(_response: &Response) -> Option<T> {
+    T::deserialize(AbsentDeserializer).ok()
+  }
+}
+
+/// The value of `T` for a response that was not found, if `T` can represent that.
+fn absent<T: FromClientResponse<T>>(response: &Response) -> Option<T> {
+  match response.status() {
+    StatusCode::NOT_FOUND => T::from_not_found(response),
+    _ => None,
+  }
+}
+
+fn accepts_not_found(not_found_problem_type: Option<&str>, body: &Result<Bytes, reqwest::Error>) -> bool {
+  match (not_found_problem_type, body) {
+    (None, _) => true,
+    (Some(problem_type), Ok(body)) => serde_json::from_slice::<Problem>(body)
+      .map(|problem| problem.problem_type == problem_type)
+      .unwrap_or(false),
+    (Some(_), Err(_)) => false,
+  }
+}
+
+/// Turns an unsuccessful `response` into a problem via `error_handler`, except for a `404 Not Found` that `T` can
+/// represent, like `None` for `Option`. With `not_found_problem_type` only a `404` with a problem of that type is.
+pub(crate) async fn from_error_response<T, E>(
+  response: Response,
+  not_found_problem_type: Option<&str>,
+  error_handler: E,
+) -> BusinessResult<T>
+where
+  T: FromClientResponse<T>,
+  E: Fn(StatusCode, Result<Bytes, reqwest::Error>) -> Problem,
+{
+  let status = response.status();
+  let absent = absent::<T>(&response);
+  let body = response.bytes().await;
+
+  match absent.filter(|_| accepts_not_found(not_found_problem_type, &body)) {
+    Some(absent) => Ok(absent),
+    None => Err(error_handler(status, body)),
+  }
+}
+
+/// Only deserializes `None`, so it succeeds for `Option` and fails for everything else.
+struct AbsentDeserializer;
+
+impl<'de> Deserializer<'de> for AbsentDeserializer {
+  type Error = de::value::Error;
+
+  fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+    Err(de::Error::custom("not an option"))
+  }
+
+  fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+    visitor.visit_none()
+  }
+
+  serde::forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit unit_struct
+    newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+  }
 }
 
 pub trait ClientErrorHandler<T> {
@@ -73,25 +141,46 @@ pub trait SendClientRequestExt: Sized {
   }
 
   fn expect_success_with_error<T, E>(self, error_handler: E) -> AsyncBusinessResult<T>
+  where
+    T: FromClientResponse<T> + 'static,
+    E: Fn(StatusCode, Result<Bytes, reqwest::Error>) -> Problem + 'static,
+  {
+    self.expect_success_with_options(None, error_handler)
+  }
+
+  /// Like `expect_success`, but only a `404` with a problem of `problem_type` is `None` for `Option` responses.
+  fn expect_success_with_not_found_problem_type<T, S>(self, problem_type: S) -> AsyncBusinessResult<T>
+  where
+    T: FromClientResponse<T> + 'static,
+    S: Into<String>,
+  {
+    self.expect_success_with_options(Some(problem_type.into()), default_error_handler)
+  }
+
+  fn expect_success_with_options<T, E>(
+    self,
+    not_found_problem_type: Option<String>,
+    error_handler: E,
+  ) -> AsyncBusinessResult<T>
   where
     T: FromClientResponse<T> + 'static,
     E: Fn(StatusCode, Result<Bytes, reqwest::Error>) -> Problem + 'static;
 }
 
 impl SendClientRequestExt for RequestBuilder {
-  fn expect_success_with_error<T, E>(self, error_handler: E) -> AsyncBusinessResult<T>
+  fn expect_success_with_options<T, E>(
+    self,
+    not_found_problem_type: Option<String>,
+    error_handler: E,
+  ) -> AsyncBusinessResult<T>
   where
     T: FromClientResponse<T> + 'static,
     E: Fn(StatusCode, Result<Bytes, reqwest::Error>) -> Problem + 'static,
   {
     Box::pin(self.send().then(move |maybe_resp| match maybe_resp {
+      Ok(resp) if resp.status().is_success() => T::from_response(resp),
       Ok(resp) => {
-        let status = resp.status();
-        if status.is_success() {
-          T::from_response(resp)
-        } else {
-          Box::pin(resp.bytes().map(move |body| Err(error_handler(status, body))))
-        }
+        Box::pin(async move { from_error_response(resp, not_found_problem_type.as_deref(), error_handler).await })
       }
       Err(err) => Box::pin(future::err(Problem::from(err))),
     }))